@@ -57,6 +57,16 @@ local function can_play_together(players)
     return true
 end
 
+-- Number of players the searchers stand for. A searcher brings the members of its party along, AI players count as one
+local function player_count(searchers)
+    local count = 0
+    for _, searcher in ipairs(searchers) do
+        -- Searchers stored without a party size search alone
+        count = count + (tonumber(redis.call('GET', searcher .. ':party_size')) or 1)
+    end
+    return count
+end
+
 local function publish_new_match(region, player_ids, mode, game, ai)
     local uuid = redis.call('INCR', 'uuid_inc')
 
@@ -70,7 +80,7 @@ local function publish_new_match(region, player_ids, mode, game, ai)
     redis.call('PUBLISH', uuid .. ':match:game', game)
     redis.call('PUBLISH', uuid .. ':match:ai', ai)
     redis.call('PUBLISH', uuid .. ':match:done', #player_ids)
-    table.insert(matches, {game, #player_ids})
+    table.insert(matches, {game, player_count(player_ids)})
 end
 
 local function handle_match(region, players)
@@ -111,7 +121,7 @@ local function fill_with_ai(players, max_player_count, preferred_ai)
         return
     end
 
-    for i = #players + 1, max_player_count do
+    for i = player_count(players) + 1, max_player_count do
         math.randomseed(redis.call('TIME')[1])
 
         local ai_player = eligable_ai[math.random(#eligable_ai)]
@@ -136,7 +146,7 @@ local function check_for_ai_search(player)
 
     fill_with_ai(players, max_players, redis.call('GET', player .. ':ai'))
 
-    if #players == max_players then
+    if player_count(players) == max_players then
         handle_match(redis.call('GET', player .. ':region'), players)
     end
 end
@@ -155,19 +165,24 @@ for i = 1, #searcher_keys do
     local min_player_count = tonumber(redis.call('GET', player1 .. ':min_players'))
 
     local players = { player1 }
+    local count = player_count(players)
     for j = i + 1, #searcher_keys do
         local player2 = searcher_keys[j]
 
-        if can_play_together({ player1, player2 }) then
+        -- Parties are only matched as a whole
+        if can_play_together({ player1, player2 }) and count + player_count({ player2 }) <= max_player_count then
             table.insert(players, player2)
-            if #players == max_player_count then
+            count = player_count(players)
+            if count == max_player_count then
                 break
             end
         end
     end
 
     local region = redis.call('GET', player1 .. ':region')
-    if #players >= min_player_count then
+    if tonumber(redis.call('GET', player1 .. ':matching') or 0) == 1 then
+        -- Matched earlier in this run. A party can reach the min players on its own and must not be matched twice
+    elseif count >= min_player_count then
         handle_match(region, players)
     else
         if tonumber(redis.call('INCR', player1 .. ':failed_searches')) > 10 then
@@ -187,7 +202,7 @@ for i = 1, #searcher_keys do
             if all_fill_with_ai then
                 fill_with_ai(players, max_player_count)
 
-                if #players == max_player_count then
+                if player_count(players) == max_player_count then
                     for _, player in ipairs(players) do
                         redis.call('SET', player .. ':ai', '*')
                     end
@@ -0,0 +1,114 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    panic::AssertUnwindSafe,
+    sync::{Arc, RwLock},
+};
+
+use futures_lite::{Future, FutureExt};
+
+use crate::{
+    envelope::{self, Message},
+    models::HealthReport,
+    HandlerError, HandlerResult,
+};
+
+/// State of the consumer of a queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsumerStatus {
+    /// The consumer is (re)connecting and does not receive messages.
+    Connecting,
+    Consuming,
+    /// The consumer was stopped by a shutdown.
+    Stopped,
+}
+
+/// Shared view on the consumer states of a communicator, keyed by queue name.
+#[derive(Debug, Clone, Default)]
+pub struct ConsumerStatuses(Arc<RwLock<HashMap<String, ConsumerStatus>>>);
+
+impl ConsumerStatuses {
+    pub(crate) fn set(&self, queue: &str, status: ConsumerStatus) {
+        self.0.write().unwrap().insert(queue.to_owned(), status);
+    }
+
+    pub fn get(&self) -> HashMap<String, ConsumerStatus> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Fails if the consumer of `queue` does not currently receive messages.
+    pub fn check(&self, queue: &str) -> Result<(), String> {
+        match self.0.read().unwrap().get(queue) {
+            Some(ConsumerStatus::Consuming) => Ok(()),
+            Some(status) => Err(format!("consumer is {:?}", status).to_lowercase()),
+            None => Err("no consumer registered".to_owned()),
+        }
+    }
+}
+
+/// Runs a message handler. A panicking handler is treated like one asking for a retry.
+pub(crate) async fn run_handler<T, Fut>(handler: Fut) -> HandlerResult<T>
+where
+    Fut: Future<Output = HandlerResult<T>>,
+{
+    AssertUnwindSafe(handler)
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|panic| Err(HandlerError::Retry(panic_message(panic))))
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|x| x.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_owned());
+    format!("handler panicked: {}", message)
+}
+
+/// Decodes a message into its id, if it has one, and its content.
+pub(crate) type Decoder<T> = fn(&[u8]) -> Result<(Option<String>, T), String>;
+
+pub(crate) fn json<T: Message>(data: &[u8]) -> Result<(Option<String>, T), String> {
+    envelope::decode(data).map(|envelope| (Some(envelope.id), envelope.payload))
+}
+
+/// Reads health reports, including the bare client ids game servers sent before reports carried their load.
+/// Reports are never deduplicated, handling one twice does no harm.
+pub(crate) fn health_report(data: &[u8]) -> Result<(Option<String>, HealthReport), String> {
+    match json::<HealthReport>(data) {
+        Ok((_, report)) => Ok((None, report)),
+        Err(err) => match std::str::from_utf8(data) {
+            Ok(client_id) if !client_id.is_empty() => Ok((
+                None,
+                HealthReport {
+                    client_id: client_id.to_owned(),
+                    ..Default::default()
+                },
+            )),
+            _ => Err(err),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_report_reads_bare_client_id() {
+        let (id, report) = health_report(b"10.0.0.1:7000").unwrap();
+        assert_eq!(id, None);
+        assert_eq!(report.client_id, "10.0.0.1:7000");
+        assert_eq!(report.capacity, 0);
+
+        let sent = HealthReport {
+            client_id: "10.0.0.1:7000".to_owned(),
+            active_matches: 3,
+            capacity: 8,
+            ..Default::default()
+        };
+        let (_, report) = health_report(&envelope::encode("game-server", &sent)).unwrap();
+        assert_eq!(report, sent);
+    }
+}
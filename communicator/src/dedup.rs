@@ -0,0 +1,151 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tracing::{debug, warn};
+
+use crate::{HandlerError, HandlerResult};
+
+/// Remembers which messages were already handled, so redelivered messages are not handled twice.
+pub trait Deduplicator: Send + Sync + 'static {
+    /// Marks the message `id` as handled for `ttl`.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the message was already marked, i.e. it is a duplicate.
+    fn claim(&self, id: &str, ttl: Duration) -> Result<bool, String>;
+
+    /// Forgets that the message `id` was handled, so its next delivery is handled again.
+    fn release(&self, id: &str) -> Result<(), String>;
+}
+
+#[cfg(feature = "state")]
+impl Deduplicator for gn_matchmaking_state::adapters::redis::RedisAdapterDefault {
+    fn claim(&self, id: &str, ttl: Duration) -> Result<bool, String> {
+        gn_matchmaking_state::adapters::redis::RedisAdapter::claim(self, &key(id), ttl)
+            .map_err(|err| err.to_string())
+    }
+
+    fn release(&self, id: &str) -> Result<(), String> {
+        gn_matchmaking_state::adapters::redis::RedisAdapter::release(self, &key(id))
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(feature = "state")]
+fn key(id: &str) -> String {
+    format!("message:{}", id)
+}
+
+/// A [`Deduplicator`] and how long it remembers messages.
+#[derive(Clone)]
+pub(crate) struct Dedup {
+    pub(crate) deduplicator: Arc<dyn Deduplicator>,
+    pub(crate) ttl: Duration,
+}
+
+/// The mark of a message whose handler is running. Unless it is kept, the mark is removed when this is dropped,
+/// which also covers handlers which panic or are cancelled.
+struct Claim<'a> {
+    deduplicator: &'a dyn Deduplicator,
+    id: &'a str,
+    keep: bool,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        if let Err(err) = self.deduplicator.release(self.id) {
+            warn!(
+                "Failed to release message {}, its retry will be skipped: {}",
+                self.id, err
+            );
+        }
+    }
+}
+
+impl Dedup {
+    /// Runs `handler` unless the message `id` was already handled.
+    ///
+    /// The message is marked before `handler` runs, so concurrent deliveries of it are skipped as well. If `handler`
+    /// asks for a retry, panics or is cancelled the mark is removed again, so the redelivery is not mistaken for a
+    /// duplicate.
+    pub(crate) async fn once<T, Fut>(&self, id: &str, handler: Fut) -> HandlerResult<Option<T>>
+    where
+        Fut: Future<Output = HandlerResult<T>>,
+    {
+        if !self
+            .deduplicator
+            .claim(id, self.ttl)
+            .map_err(HandlerError::Retry)?
+        {
+            debug!("Skipping duplicate of message {}", id);
+            return Ok(None);
+        }
+
+        let mut claim = Claim {
+            deduplicator: self.deduplicator.as_ref(),
+            id,
+            keep: false,
+        };
+        let result = handler.await;
+        claim.keep = !matches!(result, Err(HandlerError::Retry(_)));
+        result.map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Mutex};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Seen(Mutex<HashSet<String>>);
+
+    impl Deduplicator for Seen {
+        fn claim(&self, id: &str, _: Duration) -> Result<bool, String> {
+            Ok(self.0.lock().unwrap().insert(id.to_owned()))
+        }
+
+        fn release(&self, id: &str) -> Result<(), String> {
+            self.0.lock().unwrap().remove(id);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handles_message_once() {
+        let dedup = Dedup {
+            deduplicator: Arc::new(Seen::default()),
+            ttl: Duration::from_secs(60),
+        };
+
+        let failed = dedup
+            .once("1", async { Err::<(), _>(HandlerError::retry("unavailable")) })
+            .await;
+        assert!(failed.is_err());
+
+        assert_eq!(dedup.once("1", async { Ok(1) }).await, Ok(Some(1)));
+        assert_eq!(dedup.once("1", async { Ok(2) }).await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_releases_message_on_panic() {
+        use futures_lite::FutureExt;
+
+        let dedup = Dedup {
+            deduplicator: Arc::new(Seen::default()),
+            ttl: Duration::from_secs(60),
+        };
+
+        let panicked = std::panic::AssertUnwindSafe(
+            dedup.once::<(), _>("1", async { panic!("handler failed") }),
+        )
+        .catch_unwind()
+        .await;
+        assert!(panicked.is_err());
+
+        assert_eq!(dedup.once("1", async { Ok(1) }).await, Ok(Some(1)));
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, Span};
+
+use crate::{propagation, routing::Route};
+
+/// Payload versions which are read before anything was sent in an envelope.
+const LEGACY_VERSION: u32 = 1;
+
+/// A message which is sent wrapped in an [`Envelope`].
+pub trait Message: Serialize + DeserializeOwned + Send + 'static {
+    /// Name of the message type, e.g. `match.created`.
+    const TYPE: &'static str;
+    /// Version of the payload schema. Increased with every change consumers have to know about.
+    const VERSION: u32;
+
+    /// Converts the payload of an older `version` to the current schema.
+    ///
+    /// By default payloads are read as they are, which covers versions that only added optional fields.
+    fn migrate(version: u32, payload: Value) -> Result<Value, String> {
+        let _ = version;
+        Ok(payload)
+    }
+
+    /// Game, mode and region the message is routed by on topic exchanges.
+    fn route(&self) -> Route {
+        Route::default()
+    }
+}
+
+/// Metadata every message is sent with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub version: u32,
+    pub produced_at: DateTime<Utc>,
+    /// Name of the service which sent the message.
+    pub producer: String,
+    /// Trace context of the producer, as written by the OpenTelemetry propagator.
+    #[serde(default)]
+    pub trace_context: HashMap<String, String>,
+    pub payload: T,
+}
+
+impl<T: Message> Envelope<T> {
+    /// Wraps `payload` into a new envelope. The context of the current span is attached as trace context.
+    pub fn new(producer: &str, payload: T) -> Self {
+        wrap::<T, T>(producer, payload)
+    }
+}
+
+fn wrap<T: Message, P>(producer: &str, payload: P) -> Envelope<P> {
+    Envelope {
+        id: uuid::Uuid::new_v4().to_string(),
+        message_type: T::TYPE.to_owned(),
+        version: T::VERSION,
+        produced_at: Utc::now(),
+        producer: producer.to_owned(),
+        trace_context: propagation::inject_map(&Span::current()),
+        payload,
+    }
+}
+
+pub(crate) fn encode<T: Message>(producer: &str, message: &T) -> Vec<u8> {
+    serde_json::to_vec(&wrap::<T, _>(producer, message)).unwrap()
+}
+
+/// Reads a message of type `T` in any version.
+///
+/// Messages sent before envelopes were introduced are read as version 1. Older versions are migrated with
+/// [`Message::migrate`], newer ones are read as far as the current schema knows them. The current span is attached to
+/// the trace context of the envelope.
+pub(crate) fn decode<T: Message>(data: &[u8]) -> Result<Envelope<T>, String> {
+    let value: Value = serde_json::from_slice(data).map_err(|err| err.to_string())?;
+    let envelope = if is_envelope(&value) {
+        serde_json::from_value::<Envelope<Value>>(value).map_err(|err| err.to_string())?
+    } else {
+        Envelope {
+            id: uuid::Uuid::new_v4().to_string(),
+            message_type: T::TYPE.to_owned(),
+            version: LEGACY_VERSION,
+            produced_at: Utc::now(),
+            producer: "unknown".to_owned(),
+            trace_context: HashMap::new(),
+            payload: value,
+        }
+    };
+
+    if envelope.message_type != T::TYPE {
+        return Err(format!(
+            "expected message of type {}, got {}",
+            T::TYPE,
+            envelope.message_type
+        ));
+    }
+    if !envelope.trace_context.is_empty() {
+        propagation::set_parent(&Span::current(), &envelope.trace_context);
+    }
+
+    let payload = if envelope.version < T::VERSION {
+        debug!(
+            "Migrating {} from version {} to {}",
+            T::TYPE,
+            envelope.version,
+            T::VERSION
+        );
+        T::migrate(envelope.version, envelope.payload)?
+    } else {
+        envelope.payload
+    };
+
+    Ok(Envelope {
+        id: envelope.id,
+        message_type: envelope.message_type,
+        version: envelope.version,
+        produced_at: envelope.produced_at,
+        producer: envelope.producer,
+        trace_context: envelope.trace_context,
+        payload: serde_json::from_value(payload).map_err(|err| err.to_string())?,
+    })
+}
+
+fn is_envelope(value: &Value) -> bool {
+    ["type", "version", "payload"]
+        .iter()
+        .all(|key| value.get(key).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Greeting {
+        name: String,
+        #[serde(default)]
+        loud: bool,
+    }
+
+    impl Message for Greeting {
+        const TYPE: &'static str = "greeting";
+        const VERSION: u32 = 2;
+
+        fn migrate(version: u32, mut payload: Value) -> Result<Value, String> {
+            if version < 2 {
+                payload["name"] = payload["who"].take();
+            }
+            Ok(payload)
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let greeting = Greeting {
+            name: "alice".to_owned(),
+            loud: true,
+        };
+        let envelope = decode::<Greeting>(&encode("test", &greeting)).unwrap();
+        assert_eq!(envelope.payload, greeting);
+        assert_eq!(envelope.producer, "test");
+        assert_eq!(envelope.version, 2);
+    }
+
+    #[test]
+    fn test_reads_old_and_new_versions() {
+        let legacy = decode::<Greeting>(br#"{"who": "bob"}"#).unwrap();
+        assert_eq!(legacy.payload.name, "bob");
+
+        let newer = br#"{"id": "1", "type": "greeting", "version": 3, "produced_at": "2024-01-01T00:00:00Z",
+            "producer": "test", "payload": {"name": "carol", "loud": false, "emoji": "wave"}}"#;
+        assert_eq!(decode::<Greeting>(newer).unwrap().payload.name, "carol");
+    }
+
+    #[test]
+    fn test_rejects_other_types() {
+        let other = br#"{"id": "1", "type": "farewell", "version": 1, "produced_at": "2024-01-01T00:00:00Z",
+            "producer": "test", "payload": {"name": "dave"}}"#;
+        assert!(decode::<Greeting>(other).is_err());
+    }
+}
@@ -1,20 +1,197 @@
-use std::{future::Future, sync::Arc};
+use std::{fmt, future::Future, time::Duration};
+
+use envelope::Message;
+use routing::Route;
+use serde::{de::DeserializeOwned, Serialize};
 
 use models::{
-    AIPlayerRegister, CreateMatch, CreatedMatch, GameServerCreate, MatchAbrubtClose, MatchResult,
-    Task,
+    AIPlayerRegister, CreateMatch, CreatedMatch, GameRegister, GameServerCreate, HealthReport,
+    MatchAbrubtClose, MatchCancelled, MatchResult, MatchStarted, PlayerDisconnected, PlayerJoined,
+    PlayerReconnected, ServerStateChange, Task,
 };
 
+pub mod consumers;
+pub mod dedup;
+pub mod envelope;
+#[cfg(feature = "memory")]
+pub mod memory;
 pub mod models;
+pub mod outbox;
+mod propagation;
+pub mod queues;
 pub mod rabbitmq;
+#[cfg(feature = "redis-streams")]
+pub mod redis_streams;
+pub mod routing;
+#[cfg(feature = "redis-streams")]
+pub mod transport;
 
 pub trait MessageHandler<T, Fut>: Fn(T) -> Fut + Send + Sync + 'static + Clone {}
 
 impl<T, Fut, F> MessageHandler<T, Fut> for F where F: Fn(T) -> Fut + Send + Sync + 'static + Clone {}
 
+/// Returned by a message handler which could not handle a message. Decides what the communicator does with the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandlerError {
+    /// Handling failed for a reason which may go away, e.g. an unreachable service.
+    /// The message is delivered again until the retry limit of its queue is reached.
+    Retry(String),
+    /// The message can never be handled, e.g. because it references something which does not exist.
+    /// The message is dead-lettered without being retried.
+    Reject(String),
+}
+
+impl HandlerError {
+    pub fn retry(err: impl fmt::Display) -> Self {
+        Self::Retry(err.to_string())
+    }
+
+    pub fn reject(err: impl fmt::Display) -> Self {
+        Self::Reject(err.to_string())
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Retry(err) => write!(f, "{} (retryable)", err),
+            Self::Reject(err) => write!(f, "{} (rejected)", err),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+/// What a message handler returns. On `Ok` the message is acknowledged.
+pub type HandlerResult<T = ()> = Result<T, HandlerError>;
+
+/// A message which is answered by its handler, see [`Communicator::request`].
+pub trait Request: Message + Sync {
+    type Response: Serialize + DeserializeOwned + Send + 'static;
+
+    /// Entity and action of the queue the request is sent to, as named in the queues file.
+    const QUEUE: (&'static str, &'static str);
+}
+
+impl Request for GameServerCreate {
+    /// The id of the saved game server.
+    type Response = String;
+
+    const QUEUE: (&'static str, &'static str) = ("game", "create");
+}
+
+impl Request for GameRegister {
+    /// The id of the saved game.
+    type Response = String;
+
+    const QUEUE: (&'static str, &'static str) = ("game", "register");
+}
+
+/// An event in the lifecycle of a running match or game server, see [`Communicator::on_event`].
+pub trait Event: Message + Sync {
+    /// Entity and action of the queue the event is sent to, as named in the queues file.
+    const QUEUE: (&'static str, &'static str);
+}
+
+impl Event for MatchStarted {
+    const QUEUE: (&'static str, &'static str) = ("match", "started");
+}
+
+impl Event for PlayerJoined {
+    const QUEUE: (&'static str, &'static str) = ("match", "player_joined");
+}
+
+impl Event for PlayerDisconnected {
+    const QUEUE: (&'static str, &'static str) = ("match", "player_disconnected");
+}
+
+impl Event for PlayerReconnected {
+    const QUEUE: (&'static str, &'static str) = ("match", "player_reconnected");
+}
+
+impl Event for MatchCancelled {
+    const QUEUE: (&'static str, &'static str) = ("match", "cancelled");
+}
+
+impl Event for ServerStateChange {
+    const QUEUE: (&'static str, &'static str) = ("game", "state");
+}
+
+/// Why a [`Communicator::request`] did not return a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    /// No response arrived before the request timed out.
+    Timeout,
+    /// The handler of the request failed for good.
+    Failed(String),
+    /// The response could not be decoded.
+    Malformed(String),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "request timed out"),
+            Self::Failed(err) => write!(f, "request failed: {}", err),
+            Self::Malformed(err) => write!(f, "malformed response: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Why a message could not be published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublishError {
+    /// The broker could not be reached or closed the connection before confirming the message.
+    Unavailable(String),
+    /// The broker refused the message.
+    Nack,
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unavailable(err) => write!(f, "broker unavailable: {}", err),
+            Self::Nack => write!(f, "message was not confirmed by the broker"),
+        }
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+pub(crate) const DEFAULT_PUBLISH_ATTEMPTS: u32 = 5;
+pub(crate) const DEFAULT_PUBLISH_BACKOFF: Duration = Duration::from_millis(200);
+const MAX_PUBLISH_BACKOFF: Duration = Duration::from_secs(5);
+
+/// How long to wait before the publish attempt after `attempt` failed ones. Doubles with every attempt.
+pub(crate) fn publish_backoff(base: Duration, attempt: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_PUBLISH_BACKOFF)
+}
+
+/// Encodes the response to a request. Failures are sent as `{"Err": "<error>"}`.
+pub(crate) fn encode_response<T: Serialize>(response: &Result<T, String>) -> Vec<u8> {
+    serde_json::to_vec(response).unwrap()
+}
+
+pub(crate) fn decode_response<T: DeserializeOwned>(data: &[u8]) -> Result<T, RequestError> {
+    serde_json::from_slice::<Result<T, String>>(data)
+        .map_err(|err| RequestError::Malformed(err.to_string()))?
+        .map_err(RequestError::Failed)
+}
+
 /// Handles communication between the game server and the matchmaker.
 /// Structs which implement the Communicator trait enable multi-device communication.
 /// This means that if a callback is registered with an "on"-function, it may be called when any device calls a "report" or "send" function.
+///
+/// Models are sent wrapped in an [`Envelope`](envelope::Envelope) and consumers read every version of them.
+///
+/// Callbacks return a [`HandlerResult`]. A message is only acknowledged after its callback succeeded;
+/// the [`HandlerError`] of a failed callback decides whether it is delivered again or dead-lettered.
+///
+/// Functions which send a message return once the message was handed over to the broker and fail with a
+/// [`PublishError`] if that was not possible.
 pub trait Communicator
 where
     Self: Sized,
@@ -27,7 +204,7 @@ where
     async fn on_match_abrupt_close<F, Fut>(&self, callback: F)
     where
         F: MessageHandler<MatchAbrubtClose, Fut>,
-        Fut: Future<Output = ()> + Send + 'static;
+        Fut: Future<Output = HandlerResult> + Send + 'static;
 
     /// Registers a callback for when a match result is reported.
     ///
@@ -37,7 +214,7 @@ where
     async fn on_match_result<F, Fut>(&self, callback: F)
     where
         F: MessageHandler<MatchResult, Fut>,
-        Fut: Future<Output = ()> + Send + 'static;
+        Fut: Future<Output = HandlerResult> + Send + 'static;
 
     /// Registers a callback for when a match is created.
     ///
@@ -47,27 +224,157 @@ where
     async fn on_match_created<F, Fut>(&self, callback: F)
     where
         F: MessageHandler<CreatedMatch, Fut>,
-        Fut: Future<Output = ()> + Send + 'static;
+        Fut: Future<Output = HandlerResult> + Send + 'static;
+
+    /// Registers a callback for events of type `E`.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function that handles `E` events.
+    async fn on_event<E, F, Fut>(&self, callback: F)
+    where
+        E: Event,
+        F: MessageHandler<E, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static;
+
+    /// Registers a callback for when all players joined a match and it started.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function that handles `MatchStarted` events.
+    async fn on_match_started<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<MatchStarted, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.on_event(callback).await
+    }
+
+    /// Registers a callback for when a player joined a match.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function that handles `PlayerJoined` events.
+    async fn on_player_joined<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<PlayerJoined, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.on_event(callback).await
+    }
+
+    /// Registers a callback for when a player lost the connection to a match.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function that handles `PlayerDisconnected` events.
+    async fn on_player_disconnected<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<PlayerDisconnected, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.on_event(callback).await
+    }
+
+    /// Registers a callback for when a disconnected player is back in a match.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function that handles `PlayerReconnected` events.
+    async fn on_player_reconnected<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<PlayerReconnected, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.on_event(callback).await
+    }
+
+    /// Registers a callback for when a match is cancelled.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function that handles `MatchCancelled` events.
+    async fn on_match_cancelled<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<MatchCancelled, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.on_event(callback).await
+    }
+
+    /// Registers a callback for when a game server is asked to change its state.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function that handles `ServerStateChange` events.
+    async fn on_server_state_change<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<ServerStateChange, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.on_event(callback).await
+    }
 
     /// Registers a callback for when a game server is created.
     ///
     /// # Arguments
     ///
-    /// * `callback` - A function that handles `GameServerCreate` events.
+    /// * `callback` - A function that handles `GameServerCreate` events and returns the id of the saved server.
     async fn on_game_create<F, Fut>(&self, callback: F)
     where
         F: MessageHandler<GameServerCreate, Fut>,
-        Fut: Future<Output = String> + Send + 'static;
+        Fut: Future<Output = HandlerResult<String>> + Send + 'static,
+    {
+        self.on_request(callback).await
+    }
+
+    /// Registers a callback for when a game is added to the catalog.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function that handles `GameRegister` requests and returns the id of the saved game.
+    async fn on_game_register<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<GameRegister, Fut>,
+        Fut: Future<Output = HandlerResult<String>> + Send + 'static,
+    {
+        self.on_request(callback).await
+    }
+
+    /// Registers a callback which answers requests of type `R`.
+    ///
+    /// The response is sent back to the requester once the callback succeeded. If the callback rejects the request
+    /// or still fails after all retries, the requester receives the error instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - A function that handles `R` and returns its response.
+    async fn on_request<R, F, Fut>(&self, callback: F)
+    where
+        R: Request,
+        F: MessageHandler<R, Fut>,
+        Fut: Future<Output = HandlerResult<R::Response>> + Send + 'static;
+
+    /// Sends a request and waits for the response of the callback registered with [`Communicator::on_request`].
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request to send.
+    ///
+    /// # Returns
+    ///
+    /// The response or why there is none, e.g. because no response arrived before the request timed out.
+    async fn request<R: Request>(&self, request: &R) -> Result<R::Response, RequestError>;
 
     /// Registers a callback for health check events.
     ///
     /// # Arguments
     ///
-    /// * `callback` - A function that handles health check events.
+    /// * `callback` - A function that handles `HealthReport` events.
     async fn on_health_check<F, Fut>(&self, callback: F)
     where
-        F: MessageHandler<String, Fut>,
-        Fut: Future<Output = ()> + Send + 'static;
+        F: MessageHandler<HealthReport, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static;
 
     /// Registers a callback for when a match creation request is received.
     ///
@@ -77,7 +384,23 @@ where
     async fn on_match_create<F, Fut>(&self, callback: F)
     where
         F: MessageHandler<CreateMatch, Fut>,
-        Fut: Future<Output = ()> + Send + 'static;
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.on_match_create_for(Route::default(), callback).await
+    }
+
+    /// Registers a callback for the match creation requests matching `route`, e.g. only those of one game.
+    /// Callbacks registered with the same route compete for the requests, callbacks with different routes each
+    /// receive the requests matching them.
+    ///
+    /// # Arguments
+    ///
+    /// * `route` - The game, mode and region of the requests. Fields which are `None` match every value.
+    /// * `callback` - A function that handles `CreateMatch` events.
+    async fn on_match_create_for<F, Fut>(&self, route: Route, callback: F)
+    where
+        F: MessageHandler<CreateMatch, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static;
 
     /// Registers a callback for when a new ai-player is registered.
     ///
@@ -87,9 +410,9 @@ where
     async fn on_ai_register<F, Fut>(&self, callback: F)
     where
         F: MessageHandler<AIPlayerRegister, Fut>,
-        Fut: Future<Output = ()> + Send + 'static;
+        Fut: Future<Output = HandlerResult> + Send + 'static;
 
-    /// Creates a game on the game server.
+    /// Registers a game server at the matchmaker.
     ///
     /// # Arguments
     ///
@@ -98,57 +421,143 @@ where
     /// # Returns
     ///
     /// A result containing the game server ID or an error.
-    async fn create_game(
-        &self,
-        game_server: &GameServerCreate,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+    async fn create_game(&self, game_server: &GameServerCreate) -> Result<String, RequestError> {
+        self.request(game_server).await
+    }
 
-    /// Sends a health check to the specified client.
+    /// Adds a game and its modes to the catalog. Searches are only accepted for registered game modes.
     ///
     /// # Arguments
     ///
-    /// * `client_id` - The ID of the client to send the health check to.
-    async fn send_health_check(&self, client_id: String);
+    /// * `game` - The game and its modes.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the game ID or an error.
+    async fn register_game(&self, game: &GameRegister) -> Result<String, RequestError> {
+        self.request(game).await
+    }
+
+    /// Sends a health check of a game server.
+    ///
+    /// # Arguments
+    ///
+    /// * `report` - The id of the server and how busy it is.
+    async fn send_health_check(&self, report: &HealthReport) -> Result<(), PublishError>;
 
     /// Creates a match based on the provided match request.
     ///
     /// # Arguments
     ///
     /// * `match_request` - The match creation request.
-    async fn create_match(&self, match_request: &CreateMatch);
+    async fn create_match(&self, match_request: &CreateMatch) -> Result<(), PublishError>;
 
     /// Reports that a match has been created.
     ///
     /// # Arguments
     ///
     /// * `created_match` - The created match information.
-    async fn report_match_created(&self, created_match: &CreatedMatch);
+    async fn report_match_created(&self, created_match: &CreatedMatch) -> Result<(), PublishError>;
 
     /// Reports the result of a match.
     ///
     /// # Arguments
     ///
     /// * `match_result` - The match result information.
-    async fn report_match_result(&self, match_result: &MatchResult);
+    async fn report_match_result(&self, match_result: &MatchResult) -> Result<(), PublishError>;
 
     /// Reports that a match was abruptly closed.
     ///
     /// # Arguments
     ///
     /// * `match_close` - The abrupt match close information.
-    async fn report_match_abrupt_close(&self, match_close: &MatchAbrubtClose);
+    async fn report_match_abrupt_close(&self, match_close: &MatchAbrubtClose) -> Result<(), PublishError>;
+
+    /// Reports an event to the callbacks registered with [`Communicator::on_event`].
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event to report.
+    async fn report_event<E: Event>(&self, event: &E) -> Result<(), PublishError>;
+
+    /// Reports that all players joined a match and it started.
+    ///
+    /// # Arguments
+    ///
+    /// * `match_started` - The started match.
+    async fn report_match_started(&self, match_started: &MatchStarted) -> Result<(), PublishError> {
+        self.report_event(match_started).await
+    }
+
+    /// Reports that a player joined a match.
+    ///
+    /// # Arguments
+    ///
+    /// * `player_joined` - The match and the player who joined it.
+    async fn report_player_joined(&self, player_joined: &PlayerJoined) -> Result<(), PublishError> {
+        self.report_event(player_joined).await
+    }
+
+    /// Reports that a player lost the connection to a match.
+    ///
+    /// # Arguments
+    ///
+    /// * `player_disconnected` - The match and the player who disconnected.
+    async fn report_player_disconnected(
+        &self,
+        player_disconnected: &PlayerDisconnected,
+    ) -> Result<(), PublishError> {
+        self.report_event(player_disconnected).await
+    }
+
+    /// Reports that a disconnected player is back in a match.
+    ///
+    /// # Arguments
+    ///
+    /// * `player_reconnected` - The match and the player who reconnected.
+    async fn report_player_reconnected(
+        &self,
+        player_reconnected: &PlayerReconnected,
+    ) -> Result<(), PublishError> {
+        self.report_event(player_reconnected).await
+    }
+
+    /// Reports that a match was cancelled.
+    ///
+    /// # Arguments
+    ///
+    /// * `match_cancelled` - The cancelled match and why it was cancelled.
+    async fn report_match_cancelled(
+        &self,
+        match_cancelled: &MatchCancelled,
+    ) -> Result<(), PublishError> {
+        self.report_event(match_cancelled).await
+    }
+
+    /// Asks for a game server to be moved to another state, e.g. to drain it before it is shut down.
+    ///
+    /// # Arguments
+    ///
+    /// * `change` - The server and the state it is moved to.
+    async fn change_server_state(&self, change: &ServerStateChange) -> Result<(), PublishError> {
+        self.report_event(change).await
+    }
 
     /// Sends a message to create a new AI-Task.
     ///
     /// # Arguments
     ///
     /// * `task` - The AI task information.
-    async fn create_ai_task(&self, task: &Task);
-    
+    async fn create_ai_task(&self, task: &Task) -> Result<(), PublishError>;
+
     /// Sends a message to create a new AI-Player.
     ///
     /// # Arguments
     ///
     /// * `ai_player` - AI-Player Information.
-    async fn register_ai_player(&self, ai_player: &AIPlayerRegister);
+    async fn register_ai_player(&self, ai_player: &AIPlayerRegister) -> Result<(), PublishError>;
+
+    /// Stops listening for new messages, waits until the messages which are already being handled are processed
+    /// and acknowledged and closes the connections. No callbacks are called after this returns.
+    async fn shutdown(&self);
 }
@@ -0,0 +1,577 @@
+use futures_lite::Future;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, warn};
+
+use crate::{
+    consumers::health_report,
+    decode_response, encode_response,
+    envelope::{self, Message},
+    models::{
+        AIPlayerRegister, CreateMatch, CreatedMatch, HealthReport, MatchAbrubtClose, MatchResult,
+        Task,
+    },
+    queues::DEFAULT_MAX_RETRIES,
+    routing::{self, Route},
+    Event, HandlerError, HandlerResult, MessageHandler, PublishError, Request, RequestError,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const PRODUCER: &str = "memory";
+
+/// A serialized message as it is kept in a queue.
+#[derive(Clone)]
+struct Delivery {
+    data: Vec<u8>,
+    /// How often the handler of the message already failed.
+    failures: u32,
+    /// Where the response goes if the message is a request.
+    reply_to: Option<UnboundedSender<Vec<u8>>>,
+}
+
+impl Delivery {
+    fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            failures: 0,
+            reply_to: None,
+        }
+    }
+
+    fn reply(&self, response: Vec<u8>) {
+        if let Some(reply_to) = self.reply_to.as_ref() {
+            // The requester may have timed out already
+            let _ = reply_to.send(response);
+        }
+    }
+}
+
+/// A queue of serialized messages. Every message is handled by exactly one of the consumers of the queue.
+struct Queue {
+    sender: UnboundedSender<Delivery>,
+    receiver: Arc<tokio::sync::Mutex<UnboundedReceiver<Delivery>>>,
+}
+
+impl Queue {
+    fn new() -> Self {
+        let (sender, receiver) = unbounded_channel();
+        Self {
+            sender,
+            receiver: Arc::new(tokio::sync::Mutex::new(receiver)),
+        }
+    }
+}
+
+/// In-process implementation of the [`Communicator`](crate::Communicator) for tests and single-node deployments.
+///
+/// Messages are serialized like they would be for a broker and are kept in memory until a consumer takes them.
+/// Clones share the same queues, so services which run in the same process can communicate by using clones of
+/// one `MemoryCommunicator`.
+///
+/// Messages whose handler asked for a retry are queued again up to three times. There is no dead-letter queue,
+/// so messages which are rejected or still fail afterwards are logged and dropped.
+/// Requests time out after ten seconds.
+#[derive(Clone, Default)]
+pub struct MemoryCommunicator {
+    queues: Arc<Mutex<HashMap<String, Queue>>>,
+    shutdown: CancellationToken,
+    consumers: TaskTracker,
+}
+
+impl MemoryCommunicator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_queue<R>(&self, name: &str, f: impl FnOnce(&Queue) -> R) -> R {
+        let mut queues = self.queues.lock().unwrap();
+        f(queues.entry(name.to_owned()).or_insert_with(Queue::new))
+    }
+
+    /// Sends `message` to the queue of all messages of `topic` and to the queues of the routes it matches, like a
+    /// topic exchange would. If none of these queues exist yet, the message is kept in the queue of all messages.
+    fn send_routed(&self, topic: &str, route: &Route, message: Delivery) {
+        let routing_key = route.routing_key(topic);
+        let mut queues = self.queues.lock().unwrap();
+        let mut delivered = false;
+        for (pattern, queue) in queues.iter() {
+            if pattern == topic || routing::matches(pattern, &routing_key) {
+                let _ = queue.sender.send(message.clone());
+                delivered = true;
+            }
+        }
+        if !delivered {
+            let queue = queues.entry(topic.to_owned()).or_insert_with(Queue::new);
+            let _ = queue.sender.send(message);
+        }
+    }
+
+    fn publish_json<T: Message>(&self, topic: &str, message: &T) {
+        debug!("Publishing to in-memory topic {}", topic);
+        self.send_routed(
+            topic,
+            &message.route(),
+            Delivery::new(envelope::encode(PRODUCER, message)),
+        );
+    }
+
+    /// Spawns a consumer which passes every message of `queue` to `on_message` until the communicator is shut down.
+    /// If `on_message` returns a response, it is sent to the requester.
+    fn listen<F, Fut>(&self, queue: &str, on_message: F)
+    where
+        F: Fn(Vec<u8>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HandlerResult<Option<Vec<u8>>>> + Send + 'static,
+    {
+        let queue = queue.to_owned();
+        let (sender, receiver) =
+            self.with_queue(&queue, |q| (q.sender.clone(), q.receiver.clone()));
+        let shutdown = self.shutdown.clone();
+        let in_flight = self.consumers.clone();
+
+        self.consumers.spawn(async move {
+            loop {
+                let message = tokio::select! {
+                    _ = shutdown.cancelled() => return,
+                    message = async { receiver.lock().await.recv().await } => message,
+                };
+                match message {
+                    Some(mut message) => {
+                        let handler = on_message(message.data.clone());
+                        let sender = sender.clone();
+                        let queue = queue.clone();
+                        in_flight.spawn(async move {
+                            match handler.await {
+                                Ok(response) => {
+                                    if let Some(response) = response {
+                                        message.reply(response);
+                                    }
+                                }
+                                Err(HandlerError::Retry(err))
+                                    if message.failures < DEFAULT_MAX_RETRIES =>
+                                {
+                                    warn!("Handler for {} failed, retrying: {}", queue, err);
+                                    message.failures += 1;
+                                    let _ = sender.send(message);
+                                }
+                                Err(err) => {
+                                    error!("Dropping message from {}: {}", queue, err);
+                                    message.reply(encode_response::<()>(&Err(err.to_string())));
+                                }
+                            }
+                        });
+                    }
+                    None => return,
+                }
+            }
+        });
+    }
+
+    /// Passes the messages of `topic` which match `route` to `callback`.
+    fn listen_json<T, F, Fut, R>(&self, topic: &str, route: &Route, callback: F)
+    where
+        T: Message,
+        F: MessageHandler<T, Fut>,
+        Fut: Future<Output = HandlerResult<R>> + Send + 'static,
+    {
+        let queue = match route.is_any() {
+            true => topic.to_owned(),
+            false => route.binding(topic),
+        };
+        self.listen(&queue, move |data| {
+            let callback = callback.clone();
+            async move {
+                let message = envelope::decode::<T>(&data).map_err(HandlerError::Reject)?;
+                callback(message.payload).await.map(|_| None)
+            }
+        });
+    }
+}
+
+impl super::Communicator for MemoryCommunicator {
+    async fn on_match_abrupt_close<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<MatchAbrubtClose, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.listen_json("match.abrupt_close", &Route::default(), callback);
+    }
+
+    async fn on_match_result<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<MatchResult, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.listen_json("match.result", &Route::default(), callback);
+    }
+
+    async fn on_match_created<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<CreatedMatch, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.listen_json("match.created", &Route::default(), callback);
+    }
+
+    async fn on_health_check<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<HealthReport, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.listen("health_check.check", move |data| {
+            let callback = callback.clone();
+            async move {
+                let (_, report) = health_report(&data).map_err(HandlerError::Reject)?;
+                callback(report).await.map(|_| None)
+            }
+        });
+    }
+
+    async fn on_match_create_for<F, Fut>(&self, route: Route, callback: F)
+    where
+        F: MessageHandler<CreateMatch, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.listen_json("match.create", &route, callback);
+    }
+
+    async fn on_ai_register<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<AIPlayerRegister, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.listen_json("ai.register", &Route::default(), callback);
+    }
+
+    async fn on_event<E, F, Fut>(&self, callback: F)
+    where
+        E: Event,
+        F: MessageHandler<E, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        let (name, action) = E::QUEUE;
+        self.listen_json(&format!("{}.{}", name, action), &Route::default(), callback);
+    }
+
+    async fn on_request<R, F, Fut>(&self, callback: F)
+    where
+        R: Request,
+        F: MessageHandler<R, Fut>,
+        Fut: Future<Output = HandlerResult<R::Response>> + Send + 'static,
+    {
+        let (name, action) = R::QUEUE;
+        self.listen(&format!("{}.{}", name, action), move |data| {
+            let callback = callback.clone();
+            async move {
+                let request = envelope::decode::<R>(&data).map_err(HandlerError::Reject)?;
+                let response = callback(request.payload).await?;
+                Ok(Some(encode_response::<R::Response>(&Ok(response))))
+            }
+        });
+    }
+
+    async fn request<R: Request>(&self, request: &R) -> Result<R::Response, RequestError> {
+        let (reply_to, mut responses) = unbounded_channel();
+        let (name, action) = R::QUEUE;
+        self.send_routed(
+            &format!("{}.{}", name, action),
+            &request.route(),
+            Delivery {
+                reply_to: Some(reply_to),
+                ..Delivery::new(envelope::encode(PRODUCER, request))
+            },
+        );
+
+        match tokio::time::timeout(REQUEST_TIMEOUT, responses.recv()).await {
+            Ok(Some(response)) => decode_response(&response),
+            _ => Err(RequestError::Timeout),
+        }
+    }
+
+    async fn send_health_check(&self, report: &HealthReport) -> Result<(), PublishError> {
+        self.publish_json("health_check.check", report);
+        Ok(())
+    }
+
+    async fn create_match(&self, match_request: &CreateMatch) -> Result<(), PublishError> {
+        self.publish_json("match.create", match_request);
+        Ok(())
+    }
+
+    async fn report_match_created(&self, created_match: &CreatedMatch) -> Result<(), PublishError> {
+        self.publish_json("match.created", created_match);
+        Ok(())
+    }
+
+    async fn report_match_result(&self, match_result: &MatchResult) -> Result<(), PublishError> {
+        self.publish_json("match.result", match_result);
+        Ok(())
+    }
+
+    async fn report_match_abrupt_close(&self, match_close: &MatchAbrubtClose) -> Result<(), PublishError> {
+        self.publish_json("match.abrupt_close", match_close);
+        Ok(())
+    }
+
+    async fn report_event<E: Event>(&self, event: &E) -> Result<(), PublishError> {
+        let (name, action) = E::QUEUE;
+        self.publish_json(&format!("{}.{}", name, action), event);
+        Ok(())
+    }
+
+    async fn create_ai_task(&self, task: &Task) -> Result<(), PublishError> {
+        self.publish_json("ai.task", task);
+        Ok(())
+    }
+
+    async fn register_ai_player(&self, ai_player: &AIPlayerRegister) -> Result<(), PublishError> {
+        self.publish_json("ai.register", ai_player);
+        Ok(())
+    }
+
+    async fn shutdown(&self) {
+        self.shutdown.cancel();
+        self.consumers.close();
+        self.consumers.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        models::{GameServerCreate, PlayerDisconnected, PlayerJoined, RankingConf},
+        Communicator,
+    };
+
+    fn create_match() -> CreateMatch {
+        CreateMatch {
+            game: "chess".to_owned(),
+            players: vec!["player1".to_owned(), "player2".to_owned()],
+            ai_players: vec![],
+            mode: "ranked".to_owned(),
+            server: None,
+            teams: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_reaches_clone() {
+        let sender = MemoryCommunicator::new();
+        let receiver = sender.clone();
+
+        let (tx, mut rx) = unbounded_channel();
+        receiver
+            .on_match_create(move |request: CreateMatch| {
+                let tx = tx.clone();
+                async move {
+                    tx.send(request).unwrap();
+                    Ok(())
+                }
+            })
+            .await;
+
+        sender.create_match(&create_match()).await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.players, create_match().players);
+    }
+
+    #[tokio::test]
+    async fn test_events_reach_their_handlers() {
+        let communicator = MemoryCommunicator::new();
+
+        let (tx, mut rx) = unbounded_channel();
+        let joined = tx.clone();
+        communicator
+            .on_player_joined(move |event: PlayerJoined| {
+                let tx = joined.clone();
+                async move {
+                    tx.send(format!("joined {}", event.player_id)).unwrap();
+                    Ok(())
+                }
+            })
+            .await;
+        communicator
+            .on_player_disconnected(move |event: PlayerDisconnected| {
+                let tx = tx.clone();
+                async move {
+                    tx.send(format!("disconnected {}", event.player_id)).unwrap();
+                    Ok(())
+                }
+            })
+            .await;
+
+        communicator
+            .report_player_disconnected(&PlayerDisconnected {
+                match_id: "match-1".to_owned(),
+                player_id: "player1".to_owned(),
+            })
+            .await
+            .unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, "disconnected player1");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_message_is_kept_until_consumed() {
+        let communicator = MemoryCommunicator::new();
+        let report = HealthReport {
+            client_id: "server-1".to_owned(),
+            ..Default::default()
+        };
+        communicator.send_health_check(&report).await.unwrap();
+
+        let (tx, mut rx) = unbounded_channel();
+        communicator
+            .on_health_check(move |report: HealthReport| {
+                let tx = tx.clone();
+                async move {
+                    tx.send(report.client_id).unwrap();
+                    Ok(())
+                }
+            })
+            .await;
+
+        let received = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, "server-1");
+    }
+
+    #[tokio::test]
+    async fn test_message_is_handled_once() {
+        let communicator = MemoryCommunicator::new();
+
+        let (tx, mut rx) = unbounded_channel();
+        for _ in 0..2 {
+            let tx = tx.clone();
+            communicator
+                .on_match_create(move |_: CreateMatch| {
+                    let tx = tx.clone();
+                    async move {
+                        tx.send(()).unwrap();
+                        Ok(())
+                    }
+                })
+                .await;
+        }
+        drop(tx);
+
+        communicator.create_match(&create_match()).await.unwrap();
+        let first = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await;
+        assert_eq!(first.unwrap(), Some(()));
+
+        // Dropping the consumers closes the channel, so a second delivery would be received before `None`
+        communicator.shutdown().await;
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_failed_message_is_retried() {
+        let communicator = MemoryCommunicator::new();
+
+        let (tx, mut rx) = unbounded_channel();
+        communicator
+            .on_match_create(move |_: CreateMatch| {
+                let tx = tx.clone();
+                async move {
+                    tx.send(()).unwrap();
+                    Err(HandlerError::retry("state store unavailable"))
+                }
+            })
+            .await;
+
+        communicator.create_match(&create_match()).await.unwrap();
+        for _ in 0..=DEFAULT_MAX_RETRIES {
+            let attempt = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await;
+            assert_eq!(attempt.unwrap(), Some(()));
+        }
+
+        communicator.shutdown().await;
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_request_is_answered() {
+        let communicator = MemoryCommunicator::new();
+        communicator
+            .on_game_create(|server: GameServerCreate| async move {
+                match server.game.as_str() {
+                    "chess" => Ok("server-1".to_owned()),
+                    _ => Err(HandlerError::reject("unknown game")),
+                }
+            })
+            .await;
+
+        let mut server = GameServerCreate {
+            region: "eu".to_owned(),
+            game: "chess".to_owned(),
+            mode: "ranked".to_owned(),
+            min_players: 2,
+            max_players: 2,
+            server_pub: "pub".to_owned(),
+            server_priv: "priv".to_owned(),
+            ranking_conf: RankingConf {
+                max_stars: 5,
+                description: String::new(),
+                performances: vec![],
+            },
+        };
+        assert_eq!(communicator.create_game(&server).await.unwrap(), "server-1");
+
+        server.game = "go".to_owned();
+        assert_eq!(
+            communicator.create_game(&server).await,
+            Err(RequestError::Failed("unknown game (rejected)".to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_routed_subscription() {
+        let communicator = MemoryCommunicator::new();
+
+        let (tx, mut rx) = unbounded_channel();
+        communicator
+            .on_match_create_for(Route::game("go"), move |request: CreateMatch| {
+                let tx = tx.clone();
+                async move {
+                    tx.send(request.game).unwrap();
+                    Ok(())
+                }
+            })
+            .await;
+
+        communicator.create_match(&create_match()).await.unwrap();
+        communicator
+            .create_match(&CreateMatch {
+                game: "go".to_owned(),
+                ..create_match()
+            })
+            .await
+            .unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap();
+        assert_eq!(received.as_deref(), Some("go"));
+
+        communicator.shutdown().await;
+        assert_eq!(rx.recv().await, None);
+    }
+}
@@ -3,10 +3,67 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+use crate::{envelope::Message, routing::Route};
+
+macro_rules! message {
+    ($model:ty, $message_type:literal, $version:literal) => {
+        impl Message for $model {
+            const TYPE: &'static str = $message_type;
+            const VERSION: u32 = $version;
+        }
+    };
+    ($model:ty, $message_type:literal, $version:literal, $($field:ident),+) => {
+        impl Message for $model {
+            const TYPE: &'static str = $message_type;
+            const VERSION: u32 = $version;
+
+            fn route(&self) -> Route {
+                let mut route = Route::default();
+                $(route.$field = Some(self.$field.clone());)+
+                route
+            }
+        }
+    };
+}
+
+message!(MatchAbrubtClose, "match.abrupt_close", 2);
+message!(MatchResult, "match.result", 1);
+message!(CreatedMatch, "match.created", 2, game, mode, region);
+message!(GameServerCreate, "game.create", 1, game, mode, region);
+message!(GameRegister, "game.register", 1, game);
+message!(AIPlayerRegister, "ai.register", 1, game, mode);
+message!(Task, "ai.task", 1, game, mode);
+message!(HealthReport, "health_check", 1);
+message!(MatchStarted, "match.started", 1);
+message!(PlayerJoined, "match.player_joined", 1);
+message!(PlayerDisconnected, "match.player_disconnected", 1);
+message!(PlayerReconnected, "match.player_reconnected", 1);
+message!(MatchCancelled, "match.cancelled", 1);
+message!(ServerStateChange, "game.state", 1);
+
+/// Why a match was closed before it produced a result. Version 1 of `match.abrupt_close` only knew the first two.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum MatchError {
     AllPlayersDisconnected,
     PlayerDidNotJoin(String),
+    /// The game server crashed or lost the state of the match.
+    ServerCrash,
+    /// The match ran longer than the game server allows.
+    Timeout,
+    /// The game server caught the player cheating.
+    CheatingDetected(String),
+    /// An administrator stopped the match.
+    AdminAbort,
+}
+
+impl MatchError {
+    /// The player who is at fault for the close, if there is one.
+    pub fn offender(&self) -> Option<&str> {
+        match self {
+            Self::PlayerDidNotJoin(player) | Self::CheatingDetected(player) => Some(player),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -15,6 +72,79 @@ pub struct MatchAbrubtClose {
     pub reason: MatchError,
 }
 
+/// Sent by a game server to show it is alive, along with how busy it is.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct HealthReport {
+    /// The `server_priv` the game server registered with.
+    pub client_id: String,
+    #[serde(default)]
+    pub active_matches: u32,
+    /// Matches the server can run at once. 0 if the server doesn't know.
+    #[serde(default)]
+    pub capacity: u32,
+    /// CPU usage of the server between 0 and 1.
+    #[serde(default)]
+    pub cpu_load: f32,
+    /// Average round trip time between the server and its players.
+    #[serde(default)]
+    pub latency_ms: u32,
+    #[serde(default)]
+    pub version: String,
+}
+
+/// Sent by the game server once every player joined and the match is running.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MatchStarted {
+    pub match_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PlayerJoined {
+    pub match_id: String,
+    pub player_id: String,
+}
+
+/// Sent when a player lost the connection to a running match. The match waits for the player to reconnect.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PlayerDisconnected {
+    pub match_id: String,
+    pub player_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PlayerReconnected {
+    pub match_id: String,
+    pub player_id: String,
+}
+
+/// Sent when a match is called off before it produced a result, e.g. because it never started.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MatchCancelled {
+    pub match_id: String,
+    pub reason: String,
+}
+
+/// Where a game server is in its lifecycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerState {
+    /// Takes new matches.
+    #[default]
+    Active,
+    /// Takes no new matches, but runs its matches until they finish.
+    Draining,
+    /// Taken out of rotation by an administrator.
+    Maintenance,
+    /// Stopped sending health checks.
+    Dead,
+}
+
+/// Moves the game server with the id `server_id` to `state`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ServerStateChange {
+    pub server_id: String,
+    pub state: ServerState,
+}
 
 #[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct MatchResult {
@@ -40,6 +170,9 @@ pub struct CreatedMatch {
     pub read: String,
     pub url_pub: String,
     pub url_priv: String,
+    /// The teams of the [`CreateMatch`] the match was created from.
+    #[serde(default)]
+    pub teams: Vec<Vec<String>>,
 }
 
 #[derive(Deserialize, Debug, Clone, Serialize)]
@@ -67,6 +200,32 @@ pub struct Performance {
     pub weight: i32,
 }
 
+/// Adds a game and its modes to the catalog, replacing the modes registered for it before.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GameRegister {
+    pub game: String,
+    pub display_name: String,
+    pub modes: Vec<GameModeRegister>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GameModeRegister {
+    pub mode: String,
+    pub display_name: String,
+    pub min_players: u32,
+    pub max_players: u32,
+    /// Players per team, e.g. `[2, 2]` for 2v2. Empty if every player plays for themselves.
+    #[serde(default)]
+    pub team_sizes: Vec<u32>,
+    #[serde(default)]
+    pub ranked: bool,
+    #[serde(default)]
+    pub ai_allowed: bool,
+    /// Required for ranked modes.
+    #[serde(default)]
+    pub ranking_conf: Option<RankingConf>,
+}
+
 
 #[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct CreateMatch {
@@ -74,6 +233,27 @@ pub struct CreateMatch {
     pub players: Vec<String>,
     pub ai_players: Vec<String>,
     pub mode: String,
+    /// Id of the game server the match was allocated to. Version 1 matches were taken by any server.
+    #[serde(default)]
+    pub server: Option<String>,
+    /// Players and AI players of every team, in the order of the team sizes of the mode. Empty if the mode is not
+    /// played in teams.
+    #[serde(default)]
+    pub teams: Vec<Vec<String>>,
+}
+
+impl Message for CreateMatch {
+    const TYPE: &'static str = "match.create";
+    const VERSION: u32 = 3;
+
+    fn route(&self) -> Route {
+        Route {
+            game: Some(self.game.clone()),
+            mode: Some(self.mode.clone()),
+            region: None,
+            server: self.server.clone(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone, Serialize)]
@@ -84,7 +264,7 @@ pub struct AIPlayerRegister {
     pub display_name: String
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct Task {
     pub ai_id: String,
     pub game: String,
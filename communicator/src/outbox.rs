@@ -0,0 +1,215 @@
+use std::{collections::HashSet, future::Future};
+
+use chrono::{DateTime, Utc};
+use lapin::BasicProperties;
+use serde::{Deserialize, Serialize};
+
+use crate::PublishError;
+
+/// A published message which was not confirmed by the broker yet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub id: String,
+    pub exchange: String,
+    pub routing_key: String,
+    /// The encoded message, including its envelope.
+    pub data: Vec<u8>,
+    /// Headers and routing properties the message was published with.
+    #[serde(default)]
+    pub properties: BasicProperties,
+    pub created_at: DateTime<Utc>,
+}
+
+impl OutboxEntry {
+    pub fn new(
+        exchange: &str,
+        routing_key: &str,
+        data: Vec<u8>,
+        properties: BasicProperties,
+    ) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            exchange: exchange.to_owned(),
+            routing_key: routing_key.to_owned(),
+            data,
+            properties,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// Keeps published messages until the broker confirmed them, so they are not lost if the broker is unreachable or
+/// the publisher stops before the confirm arrived.
+///
+/// Entries are kept per `owner`, a publisher instance, so an instance only publishes its own entries again and
+/// never the ones another instance is still publishing.
+pub trait Outbox: Send + Sync + 'static {
+    /// Stores `entry` of `owner` until it is removed.
+    fn put(&self, owner: &str, entry: &OutboxEntry) -> Result<(), String>;
+
+    /// Removes the entry `id` of `owner` after its message was confirmed.
+    fn remove(&self, owner: &str, id: &str) -> Result<(), String>;
+
+    /// All stored entries of `owner`, in no particular order.
+    fn entries(&self, owner: &str) -> Result<Vec<OutboxEntry>, String>;
+}
+
+/// Hash the entries of `owner` are stored in.
+#[cfg(feature = "state")]
+fn key(owner: &str) -> String {
+    format!("outbox:{}", owner)
+}
+
+#[cfg(feature = "state")]
+impl Outbox for gn_matchmaking_state::adapters::redis::RedisAdapterDefault {
+    fn put(&self, owner: &str, entry: &OutboxEntry) -> Result<(), String> {
+        let value = serde_json::to_string(entry).map_err(|err| err.to_string())?;
+        self.hash_set(&key(owner), &entry.id, &value)
+            .map_err(|err| err.to_string())
+    }
+
+    fn remove(&self, owner: &str, id: &str) -> Result<(), String> {
+        self.hash_delete(&key(owner), id)
+            .map_err(|err| err.to_string())
+    }
+
+    fn entries(&self, owner: &str) -> Result<Vec<OutboxEntry>, String> {
+        self.hash_values(&key(owner))
+            .map_err(|err| err.to_string())?
+            .iter()
+            .map(|value| serde_json::from_str(value).map_err(|err| err.to_string()))
+            .collect()
+    }
+}
+
+/// Publishes the entries `owner` left in `outbox` oldest first and removes them once `publish` succeeded.
+/// Entries in `skip` are still being published by someone else and are left alone.
+///
+/// # Returns
+///
+/// The amount of published entries, or why flushing stopped early.
+pub(crate) async fn flush<F, Fut>(
+    outbox: &dyn Outbox,
+    owner: &str,
+    skip: &HashSet<String>,
+    publish: F,
+) -> Result<usize, String>
+where
+    F: Fn(OutboxEntry) -> Fut,
+    Fut: Future<Output = Result<(), PublishError>>,
+{
+    let mut entries = outbox.entries(owner)?;
+    entries.retain(|entry| !skip.contains(&entry.id));
+    entries.sort_by_key(|entry| entry.created_at);
+
+    let mut flushed = 0;
+    for entry in entries {
+        let id = entry.id.clone();
+        publish(entry).await.map_err(|err| err.to_string())?;
+        outbox.remove(owner, &id)?;
+        flushed += 1;
+    }
+    Ok(flushed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Stored(Mutex<HashMap<String, HashMap<String, OutboxEntry>>>);
+
+    impl Outbox for Stored {
+        fn put(&self, owner: &str, entry: &OutboxEntry) -> Result<(), String> {
+            self.0
+                .lock()
+                .unwrap()
+                .entry(owner.to_owned())
+                .or_default()
+                .insert(entry.id.clone(), entry.clone());
+            Ok(())
+        }
+
+        fn remove(&self, owner: &str, id: &str) -> Result<(), String> {
+            if let Some(entries) = self.0.lock().unwrap().get_mut(owner) {
+                entries.remove(id);
+            }
+            Ok(())
+        }
+
+        fn entries(&self, owner: &str) -> Result<Vec<OutboxEntry>, String> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .get(owner)
+                .map(|entries| entries.values().cloned().collect())
+                .unwrap_or_default())
+        }
+    }
+
+    fn entry(routing_key: &str, data: u8) -> OutboxEntry {
+        OutboxEntry::new(
+            "matchmaking",
+            routing_key,
+            vec![data],
+            BasicProperties::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_flushes_oldest_first_until_failure() {
+        let outbox = Stored::default();
+        let first = entry("match.create.a", 1);
+        let mut second = entry("match.create.b", 2);
+        second.created_at = first.created_at + chrono::Duration::seconds(1);
+        let in_flight = entry("match.create.c", 3);
+        for entry in [&second, &first, &in_flight] {
+            outbox.put("match-creator:0", entry).unwrap();
+        }
+        let other = entry("match.create.d", 4);
+        outbox.put("match-creator:1", &other).unwrap();
+
+        let published = Mutex::new(Vec::new());
+        let skip = HashSet::from([in_flight.id.clone()]);
+        let result = flush(&outbox, "match-creator:0", &skip, |entry| {
+            let failed = entry.data == vec![2];
+            published.lock().unwrap().push(entry.data);
+            async move {
+                match failed {
+                    true => Err(PublishError::Unavailable("connection closed".to_owned())),
+                    false => Ok(()),
+                }
+            }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(*published.lock().unwrap(), vec![vec![1], vec![2]]);
+        let mut left: Vec<_> = outbox
+            .entries("match-creator:0")
+            .unwrap()
+            .into_iter()
+            .map(|e| e.id)
+            .collect();
+        left.sort();
+        let mut expected = vec![second.id, in_flight.id];
+        expected.sort();
+        assert_eq!(left, expected);
+        assert_eq!(outbox.entries("match-creator:1").unwrap(), vec![other]);
+    }
+
+    #[test]
+    fn test_keeps_properties() {
+        let properties = BasicProperties::default()
+            .with_content_type("application/json".into())
+            .with_priority(5);
+        let stored = OutboxEntry::new("", "match.result", vec![1], properties.clone());
+
+        let value = serde_json::to_string(&stored).unwrap();
+        let read: OutboxEntry = serde_json::from_str(&value).unwrap();
+        assert_eq!(read.properties, properties);
+    }
+}
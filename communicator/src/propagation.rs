@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use lapin::types::{AMQPValue, FieldTable};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Writes the trace context into the headers of an AMQP message.
+struct HeaderInjector<'a>(&'a mut FieldTable);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0
+            .insert(key.into(), AMQPValue::LongString(value.into()));
+    }
+}
+
+/// Reads the trace context out of the headers of an AMQP message.
+struct HeaderExtractor<'a>(&'a FieldTable);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match self.0.inner().get(key)? {
+            AMQPValue::LongString(value) => std::str::from_utf8(value.as_bytes()).ok(),
+            _ => None,
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.inner().keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Injects the context of `span` into `headers` using the globally registered propagator.
+pub(crate) fn inject(span: &Span, headers: &mut FieldTable) {
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+/// Creates the span in which a received message is handled. If the message carries a trace context
+/// the span is attached to the trace of the producer.
+pub(crate) fn consumer_span(queue: &str, headers: Option<&FieldTable>) -> Span {
+    let span = tracing::info_span!("consume", queue = queue);
+    if let Some(headers) = headers {
+        let context = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(headers))
+        });
+        span.set_parent(context);
+    }
+    span
+}
+
+/// Returns the context of `span` as written by the globally registered propagator.
+pub(crate) fn inject_map(span: &Span) -> HashMap<String, String> {
+    let context = span.context();
+    let mut map = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut map));
+    map
+}
+
+/// Attaches `span` to the trace context in `map`.
+pub(crate) fn set_parent(span: &Span, map: &HashMap<String, String>) {
+    let context = global::get_text_map_propagator(|propagator| propagator.extract(map));
+    span.set_parent(context);
+}
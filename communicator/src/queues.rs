@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+pub(crate) const DEFAULT_MAX_RETRIES: u32 = 3;
+pub(crate) const DEFAULT_PREFETCH: u16 = 32;
+pub(crate) const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+
+/// Settings of a single queue.
+///
+/// In the queues file a queue is either given by its name only or by a map with the name and its options:
+///
+/// ```yaml
+/// match:
+///   created: "match-created"
+///   result:
+///     name: "match-result"
+///     dead_letter_exchange: "match-dead-letter"
+///     max_retries: 5
+///     prefetch: 10
+///     max_in_flight: 4
+///   create:
+///     name: "match-create-request"
+///     exchange: "matchmaking"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "QueueEntry")]
+pub struct QueueConfig {
+    pub name: String,
+    /// Topic exchange the messages are published to, routed by game, mode and region. If not set, messages are
+    /// published to the queue directly and consumers can't subscribe to a subset of them.
+    pub exchange: Option<String>,
+    /// Exchange messages are published to after they could not be handled. If not set, these messages are dropped.
+    pub dead_letter_exchange: Option<String>,
+    /// How often a message is redelivered after its handler failed before it is dead-lettered.
+    pub max_retries: u32,
+    /// How many unacknowledged messages the broker delivers to a consumer of the queue at once.
+    pub prefetch: u16,
+    /// How many handlers of the queue run at once. Further messages are not taken from the consumer until a
+    /// handler finished and its message was acknowledged, so the broker stops delivering once `prefetch` is reached.
+    pub max_in_flight: usize,
+}
+
+impl QueueConfig {
+    /// Name of the queue which is bound to the dead-letter exchange and keeps the dead letters of this queue.
+    pub fn dead_letter_queue(&self) -> String {
+        format!("{}.dead-letter", self.name)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum QueueEntry {
+    Name(String),
+    Options {
+        name: String,
+        #[serde(default)]
+        exchange: Option<String>,
+        #[serde(default)]
+        dead_letter_exchange: Option<String>,
+        #[serde(default = "default_max_retries")]
+        max_retries: u32,
+        #[serde(default = "default_prefetch")]
+        prefetch: u16,
+        #[serde(default = "default_max_in_flight")]
+        max_in_flight: usize,
+    },
+}
+
+fn default_max_retries() -> u32 {
+    DEFAULT_MAX_RETRIES
+}
+
+fn default_prefetch() -> u16 {
+    DEFAULT_PREFETCH
+}
+
+fn default_max_in_flight() -> usize {
+    DEFAULT_MAX_IN_FLIGHT
+}
+
+impl From<QueueEntry> for QueueConfig {
+    fn from(entry: QueueEntry) -> Self {
+        match entry {
+            QueueEntry::Name(name) => Self {
+                name,
+                exchange: None,
+                dead_letter_exchange: None,
+                max_retries: DEFAULT_MAX_RETRIES,
+                prefetch: DEFAULT_PREFETCH,
+                max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            },
+            QueueEntry::Options {
+                name,
+                exchange,
+                dead_letter_exchange,
+                max_retries,
+                prefetch,
+                max_in_flight,
+            } => Self {
+                name,
+                exchange,
+                dead_letter_exchange,
+                max_retries,
+                prefetch,
+                max_in_flight: max_in_flight.max(1),
+            },
+        }
+    }
+}
+
+/// Queues grouped by entity and action, e.g. `match` -> `created`.
+pub type Queues = HashMap<String, HashMap<String, QueueConfig>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_name_and_options() {
+        let queues: Queues = serde_yaml::from_str(
+            r#"
+match:
+  created: "match-created"
+  result:
+    name: "match-result"
+    dead_letter_exchange: "dead-letter"
+    exchange: "matchmaking"
+    max_in_flight: 4
+"#,
+        )
+        .unwrap();
+
+        let created = &queues["match"]["created"];
+        assert_eq!(created.name, "match-created");
+        assert_eq!(created.dead_letter_exchange, None);
+        assert_eq!(created.max_retries, DEFAULT_MAX_RETRIES);
+        assert_eq!(created.prefetch, DEFAULT_PREFETCH);
+
+        let result = &queues["match"]["result"];
+        assert_eq!(result.dead_letter_exchange.as_deref(), Some("dead-letter"));
+        assert_eq!(result.dead_letter_queue(), "match-result.dead-letter");
+        assert_eq!(result.exchange.as_deref(), Some("matchmaking"));
+        assert_eq!(result.max_in_flight, 4);
+    }
+}
@@ -1,19 +1,44 @@
 use futures_lite::{Future, StreamExt};
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use lapin::{
-    message::Delivery,
-    options::{BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, QueueDeclareOptions},
+    options::{
+        BasicCancelOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions,
+        ConfirmSelectOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+    },
     types::FieldTable,
-    BasicProperties, Channel, Connection,
+    BasicProperties, Channel, Connection, ExchangeKind,
 };
-use tracing::{debug, error, info, warn};
+use tokio::sync::{oneshot, OnceCell, Semaphore};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, info, info_span, warn, Instrument};
 
 use crate::{
-    models::{CreateMatch, CreatedMatch, GameServerCreate, MatchAbrubtClose, MatchResult},
-    MessageHandler,
+    decode_response,
+    dedup::{Dedup, Deduplicator},
+    encode_response,
+    envelope::{self, Message},
+    models::{CreateMatch, CreatedMatch, HealthReport, MatchAbrubtClose, MatchResult},
+    outbox::{self, Outbox, OutboxEntry},
+    propagation, publish_backoff,
+    queues::{QueueConfig, Queues, DEFAULT_MAX_IN_FLIGHT, DEFAULT_PREFETCH},
+    routing::Route,
+    Event, HandlerError, HandlerResult, MessageHandler, PublishError, Request, RequestError,
+    DEFAULT_PUBLISH_ATTEMPTS, DEFAULT_PUBLISH_BACKOFF,
 };
 
+use crate::consumers::{health_report, json, Decoder};
+pub use crate::consumers::{ConsumerStatus, ConsumerStatuses};
+
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_PRODUCER: &str = "unknown";
+
+mod settle;
+
 async fn try_connect(amqp_url: &str) -> Connection {
     loop {
         match Connection::connect(amqp_url, lapin::ConnectionProperties::default()).await {
@@ -26,19 +51,96 @@ async fn try_connect(amqp_url: &str) -> Connection {
     }
 }
 
+/// Opens a channel on which every published message is confirmed by the broker.
+async fn confirm_channel(conn: &Connection) -> Result<Channel, lapin::Error> {
+    let channel = conn.create_channel().await?;
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await?;
+    Ok(channel)
+}
+
+/// A queue a consumer reads from and how it is set up.
+struct Subscription {
+    queue: QueueConfig,
+    declare: QueueDeclareOptions,
+    /// Topic exchange and binding pattern the queue is bound with.
+    binding: Option<(String, String)>,
+}
+
+/// Declares the durable topic exchange `exchange`.
+async fn declare_exchange(channel: &Channel, exchange: &str) -> Result<(), lapin::Error> {
+    let durable = ExchangeDeclareOptions {
+        durable: true,
+        ..Default::default()
+    };
+    channel
+        .exchange_declare(
+            exchange,
+            ExchangeKind::Topic,
+            durable,
+            FieldTable::default(),
+        )
+        .await
+}
+
+async fn bind(
+    channel: &Channel,
+    queue: &str,
+    exchange: &str,
+    pattern: &str,
+) -> Result<(), lapin::Error> {
+    declare_exchange(channel, exchange).await?;
+    channel
+        .queue_bind(
+            queue,
+            exchange,
+            pattern,
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+}
+
 /// Spawn a consumer loop that automatically reconnects when the connection drops.
 /// Each invocation creates its own AMQP connection so consumers are isolated from
 /// each other and from the publish channel.
-async fn setup_queue_and_listen<F, Fut>(amqp_url: String, queue_name: String, on_message: F)
-where
-    F: Fn(Delivery) -> Fut + Send + Sync + Clone + 'static,
-    Fut: Future<Output = ()> + Send + 'static,
+///
+/// Every delivery is acknowledged after `on_message` handled it. Failed deliveries are retried or dead-lettered
+/// according to the [`QueueConfig`] of the queue. If `on_message` returns a reply, it is sent to the requester.
+///
+/// At most `max_in_flight` deliveries of the queue are handled at once, the next one is only taken after a handler
+/// finished and its delivery was settled.
+///
+/// Once `shutdown` is cancelled the consumer stops receiving deliveries, waits until every
+/// delivery it already received has been handled and closes its connection.
+async fn setup_queue_and_listen<F, Fut>(
+    amqp_url: String,
+    subscription: Subscription,
+    shutdown: CancellationToken,
+    tasks: &TaskTracker,
+    statuses: ConsumerStatuses,
+    publish_retry: (u32, Duration),
+    on_message: F,
+) where
+    F: Fn(Vec<u8>, BasicProperties) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = HandlerResult<Option<Vec<u8>>>> + Send + 'static,
 {
-    tokio::spawn(async move {
-        loop {
+    let Subscription {
+        queue,
+        declare,
+        binding,
+    } = subscription;
+    let queue_name = queue.name.clone();
+    statuses.set(&queue_name, ConsumerStatus::Connecting);
+    let handlers = Arc::new(Semaphore::new(queue.max_in_flight));
+    tasks.spawn(async move {
+        while !shutdown.is_cancelled() {
+            statuses.set(&queue_name, ConsumerStatus::Connecting);
             let conn = try_connect(&amqp_url).await;
 
-            let channel = match conn.create_channel().await {
+            // Retried and dead-lettered deliveries are only acked once the broker confirmed their copy
+            let channel = match confirm_channel(&conn).await {
                 Ok(ch) => Arc::new(ch),
                 Err(e) => {
                     error!("Failed to create channel: {:?}", e);
@@ -48,7 +150,16 @@ where
             };
 
             if let Err(e) = channel
-                .queue_declare(&queue_name, QueueDeclareOptions::default(), FieldTable::default())
+                .basic_qos(queue.prefetch, BasicQosOptions::default())
+                .await
+            {
+                error!("Failed to set prefetch for {}: {:?}", queue_name, e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+
+            if let Err(e) = channel
+                .queue_declare(&queue_name, declare, FieldTable::default())
                 .await
             {
                 error!("Failed to declare queue {}: {:?}", queue_name, e);
@@ -56,11 +167,32 @@ where
                 continue;
             }
 
+            if let Some((exchange, pattern)) = binding.as_ref() {
+                if let Err(e) = bind(&channel, &queue_name, exchange, pattern).await {
+                    error!(
+                        "Failed to bind queue {} to {}: {:?}",
+                        queue_name, exchange, e
+                    );
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            }
+
+            if let Err(e) = settle::declare_dead_letter(&channel, &queue).await {
+                error!(
+                    "Failed to declare dead-letter queue for {}: {:?}",
+                    queue_name, e
+                );
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+
+            // Use a unique tag so re-registration never conflicts with a stale consumer
+            let consumer_tag = uuid::Uuid::new_v4().to_string();
             let mut consumer = match channel
                 .basic_consume(
                     &queue_name,
-                    // Use a unique tag so re-registration never conflicts with a stale consumer
-                    &uuid::Uuid::new_v4().to_string(),
+                    &consumer_tag,
                     BasicConsumeOptions::default(),
                     FieldTable::default(),
                 )
@@ -75,21 +207,73 @@ where
             };
 
             info!("Listening on queue: {}", queue_name);
-
-            while let Some(delivery) = consumer.next().await {
+            statuses.set(&queue_name, ConsumerStatus::Consuming);
+
+            // Deliveries have to be acked on the channel they were received on,
+            // so the channel is only closed after all of them are handled.
+            let in_flight = TaskTracker::new();
+            loop {
+                // Wait for a free handler before taking the next delivery, the broker holds back the rest
+                let handler = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    handler = handlers.clone().acquire_owned() => {
+                        handler.expect("Handler semaphore is never closed")
+                    }
+                };
+                let delivery = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    delivery = consumer.next() => delivery,
+                };
                 match delivery {
-                    Ok(delivery) => {
-                        let on_message = on_message.clone();
-                        tokio::spawn(async move { on_message(delivery).await });
+                    Some(Ok(delivery)) => {
+                        let on_message =
+                            on_message(delivery.data.clone(), delivery.properties.clone());
+                        let channel = settle::SettleChannel {
+                            channel: channel.clone(),
+                            attempts: publish_retry.0,
+                            backoff: publish_retry.1,
+                        };
+                        let queue = queue.clone();
+                        let span = propagation::consumer_span(
+                            &queue_name,
+                            delivery.properties.headers().as_ref(),
+                        );
+                        in_flight.spawn(
+                            async move {
+                                settle::handle(&channel, &queue, delivery, on_message).await;
+                                drop(handler);
+                            }
+                            .instrument(span),
+                        );
                     }
-                    Err(err) => {
+                    Some(Err(err)) => {
                         error!("Consumer error on {}, reconnecting: {:?}", queue_name, err);
                         break;
                     }
+                    None => break,
+                }
+            }
+
+            if shutdown.is_cancelled() {
+                statuses.set(&queue_name, ConsumerStatus::Stopped);
+                if let Err(e) = channel
+                    .basic_cancel(&consumer_tag, BasicCancelOptions::default())
+                    .await
+                {
+                    warn!("Failed to cancel consumer for {}: {:?}", queue_name, e);
                 }
+                in_flight.close();
+                in_flight.wait().await;
+                let _ = channel.close(200, "shutdown").await;
+                let _ = conn.close(200, "shutdown").await;
+                info!("Stopped listening on queue: {}", queue_name);
+                return;
             }
 
-            warn!("Consumer loop for {} ended, reconnecting in 5s...", queue_name);
+            warn!(
+                "Consumer loop for {} ended, reconnecting in 5s...",
+                queue_name
+            );
             tokio::time::sleep(Duration::from_secs(5)).await;
         }
     });
@@ -97,33 +281,71 @@ where
 
 pub struct RabbitMQCommunicator {
     amqp_url: String,
-    /// Shared publish channel — replaced atomically when a publish fails.
-    channel: Arc<tokio::sync::RwLock<Arc<Channel>>>,
-    queues: HashMap<String, HashMap<String, String>>,
+    /// Shared publish connection and channel — replaced atomically when a publish fails.
+    channel: Arc<tokio::sync::RwLock<(Connection, Arc<Channel>)>>,
+    queues: Queues,
+    /// Cancelled by [`Communicator::shutdown`](super::Communicator::shutdown) to stop all consumers.
+    shutdown: CancellationToken,
+    consumers: TaskTracker,
+    statuses: ConsumerStatuses,
+    request_timeout: Duration,
+    /// Name of the service, sent along with every message.
+    producer: String,
+    dedup: Option<Dedup>,
+    /// Exchanges which were declared by the publisher already.
+    exchanges: std::sync::Mutex<HashSet<String>>,
+    /// Queue the responses to requests of this communicator are sent to. Created on the first request.
+    reply_queue: OnceCell<String>,
+    /// Requests waiting for a response, keyed by correlation id.
+    pending: Arc<std::sync::Mutex<HashMap<String, oneshot::Sender<Vec<u8>>>>>,
+    publish_attempts: u32,
+    publish_backoff: Duration,
+    outbox: Option<Arc<dyn Outbox>>,
+    /// Tells the outbox entries of this instance apart from the ones of other replicas of the producer.
+    instance: String,
+    /// Outbox entries which are being published right now and must not be flushed.
+    unconfirmed: std::sync::Mutex<HashSet<String>>,
+    /// Held while the outbox is flushed, so entries are not flushed twice at once.
+    flushing: tokio::sync::Mutex<()>,
 }
 
 impl RabbitMQCommunicator {
     pub async fn connect(amqp_url: &str) -> Self {
         let conn = try_connect(amqp_url).await;
         let channel = Arc::new(
-            conn.create_channel()
+            confirm_channel(&conn)
                 .await
                 .expect("Could not create publish channel"),
         );
 
         Self {
             amqp_url: amqp_url.to_string(),
-            channel: Arc::new(tokio::sync::RwLock::new(channel)),
+            channel: Arc::new(tokio::sync::RwLock::new((conn, channel))),
             queues: Self::load_default_queues(),
+            shutdown: CancellationToken::new(),
+            consumers: TaskTracker::new(),
+            statuses: ConsumerStatuses::default(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            producer: DEFAULT_PRODUCER.to_owned(),
+            dedup: None,
+            exchanges: Default::default(),
+            reply_queue: OnceCell::new(),
+            pending: Arc::default(),
+            publish_attempts: DEFAULT_PUBLISH_ATTEMPTS,
+            publish_backoff: DEFAULT_PUBLISH_BACKOFF,
+            outbox: None,
+            instance: String::new(),
+            unconfirmed: Default::default(),
+            flushing: Default::default(),
         }
     }
 
-    fn load_default_queues() -> HashMap<String, HashMap<String, String>> {
+    fn load_default_queues() -> Queues {
         let content = include_str!("../queues.yml");
         serde_yaml::from_str(content).expect("Failed to parse queues file")
     }
 
-    fn get_queue_name(&self, name: &str, action: &str) -> &str {
+    fn get_queue(&self, name: &str, action: &str) -> &QueueConfig {
         self.queues
             .get(name)
             .expect(&format!("Queue {} not found", name))
@@ -136,244 +358,623 @@ impl RabbitMQCommunicator {
         self.queues = serde_yaml::from_str(&content).expect("Failed to parse routes file");
     }
 
-    /// Publish `data` to `queue`, transparently reconnecting if the channel is dead.
-    async fn publish_with_retry(&self, queue: &str, data: Vec<u8>) {
+    /// How long [`Communicator::request`](super::Communicator::request) waits for a response. Defaults to 10 seconds.
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = timeout;
+    }
+
+    /// Name of the service the messages are sent from. Written into the envelope of every message.
+    pub fn set_producer(&mut self, producer: &str) {
+        self.producer = producer.to_owned();
+    }
+
+    /// Skips messages which `deduplicator` already saw within `ttl`, so every message is handled at most once even
+    /// if it is delivered again after a reconnect. Applies to the callbacks registered afterwards.
+    pub fn set_deduplicator(&mut self, deduplicator: Arc<dyn Deduplicator>, ttl: Duration) {
+        self.dedup = Some(Dedup { deduplicator, ttl });
+    }
+
+    /// How often a message is published before giving up and how long to wait before the first retry. The wait
+    /// doubles with every retry, up to 5 seconds. Defaults to 5 attempts and 200 milliseconds.
+    /// Also applies to the retries, dead letters and replies published by the consumers registered afterwards.
+    pub fn set_publish_retry(&mut self, attempts: u32, backoff: Duration) {
+        self.publish_attempts = attempts.max(1);
+        self.publish_backoff = backoff;
+    }
+
+    /// Stores every sent message in `outbox` until the broker confirmed it. Messages which could not be published
+    /// stay there and are published again after the next reconnect, [`RabbitMQCommunicator::flush_outbox`] or by
+    /// [`RabbitMQCommunicator::retry_outbox`].
+    ///
+    /// The entries are kept under the producer and `instance`, which has to differ between the replicas of a
+    /// service and should stay the same across restarts (e.g. the pod name), so an instance publishes what it left
+    /// behind in a previous run but never the messages another replica is publishing right now.
+    ///
+    /// A message may be published twice if the publisher stops between the confirm and removing it from the
+    /// outbox, so consumers should deduplicate (see [`RabbitMQCommunicator::set_deduplicator`]).
+    /// Requests and health checks are not stored, they are useless once their sender stopped waiting.
+    pub fn set_outbox(&mut self, outbox: Arc<dyn Outbox>, instance: &str) {
+        self.outbox = Some(outbox);
+        self.instance = instance.to_owned();
+    }
+
+    /// Key of the outbox entries of this instance.
+    fn outbox_owner(&self) -> String {
+        format!("{}:{}", self.producer, self.instance)
+    }
+
+    /// Publishes the messages which are left in the outbox, e.g. by a previous run which could not reach the broker.
+    pub async fn flush_outbox(&self) {
+        let Some(outbox) = self.outbox.as_ref() else {
+            return;
+        };
+        // Someone else is flushing already
+        let Ok(_flushing) = self.flushing.try_lock() else {
+            return;
+        };
+
+        let skip = self.unconfirmed.lock().unwrap().clone();
+        let owner = self.outbox_owner();
+        let flushed = outbox::flush(&**outbox, &owner, &skip, |entry| async move {
+            let channel = self.channel.read().await.1.clone();
+            self.publish_once(
+                &channel,
+                &entry.exchange,
+                &entry.routing_key,
+                &entry.data,
+                entry.properties,
+            )
+            .await
+        })
+        .await;
+        match flushed {
+            Ok(0) => {}
+            Ok(flushed) => info!("Published {} messages from the outbox", flushed),
+            Err(err) => warn!("Could not flush the outbox: {}", err),
+        }
+    }
+
+    /// Flushes the outbox every `interval` until the communicator is shut down, so messages whose publish failed
+    /// are sent as soon as the broker accepts them again, even if the connection never dropped.
+    pub async fn retry_outbox(&self, interval: Duration) {
+        if self.outbox.is_none() {
+            return;
+        }
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         loop {
-            let channel = self.channel.read().await.clone();
-            match channel
-                .basic_publish(
-                    "",
-                    queue,
-                    BasicPublishOptions::default(),
-                    &data,
-                    BasicProperties::default(),
-                )
-                .await
-            {
-                Ok(_) => return,
-                Err(err) => {
-                    error!("Publish to {} failed: {:?}, reconnecting...", queue, err);
-                    let conn = try_connect(&self.amqp_url).await;
-                    match conn.create_channel().await {
-                        Ok(new_ch) => *self.channel.write().await = Arc::new(new_ch),
-                        Err(e) => error!("Failed to create channel after reconnect: {:?}", e),
-                    }
-                }
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                _ = ticker.tick() => self.flush_outbox().await,
             }
         }
     }
-}
 
-impl super::Communicator for RabbitMQCommunicator {
-    async fn on_match_abrupt_close<F, Fut>(&self, callback: F)
+    /// States of the consumers of all queues a callback was registered for.
+    pub fn consumer_statuses(&self) -> ConsumerStatuses {
+        self.statuses.clone()
+    }
+
+    fn get_queue_name(&self, name: &str, action: &str) -> &str {
+        &self.get_queue(name, action).name
+    }
+
+    /// The subscription to the messages of the queue `name.action` which match `route`.
+    ///
+    /// Consumers of all messages read from the configured queue. Consumers of a subset read from a queue of their own
+    /// which is named after their route, e.g. `match-create-request.chess.any.any`.
+    fn subscription(&self, name: &str, action: &str, route: &Route) -> Subscription {
+        let mut queue = self.get_queue(name, action).clone();
+        let binding = queue
+            .exchange
+            .clone()
+            .map(|exchange| (exchange, route.binding(&format!("{}.{}", name, action))));
+
+        if !route.is_any() {
+            if binding.is_some() {
+                queue.name = format!("{}.{}", queue.name, route.queue_suffix());
+            } else {
+                warn!(
+                    "No exchange configured for {}, receiving all of its messages instead of {:?}",
+                    queue.name, route
+                );
+            }
+        }
+
+        Subscription {
+            queue,
+            declare: QueueDeclareOptions::default(),
+            binding,
+        }
+    }
+
+    async fn listen<F, Fut>(&self, subscription: Subscription, on_message: F)
     where
-        F: MessageHandler<MatchAbrubtClose, Fut>,
-        Fut: Future<Output = ()> + Send + 'static,
+        F: Fn(Vec<u8>, BasicProperties) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = HandlerResult<Option<Vec<u8>>>> + Send + 'static,
     {
-        let queue = self.get_queue_name("match", "abrupt_close").to_string();
-        setup_queue_and_listen(self.amqp_url.clone(), queue, move |delivery| {
+        setup_queue_and_listen(
+            self.amqp_url.clone(),
+            subscription,
+            self.shutdown.clone(),
+            &self.consumers,
+            self.statuses.clone(),
+            (self.publish_attempts, self.publish_backoff),
+            on_message,
+        )
+        .await;
+    }
+
+    /// Decodes every message of the queue `name.action` which matches `route` with `decode` and passes it to
+    /// `callback`. Messages which were already handled are skipped, if a deduplicator is set.
+    async fn consume<T, F, Fut, R>(
+        &self,
+        name: &str,
+        action: &str,
+        route: &Route,
+        decode: Decoder<T>,
+        callback: F,
+    ) where
+        T: Send + 'static,
+        F: MessageHandler<T, Fut>,
+        Fut: Future<Output = HandlerResult<R>> + Send + 'static,
+    {
+        let subscription = self.subscription(name, action, route);
+        let dedup = self.dedup.clone();
+        self.listen(subscription, move |data, _| {
             let callback = callback.clone();
+            let dedup = dedup.clone();
             async move {
-                if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
-                    error!("Failed to ack delivery: {:?}", e);
-                    return;
-                }
-                let reason: MatchAbrubtClose = serde_json::from_slice(&delivery.data).unwrap();
-                callback(reason).await;
+                let (id, message) = decode(&data).map_err(HandlerError::Reject)?;
+                match (dedup, id) {
+                    (Some(dedup), Some(id)) => dedup.once(&id, callback(message)).await?,
+                    _ => Some(callback(message).await?),
+                };
+                Ok(None)
             }
         })
         .await;
     }
 
-    async fn on_game_create<F, Fut>(&self, callback: F)
-    where
-        F: MessageHandler<crate::models::GameServerCreate, Fut>,
-        Fut: Future<Output = String> + Send + 'static,
-    {
-        let queue = self.get_queue_name("game", "create").to_string();
-        setup_queue_and_listen(self.amqp_url.clone(), queue, move |delivery| {
-            let callback = callback.clone();
-            async move {
-                if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
-                    error!("Failed to ack delivery: {:?}", e);
+    /// Name of the queue responses are sent to. Starts consuming it on the first call and waits until the consumer
+    /// is connected, so no response is published to a queue which does not exist yet.
+    async fn reply_queue(&self) -> Result<&str, RequestError> {
+        let name = self
+            .reply_queue
+            .get_or_init(|| async {
+                let name = format!("reply.{}", uuid::Uuid::new_v4());
+                let subscription = Subscription {
+                    queue: QueueConfig {
+                        name: name.clone(),
+                        exchange: None,
+                        dead_letter_exchange: None,
+                        max_retries: 0,
+                        prefetch: DEFAULT_PREFETCH,
+                        max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+                    },
+                    // Responses are only of interest to this communicator, so the queue goes away with its connection
+                    declare: QueueDeclareOptions {
+                        exclusive: true,
+                        auto_delete: true,
+                        ..Default::default()
+                    },
+                    binding: None,
+                };
+                let pending = self.pending.clone();
+                setup_queue_and_listen(
+                    self.amqp_url.clone(),
+                    subscription,
+                    self.shutdown.clone(),
+                    &self.consumers,
+                    self.statuses.clone(),
+                    (self.publish_attempts, self.publish_backoff),
+                    move |data, properties| {
+                        let waiting = properties
+                            .correlation_id()
+                            .as_ref()
+                            .and_then(|id| pending.lock().unwrap().remove(id.as_str()));
+                        match waiting {
+                            Some(sender) => {
+                                let _ = sender.send(data);
+                            }
+                            None => warn!("Received response to an unknown or timed out request"),
+                        }
+                        std::future::ready(Ok(None))
+                    },
+                )
+                .await;
+                name
+            })
+            .await;
+
+        tokio::time::timeout(self.request_timeout, async {
+            while self.statuses.check(name).is_err() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .map_err(|_| RequestError::Timeout)?;
+        Ok(name)
+    }
+
+    /// Publish `data` to `queue`, transparently reconnecting if the channel is dead.
+    /// The current trace context is sent along in the message headers.
+    async fn publish_with_retry(&self, queue: &str, data: Vec<u8>) -> Result<(), PublishError> {
+        self.publish("", queue, &data, BasicProperties::default())
+            .await
+    }
+
+    /// Publishes `message` in its envelope to the queue `name.action`, or to its exchange if one is configured.
+    /// The message is kept in the outbox until it is confirmed, unless it is a request. If publishing fails, the
+    /// message stays in the outbox and `Ok` is returned, [`RabbitMQCommunicator::retry_outbox`] publishes it later.
+    async fn send<T: Message>(
+        &self,
+        name: &str,
+        action: &str,
+        message: &T,
+        properties: BasicProperties,
+    ) -> Result<(), PublishError> {
+        let queue = self.get_queue(name, action);
+        let data = envelope::encode(&self.producer, message);
+        let (exchange, routing_key) = match queue.exchange.as_ref() {
+            Some(exchange) => (
+                exchange.as_str(),
+                message.route().routing_key(&format!("{}.{}", name, action)),
+            ),
+            None => ("", queue.name.clone()),
+        };
+
+        let outbox = self
+            .outbox
+            .as_ref()
+            .filter(|_| properties.reply_to().is_none());
+        let Some(outbox) = outbox else {
+            return self
+                .publish(exchange, &routing_key, &data, properties)
+                .await;
+        };
+
+        let owner = self.outbox_owner();
+        let entry = OutboxEntry::new(exchange, &routing_key, data, properties.clone());
+        if let Err(err) = outbox.put(&owner, &entry) {
+            warn!(
+                "Could not store message in the outbox, publishing it without: {}",
+                err
+            );
+            return self
+                .publish(exchange, &routing_key, &entry.data, properties)
+                .await;
+        }
+
+        self.unconfirmed.lock().unwrap().insert(entry.id.clone());
+        let published = self
+            .publish(exchange, &routing_key, &entry.data, properties)
+            .await;
+        self.unconfirmed.lock().unwrap().remove(&entry.id);
+
+        match published {
+            Ok(()) => {
+                if let Err(err) = outbox.remove(&owner, &entry.id) {
+                    warn!(
+                        "Could not remove message {} from the outbox, it will be published again: {}",
+                        entry.id, err
+                    );
+                }
+            }
+            Err(err) => warn!(
+                "Publish to {} failed, keeping the message in the outbox: {}",
+                routing_key, err
+            ),
+        }
+        Ok(())
+    }
+
+    /// Publishes `data` to `exchange` with `routing_key` and waits for the broker to confirm it.
+    /// Failed attempts are retried with a growing backoff, reconnecting if the channel is dead.
+    async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        data: &[u8],
+        properties: BasicProperties,
+    ) -> Result<(), PublishError> {
+        let span = info_span!("publish", exchange = exchange, routing_key = routing_key);
+        let mut headers = properties.headers().clone().unwrap_or_default();
+        propagation::inject(&span, &mut headers);
+        let properties = properties.with_headers(headers);
+
+        async {
+            let mut attempt = 1;
+            loop {
+                let channel = self.channel.read().await.1.clone();
+                let err = match self
+                    .publish_once(&channel, exchange, routing_key, data, properties.clone())
+                    .await
+                {
+                    Ok(()) => return Ok(()),
+                    Err(err) => err,
+                };
+                if attempt >= self.publish_attempts {
+                    error!(
+                        "Publish to {} failed after {} attempts: {}",
+                        routing_key, attempt, err
+                    );
+                    return Err(err);
+                }
+
+                let backoff = publish_backoff(self.publish_backoff, attempt);
+                warn!(
+                    "Publish to {} failed: {}, retrying in {:?}",
+                    routing_key, err, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                if !channel.status().connected() {
+                    self.reconnect(&channel).await;
+                }
+                attempt += 1;
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Publishes `data` once on `channel` and waits for the confirm.
+    /// Topic exchanges are declared before the first message is published to them.
+    async fn publish_once(
+        &self,
+        channel: &Channel,
+        exchange: &str,
+        routing_key: &str,
+        data: &[u8],
+        properties: BasicProperties,
+    ) -> Result<(), PublishError> {
+        let unavailable = |err: lapin::Error| PublishError::Unavailable(err.to_string());
+
+        if !exchange.is_empty() && !self.exchanges.lock().unwrap().contains(exchange) {
+            declare_exchange(channel, exchange)
+                .await
+                .map_err(unavailable)?;
+            self.exchanges.lock().unwrap().insert(exchange.to_owned());
+        }
+        let confirmation = channel
+            .basic_publish(
+                exchange,
+                routing_key,
+                BasicPublishOptions::default(),
+                data,
+                properties,
+            )
+            .await
+            .map_err(unavailable)?
+            .await
+            .map_err(unavailable)?;
+        match confirmation.is_nack() {
+            true => Err(PublishError::Nack),
+            false => Ok(()),
+        }
+    }
+
+    /// Replaces the publish connection if it still uses the dead `channel` and flushes the outbox afterwards.
+    async fn reconnect(&self, channel: &Arc<Channel>) {
+        {
+            let mut current = self.channel.write().await;
+            // Another publish reconnected already
+            if !Arc::ptr_eq(&current.1, channel) {
+                return;
+            }
+
+            info!("Reconnecting publish channel");
+            let reconnected = async {
+                let conn =
+                    Connection::connect(&self.amqp_url, lapin::ConnectionProperties::default())
+                        .await?;
+                let channel = confirm_channel(&conn).await?;
+                Ok::<_, lapin::Error>((conn, Arc::new(channel)))
+            }
+            .await;
+            match reconnected {
+                Ok(reconnected) => *current = reconnected,
+                Err(err) => {
+                    error!("Could not reconnect publish channel: {:?}", err);
                     return;
                 }
-                let created_game: GameServerCreate =
-                    serde_json::from_slice(&delivery.data).unwrap();
-                callback(created_game).await;
             }
-        })
-        .await;
+        }
+        self.flush_outbox().await;
+    }
+}
+
+impl super::Communicator for RabbitMQCommunicator {
+    async fn on_match_abrupt_close<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<MatchAbrubtClose, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.consume("match", "abrupt_close", &Route::default(), json, callback)
+            .await;
     }
 
     async fn on_match_created<F, Fut>(&self, callback: F)
     where
         F: MessageHandler<crate::models::CreatedMatch, Fut>,
-        Fut: Future<Output = ()> + Send + 'static,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
     {
-        let queue = self.get_queue_name("match", "created").to_string();
-        setup_queue_and_listen(self.amqp_url.clone(), queue, move |delivery| {
-            let callback = callback.clone();
-            async move {
-                if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
-                    error!("Failed to ack delivery: {:?}", e);
-                    return;
-                }
-                let created_match: CreatedMatch = serde_json::from_slice(&delivery.data).unwrap();
-                callback(created_match).await;
-            }
-        })
-        .await;
+        self.consume("match", "created", &Route::default(), json, callback)
+            .await;
     }
 
     async fn on_match_result<F, Fut>(&self, callback: F)
     where
         F: MessageHandler<crate::models::MatchResult, Fut>,
-        Fut: Future<Output = ()> + Send + 'static,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
     {
-        let queue = self.get_queue_name("match", "result").to_string();
-        setup_queue_and_listen(self.amqp_url.clone(), queue, move |delivery| {
-            let callback = callback.clone();
-            async move {
-                if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
-                    error!("Failed to ack delivery: {:?}", e);
-                    return;
-                }
-                let result: MatchResult = serde_json::from_slice(&delivery.data).unwrap();
-                callback(result).await;
-            }
-        })
-        .await;
+        self.consume("match", "result", &Route::default(), json, callback)
+            .await;
     }
 
-    async fn on_match_create<F, Fut>(&self, callback: F)
+    async fn on_match_create_for<F, Fut>(&self, route: Route, callback: F)
     where
         F: MessageHandler<crate::models::CreateMatch, Fut>,
-        Fut: Future<Output = ()> + Send + 'static,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.consume("match", "create", &route, json, callback)
+            .await;
+    }
+
+    async fn on_event<E, F, Fut>(&self, callback: F)
+    where
+        E: Event,
+        F: MessageHandler<E, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
     {
-        let queue = self.get_queue_name("match", "create").to_string();
-        setup_queue_and_listen(self.amqp_url.clone(), queue, move |delivery| {
+        let (name, action) = E::QUEUE;
+        self.consume(name, action, &Route::default(), json, callback)
+            .await;
+    }
+
+    async fn on_request<R, F, Fut>(&self, callback: F)
+    where
+        R: Request,
+        F: MessageHandler<R, Fut>,
+        Fut: Future<Output = HandlerResult<R::Response>> + Send + 'static,
+    {
+        let (name, action) = R::QUEUE;
+        let subscription = self.subscription(name, action, &Route::default());
+        let dedup = self.dedup.clone();
+        self.listen(subscription, move |data, _| {
             let callback = callback.clone();
+            let dedup = dedup.clone();
             async move {
-                if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
-                    error!("Failed to ack delivery: {:?}", e);
-                    return;
-                }
-                let result: CreateMatch = serde_json::from_slice(&delivery.data).unwrap();
-                callback(result).await;
+                let (id, request) = json::<R>(&data).map_err(HandlerError::Reject)?;
+                // Duplicates are not answered, the requester already got the response to the first delivery
+                let response = match (dedup, id) {
+                    (Some(dedup), Some(id)) => dedup.once(&id, callback(request)).await?,
+                    _ => Some(callback(request).await?),
+                };
+                Ok(response.map(|response| encode_response::<R::Response>(&Ok(response))))
             }
         })
         .await;
     }
 
-    async fn create_game(
-        &self,
-        game_server: &GameServerCreate,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        self.publish_with_retry(
-            self.get_queue_name("game", "create"),
-            serde_json::to_vec(&game_server).unwrap(),
-        )
-        .await;
-        Ok(())
+    async fn request<R: Request>(&self, request: &R) -> Result<R::Response, RequestError> {
+        let reply_to = self.reply_queue().await?;
+        let correlation_id = uuid::Uuid::new_v4().to_string();
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(correlation_id.clone(), sender);
+
+        let (name, action) = R::QUEUE;
+        let properties = BasicProperties::default()
+            .with_reply_to(reply_to.into())
+            .with_correlation_id(correlation_id.clone().into());
+        if let Err(err) = self.send(name, action, request, properties).await {
+            self.pending.lock().unwrap().remove(&correlation_id);
+            return Err(RequestError::Failed(err.to_string()));
+        }
+
+        let response = tokio::time::timeout(self.request_timeout, receiver).await;
+        self.pending.lock().unwrap().remove(&correlation_id);
+        match response {
+            Ok(Ok(data)) => decode_response(&data),
+            _ => Err(RequestError::Timeout),
+        }
     }
 
-    async fn create_match(&self, match_request: &CreateMatch) {
-        self.publish_with_retry(
-            self.get_queue_name("match", "create"),
-            serde_json::to_vec(&match_request).unwrap(),
-        )
-        .await;
+    async fn create_match(&self, match_request: &CreateMatch) -> Result<(), PublishError> {
+        self.send("match", "create", match_request, BasicProperties::default())
+            .await
     }
 
-    async fn report_match_abrupt_close(&self, match_close: &MatchAbrubtClose) {
-        self.publish_with_retry(
-            self.get_queue_name("match", "abrupt_close"),
-            serde_json::to_vec(&match_close).unwrap(),
+    async fn report_match_abrupt_close(
+        &self,
+        match_close: &MatchAbrubtClose,
+    ) -> Result<(), PublishError> {
+        self.send(
+            "match",
+            "abrupt_close",
+            match_close,
+            BasicProperties::default(),
         )
-        .await;
+        .await
     }
 
-    async fn report_match_created(&self, created_match: &CreatedMatch) {
-        self.publish_with_retry(
-            self.get_queue_name("match", "created"),
-            serde_json::to_vec(&created_match).unwrap(),
+    async fn report_match_created(&self, created_match: &CreatedMatch) -> Result<(), PublishError> {
+        self.send(
+            "match",
+            "created",
+            created_match,
+            BasicProperties::default(),
         )
-        .await;
+        .await
     }
 
-    async fn create_ai_task(&self, task: &crate::models::Task) {
-        self.publish_with_retry(
-            self.get_queue_name("ai", "task"),
-            serde_json::to_vec(&task).unwrap(),
-        )
-        .await;
+    async fn report_event<E: Event>(&self, event: &E) -> Result<(), PublishError> {
+        let (name, action) = E::QUEUE;
+        self.send(name, action, event, BasicProperties::default())
+            .await
     }
 
-    async fn report_match_result(&self, match_result: &MatchResult) {
-        self.publish_with_retry(
-            self.get_queue_name("match", "result"),
-            serde_json::to_vec(&match_result).unwrap(),
-        )
-        .await;
+    async fn create_ai_task(&self, task: &crate::models::Task) -> Result<(), PublishError> {
+        self.send("ai", "task", task, BasicProperties::default())
+            .await
+    }
+
+    async fn report_match_result(&self, match_result: &MatchResult) -> Result<(), PublishError> {
+        self.send("match", "result", match_result, BasicProperties::default())
+            .await
     }
 
-    async fn send_health_check(&self, client_id: String) {
+    async fn send_health_check(&self, report: &HealthReport) -> Result<(), PublishError> {
         self.publish_with_retry(
             self.get_queue_name("health_check", "check"),
-            client_id.into_bytes(),
+            envelope::encode(&self.producer, report),
         )
-        .await;
+        .await
     }
 
     async fn on_health_check<F, Fut>(&self, callback: F)
     where
-        F: MessageHandler<String, Fut>,
-        Fut: Future<Output = ()> + Send + 'static,
+        F: MessageHandler<HealthReport, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
     {
-        let queue = self.get_queue_name("health_check", "check").to_string();
-        setup_queue_and_listen(self.amqp_url.clone(), queue, move |delivery| {
-            let callback = callback.clone();
-            async move {
-                debug!("Received healthcheck event");
-                if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
-                    error!("Failed to ack delivery: {:?}", e);
-                    return;
-                }
-                let client_id = String::from_utf8(delivery.data.to_vec()).unwrap();
-                callback(client_id).await;
-            }
-        })
+        self.consume(
+            "health_check",
+            "check",
+            &Route::default(),
+            health_report,
+            callback,
+        )
         .await;
     }
 
     async fn on_ai_register<F, Fut>(&self, callback: F)
     where
         F: MessageHandler<crate::models::AIPlayerRegister, Fut>,
-        Fut: Future<Output = ()> + Send + 'static,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
     {
-        let queue = self.get_queue_name("ai", "register").to_string();
-        setup_queue_and_listen(self.amqp_url.clone(), queue, move |delivery| {
-            let callback = callback.clone();
-            async move {
-                debug!("Received AI register event");
-                if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
-                    error!("Failed to ack delivery: {:?}", e);
-                    return;
-                }
-                let ai_register: crate::models::AIPlayerRegister =
-                    serde_json::from_slice(&delivery.data).unwrap();
-                callback(ai_register).await;
-            }
-        })
-        .await;
+        self.consume("ai", "register", &Route::default(), json, callback)
+            .await;
     }
 
-    async fn register_ai_player(&self, ai_player: &crate::models::AIPlayerRegister) {
-        self.publish_with_retry(
-            self.get_queue_name("ai", "register"),
-            serde_json::to_vec(&ai_player).unwrap(),
-        )
-        .await;
+    async fn register_ai_player(
+        &self,
+        ai_player: &crate::models::AIPlayerRegister,
+    ) -> Result<(), PublishError> {
+        self.send("ai", "register", ai_player, BasicProperties::default())
+            .await
+    }
+
+    async fn shutdown(&self) {
+        self.shutdown.cancel();
+        self.consumers.close();
+        self.consumers.wait().await;
+
+        let (conn, channel) = &*self.channel.read().await;
+        let _ = channel.close(200, "shutdown").await;
+        let _ = conn.close(200, "shutdown").await;
+        info!("RabbitMQ communicator shut down");
     }
 }
@@ -0,0 +1,310 @@
+use std::{sync::Arc, time::Duration};
+
+use futures_lite::Future;
+use lapin::{
+    message::Delivery,
+    options::{
+        BasicAckOptions, BasicNackOptions, BasicPublishOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    types::{AMQPValue, FieldTable, ShortString},
+    BasicProperties, Channel, ExchangeKind,
+};
+use tracing::{error, warn};
+
+use crate::{
+    consumers::run_handler, encode_response, publish_backoff, queues::QueueConfig, HandlerError,
+    HandlerResult, PublishError,
+};
+
+/// The confirm channel deliveries are settled on and how often a publish on it is attempted.
+pub(super) struct SettleChannel {
+    pub channel: Arc<Channel>,
+    pub attempts: u32,
+    pub backoff: Duration,
+}
+
+const RETRY_COUNT_HEADER: &str = "x-retry-count";
+const ERROR_HEADER: &str = "x-error";
+const ORIGINAL_QUEUE_HEADER: &str = "x-original-queue";
+
+/// Declares the dead-letter exchange of `queue` and a queue keeping its dead letters.
+pub(super) async fn declare_dead_letter(
+    channel: &Channel,
+    queue: &QueueConfig,
+) -> Result<(), lapin::Error> {
+    let Some(exchange) = queue.dead_letter_exchange.as_ref() else {
+        return Ok(());
+    };
+    let durable = ExchangeDeclareOptions {
+        durable: true,
+        ..Default::default()
+    };
+    channel
+        .exchange_declare(
+            exchange,
+            ExchangeKind::Direct,
+            durable,
+            FieldTable::default(),
+        )
+        .await?;
+
+    let dead_letter_queue = queue.dead_letter_queue();
+    let durable = QueueDeclareOptions {
+        durable: true,
+        ..Default::default()
+    };
+    channel
+        .queue_declare(&dead_letter_queue, durable, FieldTable::default())
+        .await?;
+    channel
+        .queue_bind(
+            &dead_letter_queue,
+            exchange,
+            &queue.name,
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+}
+
+/// Runs `handler` for `delivery` and acknowledges the delivery afterwards.
+///
+/// Rejected deliveries, including those which can't be decoded, are dead-lettered right away. Deliveries whose handler
+/// asked for a retry or panicked are published to the end of the queue again until they failed `max_retries` times,
+/// after which they are dead-lettered as well.
+/// Dead letters keep their payload and headers and get the error attached.
+///
+/// If the delivery is a request, the reply returned by `handler` or the error which dead-lettered the delivery is
+/// sent to its `reply_to` queue.
+///
+/// The delivery is only acked after the broker confirmed everything published for it, otherwise it is requeued.
+pub(super) async fn handle<Fut>(
+    channel: &SettleChannel,
+    queue: &QueueConfig,
+    delivery: Delivery,
+    handler: Fut,
+) where
+    Fut: Future<Output = HandlerResult<Option<Vec<u8>>>>,
+{
+    let result = run_handler(handler).await;
+
+    let settled = match result {
+        Ok(None) => Ok(()),
+        Ok(Some(response)) => reply(channel, &delivery, response).await,
+        Err(HandlerError::Reject(err)) => {
+            error!("Message on {} rejected: {}", queue.name, err);
+            fail(channel, queue, &delivery, &err).await
+        }
+        Err(HandlerError::Retry(err)) => {
+            let retries = retry_count(&delivery);
+            if retries < queue.max_retries {
+                warn!(
+                    "Handler for {} failed (attempt {}/{}): {}",
+                    queue.name,
+                    retries + 1,
+                    queue.max_retries + 1,
+                    err
+                );
+                retry(channel, queue, &delivery, retries + 1).await
+            } else {
+                error!(
+                    "Handler for {} failed {} times, giving up: {}",
+                    queue.name,
+                    retries + 1,
+                    err
+                );
+                fail(channel, queue, &delivery, &err).await
+            }
+        }
+    };
+
+    let acked = match settled {
+        Ok(()) => delivery.ack(BasicAckOptions::default()).await,
+        Err(err) => {
+            error!(
+                "Failed to settle message on {}, requeueing it: {:?}",
+                queue.name, err
+            );
+            delivery
+                .nack(BasicNackOptions {
+                    requeue: true,
+                    ..Default::default()
+                })
+                .await
+        }
+    };
+    if let Err(err) = acked {
+        error!("Failed to acknowledge delivery: {:?}", err);
+    }
+}
+
+/// Dead-letters `delivery` and tells the requester about `err` if there is one.
+async fn fail(
+    channel: &SettleChannel,
+    queue: &QueueConfig,
+    delivery: &Delivery,
+    err: &str,
+) -> Result<(), PublishError> {
+    dead_letter(channel, queue, delivery, err).await?;
+    reply(
+        channel,
+        delivery,
+        encode_response::<()>(&Err(err.to_owned())),
+    )
+    .await
+}
+
+/// Publishes `response` to the `reply_to` queue of `delivery`. Does nothing if `delivery` is no request.
+async fn reply(
+    channel: &SettleChannel,
+    delivery: &Delivery,
+    response: Vec<u8>,
+) -> Result<(), PublishError> {
+    let Some(reply_to) = delivery.properties.reply_to() else {
+        return Ok(());
+    };
+    let mut properties = BasicProperties::default();
+    if let Some(correlation_id) = delivery.properties.correlation_id() {
+        properties = properties.with_correlation_id(correlation_id.clone());
+    }
+
+    publish(channel, "", reply_to.as_str(), &response, properties).await
+}
+
+/// Publishes `data` on `channel` and waits until the broker confirmed it. Failed attempts are retried with a growing
+/// backoff like the publishes of the communicator. A closed channel is not retried, its deliveries are redelivered
+/// anyway.
+async fn publish(
+    channel: &SettleChannel,
+    exchange: &str,
+    routing_key: &str,
+    data: &[u8],
+    properties: BasicProperties,
+) -> Result<(), PublishError> {
+    let mut attempt = 1;
+    loop {
+        let err = match publish_once(
+            &channel.channel,
+            exchange,
+            routing_key,
+            data,
+            properties.clone(),
+        )
+        .await
+        {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        if attempt >= channel.attempts || !channel.channel.status().connected() {
+            return Err(err);
+        }
+
+        let backoff = publish_backoff(channel.backoff, attempt);
+        warn!(
+            "Publish to {} failed: {}, retrying in {:?}",
+            routing_key, err, backoff
+        );
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+    }
+}
+
+/// Publishes `data` once on the confirm channel `channel` and waits for the confirm.
+async fn publish_once(
+    channel: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    data: &[u8],
+    properties: BasicProperties,
+) -> Result<(), PublishError> {
+    let unavailable = |err: lapin::Error| PublishError::Unavailable(err.to_string());
+    let confirmation = channel
+        .basic_publish(
+            exchange,
+            routing_key,
+            BasicPublishOptions::default(),
+            data,
+            properties,
+        )
+        .await
+        .map_err(unavailable)?
+        .await
+        .map_err(unavailable)?;
+    match confirmation.is_ack() {
+        true => Ok(()),
+        false => Err(PublishError::Nack),
+    }
+}
+
+fn retry_count(delivery: &Delivery) -> u32 {
+    let header = delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get(RETRY_COUNT_HEADER).cloned());
+    match header {
+        Some(AMQPValue::LongUInt(count)) => count,
+        Some(AMQPValue::LongLongInt(count)) => count as u32,
+        Some(AMQPValue::LongInt(count)) => count as u32,
+        _ => 0,
+    }
+}
+
+fn headers_of(delivery: &Delivery) -> FieldTable {
+    delivery.properties.headers().clone().unwrap_or_default()
+}
+
+async fn retry(
+    channel: &SettleChannel,
+    queue: &QueueConfig,
+    delivery: &Delivery,
+    retries: u32,
+) -> Result<(), PublishError> {
+    let mut headers = headers_of(delivery);
+    headers.insert(RETRY_COUNT_HEADER.into(), AMQPValue::LongUInt(retries));
+
+    publish(
+        channel,
+        "",
+        &queue.name,
+        &delivery.data,
+        delivery.properties.clone().with_headers(headers),
+    )
+    .await
+}
+
+async fn dead_letter(
+    channel: &SettleChannel,
+    queue: &QueueConfig,
+    delivery: &Delivery,
+    err: &str,
+) -> Result<(), PublishError> {
+    let Some(exchange) = queue.dead_letter_exchange.as_ref() else {
+        error!(
+            "No dead-letter exchange configured for {}, dropping message: {}",
+            queue.name,
+            String::from_utf8_lossy(&delivery.data)
+        );
+        return Ok(());
+    };
+
+    let mut headers = headers_of(delivery);
+    headers.insert(
+        ERROR_HEADER.into(),
+        AMQPValue::LongString(err.to_owned().into()),
+    );
+    headers.insert(
+        ORIGINAL_QUEUE_HEADER.into(),
+        AMQPValue::ShortString(ShortString::from(queue.name.clone())),
+    );
+
+    publish(
+        channel,
+        exchange,
+        &queue.name,
+        &delivery.data,
+        delivery.properties.clone().with_headers(headers),
+    )
+    .await
+}
@@ -0,0 +1,773 @@
+use futures_lite::Future;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use redis::{
+    aio::MultiplexedConnection,
+    streams::{StreamClaimReply, StreamPendingCountReply, StreamReadReply},
+    Client, FromRedisValue, RedisResult,
+};
+use tokio::sync::{oneshot, OnceCell, RwLock, Semaphore};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::{
+    consumers::{health_report, json, ConsumerStatus, ConsumerStatuses, Decoder},
+    decode_response,
+    dedup::{Dedup, Deduplicator},
+    encode_response,
+    envelope::{self, Message},
+    models::{CreateMatch, CreatedMatch, HealthReport, MatchAbrubtClose, MatchResult},
+    publish_backoff,
+    queues::{QueueConfig, Queues},
+    routing::{self, Route},
+    Event, HandlerError, HandlerResult, MessageHandler, PublishError, Request, RequestError,
+    DEFAULT_PUBLISH_ATTEMPTS, DEFAULT_PUBLISH_BACKOFF,
+};
+
+use settle::Delivery;
+
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_PRODUCER: &str = "unknown";
+const DEFAULT_RETRY_IDLE: Duration = Duration::from_secs(30);
+/// Streams are trimmed to roughly this many entries whenever a message is added.
+const MAX_STREAM_LENGTH: usize = 100_000;
+/// How long a read waits for new entries before the consumer looks for entries to claim.
+const READ_BLOCK: Duration = Duration::from_secs(1);
+/// How often a consumer looks for entries which were not acknowledged in time.
+const CLAIM_INTERVAL: Duration = Duration::from_secs(5);
+/// Reply streams of requesters which are gone are removed after this long.
+const REPLY_TTL: Duration = Duration::from_secs(60);
+
+mod settle;
+
+/// Key of the stream keeping the messages of `topic`, e.g. `match.create`.
+fn stream_key(topic: &str) -> String {
+    format!("stream:{}", topic)
+}
+
+async fn try_connect(client: &Client) -> MultiplexedConnection {
+    loop {
+        match client.get_multiplexed_async_connection().await {
+            Ok(conn) => return conn,
+            Err(err) => {
+                error!("Could not connect to Redis: {:?}", err);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// Connection shared by the publisher and the consumers of a [`RedisStreamsCommunicator`].
+struct Redis {
+    client: Client,
+    conn: RwLock<MultiplexedConnection>,
+}
+
+impl Redis {
+    /// Runs `cmd`, reconnecting once if the connection was dropped.
+    async fn query<T: FromRedisValue>(&self, cmd: &redis::Cmd) -> RedisResult<T> {
+        let mut conn = self.conn.read().await.clone();
+        match cmd.query_async(&mut conn).await {
+            Err(err) if err.is_connection_dropped() || err.is_io_error() => {
+                warn!("Redis connection dropped, reconnecting: {:?}", err);
+                let mut conn = self.client.get_multiplexed_async_connection().await?;
+                *self.conn.write().await = conn.clone();
+                cmd.query_async(&mut conn).await
+            }
+            result => result,
+        }
+    }
+}
+
+/// A consumer group and the stream it reads from.
+struct Subscription {
+    /// The queue the group stands for. Its name is the name of the group.
+    queue: QueueConfig,
+    stream: String,
+    /// Routing keys the group handles. Entries with other routing keys are acknowledged without being handled.
+    pattern: Option<String>,
+}
+
+/// Reads new entries of `stream` for `consumer` of `group`.
+async fn read(
+    conn: &mut MultiplexedConnection,
+    stream: &str,
+    group: &str,
+    consumer: &str,
+    count: usize,
+) -> RedisResult<Vec<Delivery>> {
+    let reply: Option<StreamReadReply> = redis::cmd("XREADGROUP")
+        .arg("GROUP")
+        .arg(group)
+        .arg(consumer)
+        .arg("COUNT")
+        .arg(count)
+        .arg("BLOCK")
+        .arg(READ_BLOCK.as_millis() as u64)
+        .arg("STREAMS")
+        .arg(stream)
+        .arg(">")
+        .query_async(conn)
+        .await?;
+    Ok(reply
+        .into_iter()
+        .flat_map(|reply| reply.keys)
+        .flat_map(|key| key.ids)
+        .map(|entry| Delivery::new(&entry, 1))
+        .collect())
+}
+
+/// Claims entries of `group` which were not acknowledged within `min_idle` for `consumer`, either because their
+/// handler asked for a retry or because the consumer which read them is gone.
+async fn claim(
+    conn: &mut MultiplexedConnection,
+    stream: &str,
+    group: &str,
+    consumer: &str,
+    min_idle: Duration,
+    count: usize,
+) -> RedisResult<Vec<Delivery>> {
+    let min_idle = min_idle.as_millis() as u64;
+    let pending: StreamPendingCountReply = redis::cmd("XPENDING")
+        .arg(stream)
+        .arg(group)
+        .arg("IDLE")
+        .arg(min_idle)
+        .arg("-")
+        .arg("+")
+        .arg(count)
+        .query_async(conn)
+        .await?;
+    if pending.ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let deliveries: HashMap<_, _> = pending
+        .ids
+        .iter()
+        .map(|pending| (pending.id.clone(), pending.times_delivered))
+        .collect();
+    let claimed: StreamClaimReply = redis::cmd("XCLAIM")
+        .arg(stream)
+        .arg(group)
+        .arg(consumer)
+        .arg(min_idle)
+        .arg(deliveries.keys().collect::<Vec<_>>())
+        .query_async(conn)
+        .await?;
+    Ok(claimed
+        .ids
+        .iter()
+        .map(|entry| Delivery::new(entry, deliveries.get(&entry.id).copied().unwrap_or(1) + 1))
+        .collect())
+}
+
+/// Reads the entries of the subscribed stream as `consumer` of the subscription's group in a background task.
+/// Each entry which matches the subscription is handled by `on_message` and settled afterwards.
+///
+/// At most `max_in_flight` entries are handled at once, the next ones are only read after a handler finished.
+/// Entries which were not acknowledged within `retry_idle` are claimed again.
+///
+/// Once `shutdown` is cancelled the consumer stops reading and waits until every entry it already read has been
+/// handled.
+#[allow(clippy::too_many_arguments)]
+async fn setup_group_and_listen<F, Fut>(
+    redis: Arc<Redis>,
+    subscription: Subscription,
+    consumer: String,
+    retry_idle: Duration,
+    shutdown: CancellationToken,
+    tasks: &TaskTracker,
+    statuses: ConsumerStatuses,
+    on_message: F,
+) where
+    F: Fn(Vec<u8>) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = HandlerResult<Option<Vec<u8>>>> + Send + 'static,
+{
+    let Subscription {
+        queue,
+        stream,
+        pattern,
+    } = subscription;
+    let group = queue.name.clone();
+    statuses.set(&group, ConsumerStatus::Connecting);
+    let handlers = Arc::new(Semaphore::new(queue.max_in_flight));
+    tasks.spawn(async move {
+        let in_flight = TaskTracker::new();
+        while !shutdown.is_cancelled() {
+            statuses.set(&group, ConsumerStatus::Connecting);
+            // Reads block until entries arrive, so they get a connection of their own
+            let mut reader = try_connect(&redis.client).await;
+
+            let created: RedisResult<()> = redis::cmd("XGROUP")
+                .arg("CREATE")
+                .arg(&stream)
+                .arg(&group)
+                .arg("$")
+                .arg("MKSTREAM")
+                .query_async(&mut reader)
+                .await;
+            if let Err(err) = created.or_else(|err| match err.code() {
+                Some("BUSYGROUP") => Ok(()),
+                _ => Err(err),
+            }) {
+                error!("Failed to create group {} on {}: {:?}", group, stream, err);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+
+            info!("Listening on stream {} as group {}", stream, group);
+            statuses.set(&group, ConsumerStatus::Consuming);
+
+            let mut last_claim: Option<Instant> = None;
+            loop {
+                // Wait for a free handler before reading, unread entries stay in the stream
+                let handler = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    handler = handlers.clone().acquire_owned() => {
+                        handler.expect("Handler semaphore is never closed")
+                    }
+                };
+                let count = (handlers.available_permits() + 1).min(queue.prefetch.max(1) as usize);
+
+                let mut deliveries = Ok(Vec::new());
+                if last_claim.is_none_or(|claimed| claimed.elapsed() >= CLAIM_INTERVAL) {
+                    last_claim = Some(Instant::now());
+                    deliveries =
+                        claim(&mut reader, &stream, &group, &consumer, retry_idle, count).await;
+                }
+                if matches!(deliveries.as_ref(), Ok(claimed) if claimed.is_empty()) {
+                    deliveries = tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        read = read(&mut reader, &stream, &group, &consumer, count) => read,
+                    };
+                }
+                let deliveries = match deliveries {
+                    Ok(deliveries) => deliveries,
+                    Err(err) => {
+                        error!("Consumer error on {}, reconnecting: {:?}", group, err);
+                        break;
+                    }
+                };
+
+                let mut handler = Some(handler);
+                for delivery in deliveries {
+                    let routed = match (pattern.as_ref(), delivery.routing_key.as_ref()) {
+                        (Some(pattern), Some(routing_key)) => {
+                            routing::matches(pattern, routing_key)
+                        }
+                        _ => true,
+                    };
+                    if !routed {
+                        settle::skip(&redis, &stream, &group, &delivery.id).await;
+                        continue;
+                    }
+
+                    let handler = match handler.take() {
+                        Some(handler) => handler,
+                        None => handlers
+                            .clone()
+                            .acquire_owned()
+                            .await
+                            .expect("Handler semaphore is never closed"),
+                    };
+                    let on_message = on_message(delivery.data.clone());
+                    let redis = redis.clone();
+                    let stream = stream.clone();
+                    let group = group.clone();
+                    let queue = queue.clone();
+                    let span = info_span!("consume", queue = %group, id = %delivery.id);
+                    in_flight.spawn(
+                        async move {
+                            settle::handle(
+                                &redis, &stream, &group, &queue, retry_idle, delivery, on_message,
+                            )
+                            .await;
+                            drop(handler);
+                        }
+                        .instrument(span),
+                    );
+                }
+            }
+
+            if shutdown.is_cancelled() {
+                statuses.set(&group, ConsumerStatus::Stopped);
+                in_flight.close();
+                in_flight.wait().await;
+                info!("Stopped listening on stream {} as group {}", stream, group);
+                return;
+            }
+
+            warn!("Consumer loop for {} ended, reconnecting in 5s...", group);
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    });
+}
+
+/// [`Communicator`](super::Communicator) on top of Redis Streams, for deployments without RabbitMQ.
+///
+/// Every message type is kept in a stream of its own (`stream:match.create`) and every queue of the queues file is a
+/// consumer group on it, so consumers of the same queue compete for the messages and every queue receives all of them.
+/// Routed subscriptions get a group of their own which skips the messages not matching its route.
+///
+/// Entries are acknowledged after their handler succeeded. Entries which were not acknowledged, because the handler
+/// asked for a retry or the consumer stopped, are claimed again after the retry idle time.
+/// Needs Redis 6.2 or newer.
+pub struct RedisStreamsCommunicator {
+    redis: Arc<Redis>,
+    queues: Queues,
+    /// Cancelled by [`Communicator::shutdown`](super::Communicator::shutdown) to stop all consumers.
+    shutdown: CancellationToken,
+    consumers: TaskTracker,
+    statuses: ConsumerStatuses,
+    request_timeout: Duration,
+    /// Name of the service, sent along with every message.
+    producer: String,
+    /// Name of this communicator in the consumer groups.
+    consumer: String,
+    dedup: Option<Dedup>,
+    retry_idle: Duration,
+    publish_attempts: u32,
+    publish_backoff: Duration,
+    /// Stream the responses to requests of this communicator are added to. Read from on the first request.
+    reply_stream: OnceCell<String>,
+    /// Requests waiting for a response, keyed by correlation id.
+    pending: Arc<std::sync::Mutex<HashMap<String, oneshot::Sender<Vec<u8>>>>>,
+}
+
+impl RedisStreamsCommunicator {
+    pub async fn connect(redis_url: &str) -> Self {
+        let client = Client::open(redis_url).expect("Invalid Redis URL");
+        let conn = try_connect(&client).await;
+
+        Self {
+            redis: Arc::new(Redis {
+                client,
+                conn: RwLock::new(conn),
+            }),
+            queues: Self::load_default_queues(),
+            shutdown: CancellationToken::new(),
+            consumers: TaskTracker::new(),
+            statuses: ConsumerStatuses::default(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            producer: DEFAULT_PRODUCER.to_owned(),
+            consumer: uuid::Uuid::new_v4().to_string(),
+            dedup: None,
+            retry_idle: DEFAULT_RETRY_IDLE,
+            publish_attempts: DEFAULT_PUBLISH_ATTEMPTS,
+            publish_backoff: DEFAULT_PUBLISH_BACKOFF,
+            reply_stream: OnceCell::new(),
+            pending: Arc::default(),
+        }
+    }
+
+    fn load_default_queues() -> Queues {
+        let content = include_str!("../queues.yml");
+        serde_yaml::from_str(content).expect("Failed to parse queues file")
+    }
+
+    fn get_queue(&self, name: &str, action: &str) -> &QueueConfig {
+        self.queues
+            .get(name)
+            .unwrap_or_else(|| panic!("Queue {} not found", name))
+            .get(action)
+            .unwrap_or_else(|| panic!("Action {} not found for queue {}", action, name))
+    }
+
+    /// Replaces the bundled queues. Only the names and consumer options are used, there are no exchanges.
+    pub fn load_queues(&mut self, path: &str) {
+        let content = std::fs::read_to_string(path).expect("Failed to read file");
+        self.queues = serde_yaml::from_str(&content).expect("Failed to parse routes file");
+    }
+
+    /// How long [`Communicator::request`](super::Communicator::request) waits for a response. Defaults to 10 seconds.
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = timeout;
+    }
+
+    /// Name of the service the messages are sent from. Written into the envelope of every message and used as
+    /// prefix of the consumer name.
+    pub fn set_producer(&mut self, producer: &str) {
+        self.producer = producer.to_owned();
+        self.consumer = format!("{}-{}", producer, uuid::Uuid::new_v4());
+    }
+
+    /// Skips messages which `deduplicator` already saw within `ttl`, so every message is handled at most once even
+    /// if it is claimed again after its consumer stopped. Applies to the callbacks registered afterwards.
+    pub fn set_deduplicator(&mut self, deduplicator: Arc<dyn Deduplicator>, ttl: Duration) {
+        self.dedup = Some(Dedup { deduplicator, ttl });
+    }
+
+    /// How long a message may stay unacknowledged before another consumer claims it. This is the delay between
+    /// retries, so it should be longer than the slowest handler. Defaults to 30 seconds.
+    pub fn set_retry_idle(&mut self, retry_idle: Duration) {
+        self.retry_idle = retry_idle;
+    }
+
+    /// How often a message is added to its stream before giving up and how long to wait before the first retry.
+    /// The wait doubles with every retry, up to 5 seconds. Defaults to 5 attempts and 200 milliseconds.
+    pub fn set_publish_retry(&mut self, attempts: u32, backoff: Duration) {
+        self.publish_attempts = attempts.max(1);
+        self.publish_backoff = backoff;
+    }
+
+    /// States of the consumers of all queues a callback was registered for.
+    pub fn consumer_statuses(&self) -> ConsumerStatuses {
+        self.statuses.clone()
+    }
+
+    /// The group reading the messages of `name.action` which match `route`.
+    /// Routes other than [`Route::default`] get a group of their own named like the queue with the route appended.
+    fn subscription(&self, name: &str, action: &str, route: &Route) -> Subscription {
+        let mut queue = self.get_queue(name, action).clone();
+        let topic = format!("{}.{}", name, action);
+        let pattern = (!route.is_any()).then(|| {
+            queue.name = format!("{}.{}", queue.name, route.queue_suffix());
+            route.binding(&topic)
+        });
+        Subscription {
+            queue,
+            stream: stream_key(&topic),
+            pattern,
+        }
+    }
+
+    async fn listen<F, Fut>(&self, subscription: Subscription, on_message: F)
+    where
+        F: Fn(Vec<u8>) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = HandlerResult<Option<Vec<u8>>>> + Send + 'static,
+    {
+        setup_group_and_listen(
+            self.redis.clone(),
+            subscription,
+            self.consumer.clone(),
+            self.retry_idle,
+            self.shutdown.clone(),
+            &self.consumers,
+            self.statuses.clone(),
+            on_message,
+        )
+        .await;
+    }
+
+    /// Decodes every message of `name.action` which matches `route` with `decode` and passes it to `callback`.
+    /// Messages which were already handled are skipped, if a deduplicator is set.
+    async fn consume<T, F, Fut, R>(
+        &self,
+        name: &str,
+        action: &str,
+        route: &Route,
+        decode: Decoder<T>,
+        callback: F,
+    ) where
+        T: Send + 'static,
+        F: MessageHandler<T, Fut>,
+        Fut: Future<Output = HandlerResult<R>> + Send + 'static,
+    {
+        let subscription = self.subscription(name, action, route);
+        let dedup = self.dedup.clone();
+        self.listen(subscription, move |data| {
+            let callback = callback.clone();
+            let dedup = dedup.clone();
+            async move {
+                let (id, message) = decode(&data).map_err(HandlerError::Reject)?;
+                match (dedup, id) {
+                    (Some(dedup), Some(id)) => dedup.once(&id, callback(message)).await?,
+                    _ => Some(callback(message).await?),
+                };
+                Ok(None)
+            }
+        })
+        .await;
+    }
+
+    /// Key of the stream responses are added to. Starts reading it on the first call.
+    async fn reply_stream(&self) -> &str {
+        self.reply_stream
+            .get_or_init(|| async {
+                let stream = stream_key(&format!("reply.{}", uuid::Uuid::new_v4()));
+                let redis = self.redis.clone();
+                let pending = self.pending.clone();
+                let shutdown = self.shutdown.clone();
+                let key = stream.clone();
+                self.consumers.spawn(async move {
+                    let mut reader = try_connect(&redis.client).await;
+                    // The stream belongs to this communicator, so it is read from the start
+                    let mut last_id = "0".to_owned();
+                    loop {
+                        let mut cmd = redis::cmd("XREAD");
+                        cmd.arg("BLOCK")
+                            .arg(READ_BLOCK.as_millis() as u64)
+                            .arg("STREAMS")
+                            .arg(&key)
+                            .arg(&last_id);
+                        let read = tokio::select! {
+                            _ = shutdown.cancelled() => break,
+                            read = cmd.query_async::<_, Option<StreamReadReply>>(&mut reader) => read,
+                        };
+                        let entries = match read {
+                            Ok(reply) => reply
+                                .into_iter()
+                                .flat_map(|reply| reply.keys)
+                                .flat_map(|key| key.ids),
+                            Err(err) => {
+                                error!("Failed to read responses, reconnecting: {:?}", err);
+                                tokio::time::sleep(Duration::from_secs(5)).await;
+                                reader = try_connect(&redis.client).await;
+                                continue;
+                            }
+                        };
+                        for entry in entries {
+                            last_id = entry.id.clone();
+                            let Some((correlation_id, response)) = settle::response(&entry) else {
+                                continue;
+                            };
+                            if let Some(sender) = pending.lock().unwrap().remove(&correlation_id) {
+                                let _ = sender.send(response);
+                            }
+                        }
+                    }
+                    let _: RedisResult<()> = redis.query(redis::cmd("DEL").arg(&key)).await;
+                });
+                stream
+            })
+            .await
+    }
+
+    /// Adds `message` in its envelope to the stream of `name.action`. If `reply_to` is given, the message is a request
+    /// and the response goes to the given stream with the given correlation id.
+    async fn send<T: Message>(
+        &self,
+        name: &str,
+        action: &str,
+        message: &T,
+        reply_to: Option<(&str, &str)>,
+    ) -> Result<(), PublishError> {
+        let topic = format!("{}.{}", name, action);
+        let routing_key = message.route().routing_key(&topic);
+        let data = envelope::encode(&self.producer, message);
+        self.publish(&stream_key(&topic), &routing_key, &data, reply_to)
+            .await
+    }
+
+    /// Adds an entry to `stream`, retrying with a growing backoff if Redis can't be reached.
+    async fn publish(
+        &self,
+        stream: &str,
+        routing_key: &str,
+        data: &[u8],
+        reply_to: Option<(&str, &str)>,
+    ) -> Result<(), PublishError> {
+        let mut cmd = redis::cmd("XADD");
+        cmd.arg(stream)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(MAX_STREAM_LENGTH)
+            .arg("*");
+        for (field, value) in settle::fields(data, routing_key, reply_to) {
+            cmd.arg(field).arg(value);
+        }
+
+        let span = info_span!("publish", stream = stream, routing_key = routing_key);
+        async {
+            let mut attempt = 1;
+            loop {
+                let err = match self.redis.query::<String>(&cmd).await {
+                    Ok(_) => return Ok(()),
+                    Err(err) => err,
+                };
+                if attempt >= self.publish_attempts {
+                    error!(
+                        "Publish to {} failed after {} attempts: {:?}",
+                        stream, attempt, err
+                    );
+                    return Err(PublishError::Unavailable(err.to_string()));
+                }
+
+                let backoff = publish_backoff(self.publish_backoff, attempt);
+                warn!(
+                    "Publish to {} failed: {:?}, retrying in {:?}",
+                    stream, err, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+impl super::Communicator for RedisStreamsCommunicator {
+    async fn on_match_abrupt_close<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<MatchAbrubtClose, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.consume("match", "abrupt_close", &Route::default(), json, callback)
+            .await;
+    }
+
+    async fn on_match_created<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<CreatedMatch, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.consume("match", "created", &Route::default(), json, callback)
+            .await;
+    }
+
+    async fn on_match_result<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<MatchResult, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.consume("match", "result", &Route::default(), json, callback)
+            .await;
+    }
+
+    async fn on_match_create_for<F, Fut>(&self, route: Route, callback: F)
+    where
+        F: MessageHandler<CreateMatch, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.consume("match", "create", &route, json, callback)
+            .await;
+    }
+
+    async fn on_event<E, F, Fut>(&self, callback: F)
+    where
+        E: Event,
+        F: MessageHandler<E, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        let (name, action) = E::QUEUE;
+        self.consume(name, action, &Route::default(), json, callback)
+            .await;
+    }
+
+    async fn on_request<R, F, Fut>(&self, callback: F)
+    where
+        R: Request,
+        F: MessageHandler<R, Fut>,
+        Fut: Future<Output = HandlerResult<R::Response>> + Send + 'static,
+    {
+        let (name, action) = R::QUEUE;
+        let subscription = self.subscription(name, action, &Route::default());
+        let dedup = self.dedup.clone();
+        self.listen(subscription, move |data| {
+            let callback = callback.clone();
+            let dedup = dedup.clone();
+            async move {
+                let (id, request) = json::<R>(&data).map_err(HandlerError::Reject)?;
+                // Duplicates are not answered, the requester already got the response to the first delivery
+                let response = match (dedup, id) {
+                    (Some(dedup), Some(id)) => dedup.once(&id, callback(request)).await?,
+                    _ => Some(callback(request).await?),
+                };
+                Ok(response.map(|response| encode_response::<R::Response>(&Ok(response))))
+            }
+        })
+        .await;
+    }
+
+    async fn request<R: Request>(&self, request: &R) -> Result<R::Response, RequestError> {
+        let reply_to = self.reply_stream().await;
+        let correlation_id = uuid::Uuid::new_v4().to_string();
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(correlation_id.clone(), sender);
+
+        let (name, action) = R::QUEUE;
+        let sent = self
+            .send(name, action, request, Some((reply_to, &correlation_id)))
+            .await;
+        if let Err(err) = sent {
+            self.pending.lock().unwrap().remove(&correlation_id);
+            return Err(RequestError::Failed(err.to_string()));
+        }
+
+        let response = tokio::time::timeout(self.request_timeout, receiver).await;
+        self.pending.lock().unwrap().remove(&correlation_id);
+        match response {
+            Ok(Ok(data)) => decode_response(&data),
+            _ => Err(RequestError::Timeout),
+        }
+    }
+
+    async fn create_match(&self, match_request: &CreateMatch) -> Result<(), PublishError> {
+        self.send("match", "create", match_request, None).await
+    }
+
+    async fn report_match_abrupt_close(
+        &self,
+        match_close: &MatchAbrubtClose,
+    ) -> Result<(), PublishError> {
+        self.send("match", "abrupt_close", match_close, None).await
+    }
+
+    async fn report_match_created(&self, created_match: &CreatedMatch) -> Result<(), PublishError> {
+        self.send("match", "created", created_match, None).await
+    }
+
+    async fn report_event<E: Event>(&self, event: &E) -> Result<(), PublishError> {
+        let (name, action) = E::QUEUE;
+        self.send(name, action, event, None).await
+    }
+
+    async fn create_ai_task(&self, task: &crate::models::Task) -> Result<(), PublishError> {
+        self.send("ai", "task", task, None).await
+    }
+
+    async fn report_match_result(&self, match_result: &MatchResult) -> Result<(), PublishError> {
+        self.send("match", "result", match_result, None).await
+    }
+
+    async fn send_health_check(&self, report: &HealthReport) -> Result<(), PublishError> {
+        self.send("health_check", "check", report, None).await
+    }
+
+    async fn on_health_check<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<HealthReport, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.consume(
+            "health_check",
+            "check",
+            &Route::default(),
+            health_report,
+            callback,
+        )
+        .await;
+    }
+
+    async fn on_ai_register<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<crate::models::AIPlayerRegister, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        self.consume("ai", "register", &Route::default(), json, callback)
+            .await;
+    }
+
+    async fn register_ai_player(
+        &self,
+        ai_player: &crate::models::AIPlayerRegister,
+    ) -> Result<(), PublishError> {
+        self.send("ai", "register", ai_player, None).await
+    }
+
+    async fn shutdown(&self) {
+        self.shutdown.cancel();
+        self.consumers.close();
+        self.consumers.wait().await;
+        info!("Redis Streams communicator shut down");
+    }
+}
@@ -0,0 +1,237 @@
+use std::time::Duration;
+
+use futures_lite::Future;
+use redis::{streams::StreamId, RedisResult};
+use tracing::{error, warn};
+
+use super::{stream_key, Redis, REPLY_TTL};
+use crate::{
+    consumers::run_handler, encode_response, queues::QueueConfig, HandlerError, HandlerResult,
+};
+
+const DATA_FIELD: &str = "data";
+const ROUTING_KEY_FIELD: &str = "routing_key";
+const REPLY_TO_FIELD: &str = "reply_to";
+const CORRELATION_ID_FIELD: &str = "correlation_id";
+const ERROR_FIELD: &str = "error";
+const ORIGINAL_QUEUE_FIELD: &str = "original_queue";
+const DELIVERIES_FIELD: &str = "deliveries";
+
+/// An entry of a stream read by a consumer group.
+pub(super) struct Delivery {
+    pub(super) id: String,
+    pub(super) data: Vec<u8>,
+    pub(super) routing_key: Option<String>,
+    /// Stream the response goes to if the entry is a request.
+    pub(super) reply_to: Option<String>,
+    pub(super) correlation_id: Option<String>,
+    /// How often the entry was delivered to the group, including this delivery.
+    pub(super) deliveries: usize,
+}
+
+impl Delivery {
+    pub(super) fn new(entry: &StreamId, deliveries: usize) -> Self {
+        Self {
+            id: entry.id.clone(),
+            data: entry.get(DATA_FIELD).unwrap_or_default(),
+            routing_key: entry.get(ROUTING_KEY_FIELD),
+            reply_to: entry.get(REPLY_TO_FIELD),
+            correlation_id: entry.get(CORRELATION_ID_FIELD),
+            deliveries,
+        }
+    }
+}
+
+/// Fields of a new stream entry.
+pub(super) fn fields(
+    data: &[u8],
+    routing_key: &str,
+    reply_to: Option<(&str, &str)>,
+) -> Vec<(&'static str, Vec<u8>)> {
+    let mut fields = vec![
+        (DATA_FIELD, data.to_vec()),
+        (ROUTING_KEY_FIELD, routing_key.as_bytes().to_vec()),
+    ];
+    if let Some((reply_to, correlation_id)) = reply_to {
+        fields.push((REPLY_TO_FIELD, reply_to.as_bytes().to_vec()));
+        fields.push((CORRELATION_ID_FIELD, correlation_id.as_bytes().to_vec()));
+    }
+    fields
+}
+
+/// Reads the correlation id and the response from an entry of a reply stream.
+pub(super) fn response(entry: &StreamId) -> Option<(String, Vec<u8>)> {
+    Some((entry.get(CORRELATION_ID_FIELD)?, entry.get(DATA_FIELD)?))
+}
+
+/// Runs `handler` for `delivery` and acknowledges the delivery afterwards.
+///
+/// Rejected deliveries are dead-lettered right away. Deliveries whose handler asked for a retry or panicked stay
+/// pending in the group and are claimed again after `retry_idle`, until they were delivered more than `max_retries`
+/// times, after which they are dead-lettered as well.
+///
+/// If the delivery is a request, the reply returned by `handler` or the error which dead-lettered the delivery is
+/// added to its `reply_to` stream.
+pub(super) async fn handle<Fut>(
+    redis: &Redis,
+    stream: &str,
+    group: &str,
+    queue: &QueueConfig,
+    retry_idle: Duration,
+    delivery: Delivery,
+    handler: Fut,
+) where
+    Fut: Future<Output = HandlerResult<Option<Vec<u8>>>>,
+{
+    let result = run_handler(handler).await;
+
+    let settled = match result {
+        Ok(None) => Ok(()),
+        Ok(Some(response)) => reply(redis, &delivery, response).await,
+        Err(HandlerError::Reject(err)) => {
+            error!("Message on {} rejected: {}", queue.name, err);
+            fail(redis, queue, &delivery, &err).await
+        }
+        Err(HandlerError::Retry(err)) if delivery.deliveries <= queue.max_retries as usize => {
+            warn!(
+                "Handler for {} failed (attempt {}/{}), retrying in {:?}: {}",
+                queue.name,
+                delivery.deliveries,
+                queue.max_retries + 1,
+                retry_idle,
+                err
+            );
+            // Left pending, so it is claimed again
+            return;
+        }
+        Err(HandlerError::Retry(err)) => {
+            error!(
+                "Handler for {} failed {} times, giving up: {}",
+                queue.name, delivery.deliveries, err
+            );
+            fail(redis, queue, &delivery, &err).await
+        }
+    };
+
+    if let Err(err) = settled {
+        error!(
+            "Failed to settle message on {}, it is delivered again: {:?}",
+            queue.name, err
+        );
+        return;
+    }
+    let acked: RedisResult<()> = redis
+        .query(redis::cmd("XACK").arg(stream).arg(group).arg(&delivery.id))
+        .await;
+    if let Err(err) = acked {
+        error!("Failed to acknowledge delivery: {:?}", err);
+    }
+}
+
+/// Acknowledges an entry without handling it, e.g. because its routing key does not match the consumer.
+pub(super) async fn skip(redis: &Redis, stream: &str, group: &str, id: &str) {
+    let acked: RedisResult<()> = redis
+        .query(redis::cmd("XACK").arg(stream).arg(group).arg(id))
+        .await;
+    if let Err(err) = acked {
+        error!("Failed to acknowledge skipped delivery: {:?}", err);
+    }
+}
+
+/// Dead-letters `delivery` and tells the requester about `err` if there is one.
+async fn fail(
+    redis: &Redis,
+    queue: &QueueConfig,
+    delivery: &Delivery,
+    err: &str,
+) -> RedisResult<()> {
+    dead_letter(redis, queue, delivery, err).await?;
+    reply(redis, delivery, encode_response::<()>(&Err(err.to_owned()))).await
+}
+
+/// Adds `response` to the `reply_to` stream of `delivery`. Does nothing if `delivery` is no request.
+async fn reply(redis: &Redis, delivery: &Delivery, response: Vec<u8>) -> RedisResult<()> {
+    let Some(reply_to) = delivery.reply_to.as_ref() else {
+        return Ok(());
+    };
+    let correlation_id = delivery.correlation_id.clone().unwrap_or_default();
+
+    redis
+        .query::<()>(
+            redis::cmd("XADD")
+                .arg(reply_to)
+                .arg("*")
+                .arg(DATA_FIELD)
+                .arg(response)
+                .arg(CORRELATION_ID_FIELD)
+                .arg(correlation_id),
+        )
+        .await?;
+    // The requester may be gone, in which case nobody removes its reply stream
+    redis
+        .query(
+            redis::cmd("PEXPIRE")
+                .arg(reply_to)
+                .arg(REPLY_TTL.as_millis() as u64),
+        )
+        .await
+}
+
+async fn dead_letter(
+    redis: &Redis,
+    queue: &QueueConfig,
+    delivery: &Delivery,
+    err: &str,
+) -> RedisResult<()> {
+    if queue.dead_letter_exchange.is_none() {
+        error!(
+            "No dead-letter exchange configured for {}, dropping message: {}",
+            queue.name,
+            String::from_utf8_lossy(&delivery.data)
+        );
+        return Ok(());
+    }
+
+    redis
+        .query(
+            redis::cmd("XADD")
+                .arg(stream_key(&queue.dead_letter_queue()))
+                .arg("*")
+                .arg(DATA_FIELD)
+                .arg(&delivery.data)
+                .arg(ROUTING_KEY_FIELD)
+                .arg(delivery.routing_key.as_deref().unwrap_or_default())
+                .arg(ERROR_FIELD)
+                .arg(err)
+                .arg(ORIGINAL_QUEUE_FIELD)
+                .arg(&queue.name)
+                .arg(DELIVERIES_FIELD)
+                .arg(delivery.deliveries),
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::Value;
+
+    use super::*;
+
+    #[test]
+    fn test_delivery_reads_entry_fields() {
+        let entry = StreamId {
+            id: "1-0".to_owned(),
+            map: fields(b"{}", "game.create._._._", Some(("stream:reply.1", "42")))
+                .into_iter()
+                .map(|(field, value)| (field.to_owned(), Value::Data(value)))
+                .collect(),
+        };
+
+        let delivery = Delivery::new(&entry, 2);
+        assert_eq!(delivery.data, b"{}");
+        assert_eq!(delivery.routing_key.as_deref(), Some("game.create._._._"));
+        assert_eq!(delivery.reply_to.as_deref(), Some("stream:reply.1"));
+        assert_eq!(delivery.deliveries, 2);
+        assert_eq!(response(&entry), Some(("42".to_owned(), b"{}".to_vec())));
+    }
+}
@@ -0,0 +1,171 @@
+/// Game, mode and region a message is about, and the game server it is addressed to if there is one.
+///
+/// Messages are published to topic exchanges with the routing key `<type>.<game>.<mode>.<region>`, e.g.
+/// `match.create.chess.ranked._`, followed by `.<server>` if they are addressed to a server. Used as a filter,
+/// fields which are `None` match every value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Route {
+    pub game: Option<String>,
+    pub mode: Option<String>,
+    pub region: Option<String>,
+    /// Id of the game server, as returned by [`Communicator::create_game`](crate::Communicator::create_game).
+    pub server: Option<String>,
+}
+
+/// Routing key segment of a field which is not set.
+const UNSET: &str = "_";
+
+impl Route {
+    /// Matches every message of `game`.
+    pub fn game(game: &str) -> Self {
+        Self {
+            game: Some(game.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_mode(mut self, mode: &str) -> Self {
+        self.mode = Some(mode.to_owned());
+        self
+    }
+
+    pub fn with_region(mut self, region: &str) -> Self {
+        self.region = Some(region.to_owned());
+        self
+    }
+
+    /// Matches the messages addressed to the game server `server`.
+    pub fn server(server: &str) -> Self {
+        Self {
+            server: Some(server.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the route matches every message.
+    pub fn is_any(&self) -> bool {
+        self == &Self::default()
+    }
+
+    fn segments(&self) -> [Option<&String>; 3] {
+        [self.game.as_ref(), self.mode.as_ref(), self.region.as_ref()]
+    }
+
+    /// Routing key a message of `message_type` with this route is published with.
+    pub(crate) fn routing_key(&self, message_type: &str) -> String {
+        let segments = self
+            .segments()
+            .map(|segment| segment.map_or(UNSET.to_owned(), |x| escape(x)));
+        match self.server.as_ref() {
+            Some(server) => format!("{}.{}.{}", message_type, segments.join("."), escape(server)),
+            None => format!("{}.{}", message_type, segments.join(".")),
+        }
+    }
+
+    /// Binding pattern matching the routing keys of all messages of `message_type` this route matches.
+    pub(crate) fn binding(&self, message_type: &str) -> String {
+        if self.is_any() {
+            return format!("{}.#", message_type);
+        }
+        let segments = self
+            .segments()
+            .map(|segment| segment.map_or("*".to_owned(), |x| escape(x)));
+        // Without a server, messages which are addressed to one match as well
+        let server = self.server.as_ref().map_or("#".to_owned(), |x| escape(x));
+        format!("{}.{}.{}", message_type, segments.join("."), server)
+    }
+
+    /// Suffix of the name of a queue which only receives the messages matching this route, e.g. `chess.any.any`.
+    pub(crate) fn queue_suffix(&self) -> String {
+        let segments = self
+            .segments()
+            .map(|segment| segment.map_or("any".to_owned(), |x| escape(x)))
+            .join(".");
+        match self.server.as_ref() {
+            Some(server) => format!("{}.{}", segments, escape(server)),
+            None => segments,
+        }
+    }
+}
+
+/// Routing key segments must not contain the separator.
+fn escape(segment: &str) -> String {
+    segment.replace('.', "_")
+}
+
+/// Whether `routing_key` matches the topic exchange binding `pattern`.
+/// `*` matches exactly one segment and `#` zero or more segments.
+#[cfg(any(feature = "memory", feature = "redis-streams"))]
+pub(crate) fn matches(pattern: &str, routing_key: &str) -> bool {
+    fn matches_segments(pattern: &[&str], key: &[&str]) -> bool {
+        match (pattern.first(), key.first()) {
+            (None, None) => true,
+            (Some(&"#"), _) => {
+                matches_segments(&pattern[1..], key)
+                    || (!key.is_empty() && matches_segments(pattern, &key[1..]))
+            }
+            (Some(&"*"), Some(_)) => matches_segments(&pattern[1..], &key[1..]),
+            (Some(p), Some(k)) if p == k => matches_segments(&pattern[1..], &key[1..]),
+            _ => false,
+        }
+    }
+
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let key: Vec<&str> = routing_key.split('.').collect();
+    matches_segments(&pattern, &key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing_key_and_binding() {
+        let route = Route::game("chess").with_region("eu.west");
+        assert_eq!(
+            route.routing_key("match.create"),
+            "match.create.chess._.eu_west"
+        );
+        assert_eq!(
+            route.binding("match.create"),
+            "match.create.chess.*.eu_west.#"
+        );
+        assert_eq!(Route::default().binding("match.create"), "match.create.#");
+        assert_eq!(route.queue_suffix(), "chess.any.eu_west");
+
+        let route = Route::server("server-1");
+        assert_eq!(
+            Route {
+                game: Some("chess".to_owned()),
+                ..route.clone()
+            }
+            .routing_key("match.create"),
+            "match.create.chess._._.server-1"
+        );
+        assert_eq!(route.binding("match.create"), "match.create.*.*.*.server-1");
+        assert_eq!(route.queue_suffix(), "any.any.any.server-1");
+    }
+
+    #[cfg(any(feature = "memory", feature = "redis-streams"))]
+    #[test]
+    fn test_matches() {
+        assert!(matches("match.create.#", "match.create.chess.ranked._"));
+        assert!(matches(
+            "match.create.chess.*.*",
+            "match.create.chess.ranked._"
+        ));
+        assert!(!matches(
+            "match.create.chess.*.*",
+            "match.create.go.ranked._"
+        ));
+        assert!(!matches("match.create.#", "match.created.chess.ranked._"));
+        assert!(matches(
+            "match.create.chess.*.*.#",
+            "match.create.chess.ranked._.server-1"
+        ));
+        assert!(!matches(
+            "match.create.*.*.*.server-1",
+            "match.create.chess.ranked._"
+        ));
+    }
+}
@@ -0,0 +1,211 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use serde::Deserialize;
+
+use crate::{
+    consumers::ConsumerStatuses,
+    dedup::Deduplicator,
+    models::{
+        AIPlayerRegister, CreateMatch, CreatedMatch, HealthReport, MatchAbrubtClose, MatchResult,
+        Task,
+    },
+    outbox::Outbox,
+    rabbitmq::RabbitMQCommunicator,
+    redis_streams::RedisStreamsCommunicator,
+    routing::Route,
+    Communicator, Event, HandlerResult, MessageHandler, PublishError, Request, RequestError,
+};
+
+/// Broker the messages of a service are sent over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransportKind {
+    #[default]
+    Rabbitmq,
+    /// Redis Streams, for small deployments which run Redis anyway.
+    RedisStreams,
+}
+
+/// A [`Communicator`] whose broker is chosen at runtime, e.g. from the configuration of a service.
+pub enum Transport {
+    RabbitMQ(RabbitMQCommunicator),
+    RedisStreams(RedisStreamsCommunicator),
+}
+
+/// Calls the same expression on whichever communicator `$self` wraps.
+macro_rules! delegate {
+    ($self:ident, $communicator:ident => $call:expr) => {
+        match $self {
+            Transport::RabbitMQ($communicator) => $call,
+            Transport::RedisStreams($communicator) => $call,
+        }
+    };
+}
+
+impl Transport {
+    /// Connects to the broker of `kind` at `url`, an AMQP url for RabbitMQ and a Redis url for Redis Streams.
+    pub async fn connect(kind: TransportKind, url: &str) -> Self {
+        match kind {
+            TransportKind::Rabbitmq => Self::RabbitMQ(RabbitMQCommunicator::connect(url).await),
+            TransportKind::RedisStreams => {
+                Self::RedisStreams(RedisStreamsCommunicator::connect(url).await)
+            }
+        }
+    }
+
+    pub fn load_queues(&mut self, path: &str) {
+        delegate!(self, communicator => communicator.load_queues(path))
+    }
+
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        delegate!(self, communicator => communicator.set_request_timeout(timeout))
+    }
+
+    pub fn set_producer(&mut self, producer: &str) {
+        delegate!(self, communicator => communicator.set_producer(producer))
+    }
+
+    pub fn set_deduplicator(&mut self, deduplicator: Arc<dyn Deduplicator>, ttl: Duration) {
+        delegate!(self, communicator => communicator.set_deduplicator(deduplicator, ttl))
+    }
+
+    pub fn set_publish_retry(&mut self, attempts: u32, backoff: Duration) {
+        delegate!(self, communicator => communicator.set_publish_retry(attempts, backoff))
+    }
+
+    /// See [`RabbitMQCommunicator::set_outbox`]. Redis Streams keep the messages in Redis already and don't use it.
+    pub fn set_outbox(&mut self, outbox: Arc<dyn Outbox>, instance: &str) {
+        if let Self::RabbitMQ(communicator) = self {
+            communicator.set_outbox(outbox, instance);
+        }
+    }
+
+    /// See [`RabbitMQCommunicator::flush_outbox`].
+    pub async fn flush_outbox(&self) {
+        if let Self::RabbitMQ(communicator) = self {
+            communicator.flush_outbox().await;
+        }
+    }
+
+    /// See [`RabbitMQCommunicator::retry_outbox`].
+    pub async fn retry_outbox(&self, interval: Duration) {
+        if let Self::RabbitMQ(communicator) = self {
+            communicator.retry_outbox(interval).await;
+        }
+    }
+
+    pub fn consumer_statuses(&self) -> ConsumerStatuses {
+        delegate!(self, communicator => communicator.consumer_statuses())
+    }
+}
+
+impl Communicator for Transport {
+    async fn on_match_abrupt_close<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<MatchAbrubtClose, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        delegate!(self, communicator => communicator.on_match_abrupt_close(callback).await)
+    }
+
+    async fn on_match_result<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<MatchResult, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        delegate!(self, communicator => communicator.on_match_result(callback).await)
+    }
+
+    async fn on_match_created<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<CreatedMatch, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        delegate!(self, communicator => communicator.on_match_created(callback).await)
+    }
+
+    async fn on_event<E, F, Fut>(&self, callback: F)
+    where
+        E: Event,
+        F: MessageHandler<E, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        delegate!(self, communicator => communicator.on_event(callback).await)
+    }
+
+    async fn on_request<R, F, Fut>(&self, callback: F)
+    where
+        R: Request,
+        F: MessageHandler<R, Fut>,
+        Fut: Future<Output = HandlerResult<R::Response>> + Send + 'static,
+    {
+        delegate!(self, communicator => communicator.on_request(callback).await)
+    }
+
+    async fn request<R: Request>(&self, request: &R) -> Result<R::Response, RequestError> {
+        delegate!(self, communicator => communicator.request(request).await)
+    }
+
+    async fn on_health_check<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<HealthReport, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        delegate!(self, communicator => communicator.on_health_check(callback).await)
+    }
+
+    async fn on_match_create_for<F, Fut>(&self, route: Route, callback: F)
+    where
+        F: MessageHandler<CreateMatch, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        delegate!(self, communicator => communicator.on_match_create_for(route, callback).await)
+    }
+
+    async fn on_ai_register<F, Fut>(&self, callback: F)
+    where
+        F: MessageHandler<AIPlayerRegister, Fut>,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        delegate!(self, communicator => communicator.on_ai_register(callback).await)
+    }
+
+    async fn send_health_check(&self, report: &HealthReport) -> Result<(), PublishError> {
+        delegate!(self, communicator => communicator.send_health_check(report).await)
+    }
+
+    async fn create_match(&self, match_request: &CreateMatch) -> Result<(), PublishError> {
+        delegate!(self, communicator => communicator.create_match(match_request).await)
+    }
+
+    async fn report_match_created(&self, created_match: &CreatedMatch) -> Result<(), PublishError> {
+        delegate!(self, communicator => communicator.report_match_created(created_match).await)
+    }
+
+    async fn report_match_result(&self, match_result: &MatchResult) -> Result<(), PublishError> {
+        delegate!(self, communicator => communicator.report_match_result(match_result).await)
+    }
+
+    async fn report_match_abrupt_close(
+        &self,
+        match_close: &MatchAbrubtClose,
+    ) -> Result<(), PublishError> {
+        delegate!(self, communicator => communicator.report_match_abrupt_close(match_close).await)
+    }
+
+    async fn report_event<E: Event>(&self, event: &E) -> Result<(), PublishError> {
+        delegate!(self, communicator => communicator.report_event(event).await)
+    }
+
+    async fn create_ai_task(&self, task: &Task) -> Result<(), PublishError> {
+        delegate!(self, communicator => communicator.create_ai_task(task).await)
+    }
+
+    async fn register_ai_player(&self, ai_player: &AIPlayerRegister) -> Result<(), PublishError> {
+        delegate!(self, communicator => communicator.register_ai_player(ai_player).await)
+    }
+
+    async fn shutdown(&self) {
+        delegate!(self, communicator => communicator.shutdown().await)
+    }
+}
@@ -0,0 +1,24 @@
+use gn_service::{
+    config::{require_url, ConfigError, Validate},
+    telemetry::TelemetryConfig,
+};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// Address the Socket.IO and metrics server binds to.
+    pub host_addr: std::net::SocketAddr,
+    pub redis_url: String,
+    pub ezauth_url: String,
+    pub ranking_api_key: String,
+    #[serde(flatten)]
+    pub telemetry: TelemetryConfig,
+}
+
+impl Validate for Config {
+    fn validate(&self) -> Result<(), ConfigError> {
+        require_url("redis_url", &self.redis_url, &["redis", "rediss"])?;
+        require_url("ezauth_url", &self.ezauth_url, &["http", "https"])?;
+        self.telemetry.validate()
+    }
+}
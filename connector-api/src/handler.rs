@@ -3,8 +3,8 @@ use gn_matchmaking_state::adapters::{
     redis::RedisAdapterDefault, Gettable, Insertable, Removable, Updateable,
 };
 use gn_matchmaking_state_types::{
-    ActiveMatchDB, DBGameServer, DBSearcher, GameServer, HostRequest, HostRequestDB,
-    HostRequestUpdate, Searcher,
+    ActiveMatchDB, DBGameServer, DBSearcher, GameModeDB, GameServer, HostRequest, HostRequestDB,
+    HostRequestUpdate, LeaverPenaltyDB, Party, PartyDB, PartyUpdate, Searcher,
 };
 use rand::{distributions::Alphanumeric, Rng};
 use std::{
@@ -17,9 +17,10 @@ use tracing::{debug, info, warn};
 use uuid::Uuid;
 
 use axum::body::Bytes;
+use gn_service::shutdown::Shutdown;
 use socketioxide::extract::SocketRef;
 
-use crate::models::{Host, JoinPriv, JoinPub, Match, Search};
+use crate::models::{Host, JoinPriv, JoinPub, Match, PartyAccept, PartyInvite, Search, Session};
 
 const DEFAULT_ELO: u32 = 1250;
 
@@ -37,6 +38,26 @@ pub enum HandlerError {
     PlayerAlreadyPlaying(ActiveMatchDB),
     MatchIsFull,
     InvalidJoinToken,
+    ShuttingDown,
+    /// The player left or broke a match and may not search for the contained number of seconds.
+    PlayerPenalized(u64),
+    /// The game mode is not in the catalog.
+    UnknownGameMode,
+    /// The game mode can't be played against AI players.
+    AINotAllowed,
+    PlayerAlreadyInParty,
+    PlayerAlreadySearching,
+    PlayerNotInParty,
+    PlayerNotInvited,
+    PartyNotFound,
+    /// Only the leader of a party may invite players and search for the party.
+    NotPartyLeader,
+    /// Nobody can join a party while it searches.
+    PartyIsSearching,
+    /// The party has more players than the game mode allows.
+    PartyTooLarge,
+    /// A member of the party is still playing a match.
+    PartyMemberPlaying,
 }
 
 impl fmt::Display for HandlerError {
@@ -56,6 +77,7 @@ pub struct Handler {
     ezauth_response: Mutex<Option<EZAUTHValidationResponse>>,
     ezauth_url: String,
     ranking_client: Arc<gn_ranking_client_rs::RankingClient>,
+    shutdown: Shutdown,
 }
 
 impl Handler {
@@ -65,6 +87,8 @@ impl Handler {
     ///
     /// * `state` - An `Arc` containing the `RedisAdapterDefault` instance.
     /// * `ranking_client` - An `Arc` containing the `RankingClient` instance.
+    /// * `ezauth_url` - The base URL of the ezauth service.
+    /// * `shutdown` - The shutdown of the service. No new searches are accepted once it was triggered.
     ///
     /// # Returns
     ///
@@ -72,10 +96,9 @@ impl Handler {
     pub fn new(
         state: Arc<RedisAdapterDefault>,
         ranking_client: Arc<gn_ranking_client_rs::RankingClient>,
+        ezauth_url: String,
+        shutdown: Shutdown,
     ) -> Self {
-        let ezauth_url = std::env::var("EZAUTH_URL").unwrap();
-        let ezauth_url = ezauth_url.to_string();
-
         Self {
             search: Mutex::new(None),
             state,
@@ -83,9 +106,77 @@ impl Handler {
             ezauth_url,
             ranking_client,
             ezauth_response: Mutex::new(None),
+            shutdown,
+        }
+    }
+
+    /// Fails if the service is shutting down and therefore must not register new searchers or host requests.
+    #[inline]
+    fn ensure_accepting(&self) -> Result<(), HandlerError> {
+        if self.shutdown.is_triggered() {
+            return Err(HandlerError::ShuttingDown);
+        }
+        Ok(())
+    }
+
+    /// Fails if the player has a running leaver penalty for the game mode. Expired penalties are removed.
+    fn ensure_not_penalized(
+        &self,
+        player_id: &str,
+        game: &str,
+        mode: &str,
+    ) -> Result<(), HandlerError> {
+        let penalty = self.state.all().unwrap().find(|x: &LeaverPenaltyDB| {
+            x.player_id == player_id && x.game == game && x.mode == mode
+        });
+        let Some(penalty) = penalty else {
+            return Ok(());
+        };
+
+        match penalty.until.duration_since(SystemTime::now()) {
+            Ok(remaining) => Err(HandlerError::PlayerPenalized(remaining.as_secs() + 1)),
+            Err(_) => {
+                if let Err(err) = self.state.remove(&penalty.uuid) {
+                    warn!("Failed to remove expired leaver penalty: {:?}", err);
+                }
+                Ok(())
+            }
         }
     }
 
+    /// Looks the game mode up in the catalog. Searches and host requests are only accepted for registered modes.
+    fn find_game_mode(&self, game: &str, mode: &str) -> Result<GameModeDB, HandlerError> {
+        self.state
+            .all()
+            .unwrap()
+            .find(|x: &GameModeDB| x.game == game && x.mode == mode)
+            .ok_or(HandlerError::UnknownGameMode)
+    }
+
+    /// The party `player_id` is a member of.
+    fn find_party(&self, player_id: &str) -> Option<PartyDB> {
+        self.state
+            .all()
+            .unwrap()
+            .find(|x: &PartyDB| x.members.iter().any(|member| member == player_id))
+    }
+
+    /// Whether `player_id` searches, alone or for their party.
+    fn is_searching(&self, player_id: &str) -> bool {
+        self.state
+            .all()
+            .unwrap()
+            .any(|x: DBSearcher| x.player_id == player_id)
+    }
+
+    /// Whether `player_id` is a player of an active match.
+    fn is_playing(&self, player_id: &str) -> bool {
+        self.state
+            .all()
+            .unwrap()
+            .any(|x: ActiveMatchDB| x.player_write.contains_key(player_id))
+    }
+
     /// Retrieves the searcher ID if available.
     ///
     /// # Returns
@@ -114,7 +205,7 @@ impl Handler {
     ///
     /// # Arguments
     ///
-    /// * `validation` - A reference to the `EZAUTHValidationResponse`.
+    /// * `player_id` - The id of the player.
     /// * `game` - The game name.
     /// * `mode` - The game mode.
     ///
@@ -122,18 +213,13 @@ impl Handler {
     ///
     /// A `Result` containing the ELO rating or an error.
     #[inline]
-    async fn get_elo(
-        &self,
-        validation: &ezauth::EZAUTHValidationResponse,
-        game: &str,
-        mode: &str,
-    ) -> u32 {
+    async fn get_elo(&self, player_id: &str, game: &str, mode: &str) -> u32 {
         #[cfg(disable_elo)]
         return DEFAULT_ELO;
         #[cfg(not(disable_elo))]
         return match self
             .ranking_client
-            .player_stars(&validation._id, game, mode)
+            .player_stars(player_id, game, mode)
             .await
         {
             Ok(stars) => stars as u32,
@@ -154,19 +240,22 @@ impl Handler {
     ///
     /// # Returns
     ///
-    /// A vector of `DBGameServer` instances that match the criteria.
+    /// A vector of `DBGameServer` instances that match the criteria and can take another match, least loaded first.
     #[inline]
     fn check_for_active_servers(&self, game: &str, mode: &str, region: &str) -> Vec<DBGameServer> {
-        self.state
+        let mut servers: Vec<DBGameServer> = self
+            .state
             .all()
             .unwrap()
             .filter(|server: &DBGameServer| {
-                server.healthy
+                server.accepts_matches()
                     && server.game == game
                     && server.mode == mode
                     && server.region == region
             })
-            .collect()
+            .collect();
+        servers.sort_by(|a, b| a.load().total_cmp(&b.load()));
+        servers
     }
 
     /// Handles a search request for a match.
@@ -180,6 +269,7 @@ impl Handler {
     /// A `Result` indicating success or failure.
     pub async fn handle_search(&self, data: Search) -> Result<(), HandlerError> {
         debug!("Received Search event: {:?}", data);
+        self.ensure_accepting()?;
 
         let validation = self.authorize(&data.session_token).await?;
 
@@ -194,6 +284,38 @@ impl Handler {
             }
         }
 
+        // A party leader searches for the whole party
+        let party_members: Vec<String> = match self.find_party(&validation._id) {
+            Some(party) if party.leader != validation._id => {
+                return Err(HandlerError::NotPartyLeader)
+            }
+            Some(party) => party
+                .members
+                .into_iter()
+                .filter(|x| *x != validation._id)
+                .collect(),
+            None => Vec::new(),
+        };
+
+        self.ensure_not_penalized(&validation._id, &data.game, &data.mode)?;
+        for member in &party_members {
+            self.ensure_not_penalized(member, &data.game, &data.mode)?;
+            if self.is_playing(member) {
+                return Err(HandlerError::PartyMemberPlaying);
+            }
+            if self.is_searching(member) {
+                return Err(HandlerError::PlayerAlreadySearching);
+            }
+        }
+
+        let game_mode = self.find_game_mode(&data.game, &data.mode)?;
+        if data.ai.is_some() && !game_mode.ai_allowed {
+            return Err(HandlerError::AINotAllowed);
+        }
+        if party_members.len() + 1 > game_mode.max_players as usize {
+            return Err(HandlerError::PartyTooLarge);
+        }
+
         let servers = self.check_for_active_servers(&data.game, &data.mode, &data.region);
 
         debug!("Servers found for search ({:?}): {:?}", data, servers);
@@ -202,7 +324,11 @@ impl Handler {
             return Err(HandlerError::NoServerOnline);
         }
 
-        let elo = self.get_elo(&validation, &data.game, &data.mode).await;
+        // A party is matched by its strongest player, so it is not put into matches it would overpower
+        let mut elo = self.get_elo(&validation._id, &data.game, &data.mode).await;
+        for member in &party_members {
+            elo = elo.max(self.get_elo(member, &data.game, &data.mode).await);
+        }
 
         let search = data;
 
@@ -216,7 +342,6 @@ impl Handler {
             return Ok(());
         }
 
-        let sample_server = servers.first().unwrap();
         let searcher = Searcher {
             player_id: validation._id.clone(),
             elo,
@@ -224,9 +349,11 @@ impl Handler {
             mode: search.mode.clone(),
             ai: search.ai,
             region: search.region.clone(),
-            min_players: sample_server.min_players,
-            max_players: sample_server.max_players,
+            min_players: game_mode.min_players,
+            max_players: game_mode.max_players,
             wait_start: SystemTime::now(),
+            party_size: party_members.len() as u32 + 1,
+            party_members,
         };
         let uuid = self.state.insert(searcher).unwrap();
         debug!("Searcher inserted with uuid: {}", uuid);
@@ -289,7 +416,9 @@ impl Handler {
     ///
     /// A `Result` containing the join token or an error.
     pub async fn handle_host(&self, data: Host) -> Result<String, HandlerError> {
+        self.ensure_accepting()?;
         let validation = self.authorize(&data.session_token).await?;
+        let game_mode = self.find_game_mode(&data.game, &data.mode)?;
 
         let servers = self.check_for_active_servers(&data.game, &data.mode, &data.region);
 
@@ -323,8 +452,8 @@ impl Handler {
             join_token: join_token.clone(),
             joined_players: vec![validation._id.clone()],
             start_requested: false,
-            min_players: servers.first().unwrap().min_players,
-            max_players: servers.first().unwrap().max_players,
+            min_players: game_mode.min_players,
+            max_players: game_mode.max_players,
             wait_start: SystemTime::now(),
         };
 
@@ -406,6 +535,7 @@ impl Handler {
     ///
     /// A `Result` indicating success or failure.
     pub async fn handle_join_priv(&self, data: JoinPriv) -> Result<(), HandlerError> {
+        self.ensure_accepting()?;
         if data.join_token.is_empty() {
             return Err(HandlerError::InvalidJoinToken);
         }
@@ -433,6 +563,7 @@ impl Handler {
     ///
     /// A `Result` indicating success or failure.
     pub async fn handle_join_pub(&self, data: JoinPub) -> Result<(), HandlerError> {
+        self.ensure_accepting()?;
         let validation = self.authorize(&data.session_token).await?;
 
         let host_request: HostRequestDB = self.state.get(&data.host_id).unwrap();
@@ -495,6 +626,163 @@ impl Handler {
         Ok(())
     }
 
+    /// Handles a request to create a party led by the player.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The party data.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new party or an error.
+    pub async fn handle_party_create(&self, data: Session) -> Result<PartyDB, HandlerError> {
+        self.ensure_accepting()?;
+        let validation = self.authorize(&data.session_token).await?;
+
+        if self.find_party(&validation._id).is_some() {
+            return Err(HandlerError::PlayerAlreadyInParty);
+        }
+
+        let party = Party {
+            leader: validation._id.clone(),
+            members: vec![validation._id.clone()],
+            invited: Vec::new(),
+        };
+        let uuid = self.state.insert(party).unwrap();
+        debug!("Party inserted with uuid: {}", uuid);
+        Ok(self.state.get(&uuid).unwrap())
+    }
+
+    /// Handles a request of a party leader to invite a player to their party.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The invite data.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the updated party or an error.
+    pub async fn handle_party_invite(&self, data: PartyInvite) -> Result<PartyDB, HandlerError> {
+        self.ensure_accepting()?;
+        let validation = self.authorize(&data.session_token).await?;
+
+        let mut party = self
+            .find_party(&validation._id)
+            .ok_or(HandlerError::PlayerNotInParty)?;
+        if party.leader != validation._id {
+            return Err(HandlerError::NotPartyLeader);
+        }
+        if party.members.contains(&data.player_id) {
+            return Err(HandlerError::PlayerAlreadyInParty);
+        }
+
+        if !party.invited.contains(&data.player_id) {
+            party.invited.push(data.player_id);
+        }
+        let update = PartyUpdate {
+            invited: Some(party.invited.clone()),
+            ..Default::default()
+        };
+        self.state.update(&party.uuid, update).unwrap();
+        Ok(party)
+    }
+
+    /// Handles a request to accept the invite to a party.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The accept data.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the joined party or an error.
+    pub async fn handle_party_accept(&self, data: PartyAccept) -> Result<PartyDB, HandlerError> {
+        self.ensure_accepting()?;
+        let validation = self.authorize(&data.session_token).await?;
+
+        if self.find_party(&validation._id).is_some() {
+            return Err(HandlerError::PlayerAlreadyInParty);
+        }
+        if self.is_searching(&validation._id) {
+            return Err(HandlerError::PlayerAlreadySearching);
+        }
+
+        let mut party: PartyDB = self
+            .state
+            .get(&data.party_id)
+            .map_err(|_| HandlerError::PartyNotFound)?;
+        if !party.invited.contains(&validation._id) {
+            return Err(HandlerError::PlayerNotInvited);
+        }
+        if self.is_searching(&party.leader) {
+            return Err(HandlerError::PartyIsSearching);
+        }
+
+        party.invited.retain(|x| *x != validation._id);
+        party.members.push(validation._id.clone());
+        let update = PartyUpdate {
+            members: Some(party.members.clone()),
+            invited: Some(party.invited.clone()),
+            ..Default::default()
+        };
+        self.state.update(&party.uuid, update).unwrap();
+        Ok(party)
+    }
+
+    /// Handles a request for the party of the player.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The session of the player.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the party or an error if the player is not in a party.
+    pub async fn handle_party_info(&self, data: Session) -> Result<PartyDB, HandlerError> {
+        let validation = self.authorize(&data.session_token).await?;
+        self.find_party(&validation._id)
+            .ok_or(HandlerError::PlayerNotInParty)
+    }
+
+    /// Takes the player out of their party. A party whose leader leaves is disbanded. A party which is searching
+    /// stops its search, so nobody is matched with a party they left.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or failure.
+    pub fn leave_party(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(player_id) = self.get_user_id() else {
+            return Ok(());
+        };
+        let Some(mut party) = self.find_party(&player_id) else {
+            return Ok(());
+        };
+
+        if let Some(searcher) = self
+            .state
+            .all()?
+            .find(|x: &DBSearcher| x.player_id == party.leader)
+        {
+            self.state.remove(&searcher.uuid)?;
+            debug!("Search of party {} stopped", party.uuid);
+        }
+
+        if party.leader == player_id {
+            self.state.remove(&party.uuid)?;
+            debug!("Party {} disbanded", party.uuid);
+            return Ok(());
+        }
+
+        party.members.retain(|x| *x != player_id);
+        let update = PartyUpdate {
+            members: Some(party.members),
+            ..Default::default()
+        };
+        self.state.update(&party.uuid, update)?;
+        debug!("Player {} left party {}", player_id, party.uuid);
+        Ok(())
+    }
+
     /// Removes the current searcher from the state.
     ///
     /// # Returns
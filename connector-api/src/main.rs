@@ -1,11 +1,18 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    future::IntoFuture,
+    sync::{Arc, Mutex},
+};
 
 use gn_matchmaking_state::prelude::*;
+use gn_service::{config::ConfigLoader, health::Health, shutdown::Shutdown, telemetry};
 use gn_matchmaking_state_types::{DBSearcher, HostRequestDB};
 use handler::{Handler, HandlerError};
-use lazy_static::lazy_static;
 use match_maker::MatchMaker;
-use models::{Host, HostInfo, JoinPriv, JoinPub, Match, Search};
+use metrics::{counter, gauge};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use models::{
+    Host, HostInfo, JoinPriv, JoinPub, Match, PartyAccept, PartyInfo, PartyInvite, Search, Session,
+};
 use rand::rngs::adapter;
 use serde_json::Value;
 use socketioxide::{
@@ -16,15 +23,29 @@ use tower_http::{
     cors::{Any, CorsLayer},
     validate_request::ValidateRequestHeaderLayer,
 };
-use tracing::{debug, error, info, Level};
-use tracing_subscriber::FmtSubscriber;
+use tracing::{debug, error, info};
 
+mod config;
 mod handler;
 mod match_maker;
 mod models;
 
-lazy_static! {
-    static ref HOST_ADDR: String = option_env!("HOST_ADDR").unwrap().to_string();
+const HEALTH_CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Registers `handler` as the disconnect handler of `socket`, keeps the connected sockets gauge up to date and takes the
+/// player of `player_handler` out of their party.
+/// Socket.IO only keeps the last registered disconnect handler, therefore every disconnect handler has to be registered through this function.
+fn on_disconnect<F>(socket: &SocketRef, player_handler: Arc<Handler>, handler: F)
+where
+    F: Fn(SocketRef) + Clone + Send + Sync + 'static,
+{
+    socket.on_disconnect(move |socket: SocketRef| {
+        gauge!("connector_connected_sockets").decrement(1.0);
+        handler(socket);
+        if let Err(err) = player_handler.leave_party() {
+            error!("Error leaving party: {:?}", err);
+        }
+    });
 }
 
 /// Sets up listeners for various Socket.IO events related to match-making.
@@ -34,6 +55,8 @@ lazy_static! {
 /// - `host`: Hosts a new match.
 /// - `start`: Starts a hosted match.
 /// - `join`: Joins an existing match.
+/// - `party_create`, `party_invite`, `party_accept`, `party_leave` and `party_info`: Manage the party of the player.
+///   A party leader's `search` is for the whole party.
 ///
 /// Each event handler performs the necessary actions and emits appropriate responses or errors.
 /// Additionally, it sets up disconnection handlers to clean up resources when a socket disconnects.
@@ -43,6 +66,9 @@ lazy_static! {
 /// * `io` - A reference to the `SocketIo` instance.
 /// * `adapter` - An `Arc` containing the `RedisAdapterDefault` instance.
 /// * `ranking_client` - An `Arc` containing the `RankingClient` instance.
+/// * `ezauth_url` - The base URL of the ezauth service used to authenticate sockets.
+/// * `shutdown` - The shutdown of the service. Sockets are not allowed to search anymore once it was triggered.
+/// * `health` - The health of the service. The listener for created matches is registered as liveness check.
 ///
 /// # Example
 ///
@@ -54,20 +80,36 @@ lazy_static! {
 ///
 ///     let (_, io) = SocketIo::new_layer();
 ///
-///     setup_listeners(&io, adapter, ranking_client);
+///     let shutdown = Shutdown::new();
+///     let health = Health::new(shutdown.clone());
+///
+///     setup_listeners(&io, adapter, ranking_client, "http://ezauth:3000".to_owned(), shutdown, &health);
 /// ```
 fn setup_listeners(
     io: &SocketIo,
     adapter: Arc<RedisAdapterDefault>,
     ranking_client: Arc<gn_ranking_client_rs::RankingClient>,
+    ezauth_url: String,
+    shutdown: Shutdown,
+    health: &Health,
 ) {
     let match_maker = match_maker::MatchMaker::new(adapter.clone());
+    health.add_task("match_listener", match_maker.lock().unwrap().listener());
     let adapter_clone = adapter.clone();
 
     let on_match_search = {
         move |socket: SocketRef| {
             info!("Socket.IO connected: {:?} {:?}", socket.ns(), socket.id);
-            let handler = Arc::new(Handler::new(adapter_clone.clone(), ranking_client));
+            gauge!("connector_connected_sockets").increment(1.0);
+            let handler = Arc::new(Handler::new(
+                adapter_clone.clone(),
+                ranking_client,
+                ezauth_url.clone(),
+                shutdown.clone(),
+            ));
+            on_disconnect(&socket, handler.clone(), |socket: SocketRef| {
+                info!("Socket.IO disconnected: {:?}", socket.id);
+            });
 
             // Generic handler to notify that a match has been found
             let notify_on_match = {
@@ -91,6 +133,13 @@ fn setup_listeners(
                 let init_notify_on_match = notify_on_match.clone();
                 move |socket: SocketRef, Data::<Search>(data)| async move {
                     debug!("Search event received: {:?}", data);
+                    counter!(
+                        "connector_searches_total",
+                        "game" => data.game.clone(),
+                        "mode" => data.mode.clone(),
+                        "region" => data.region.clone()
+                    )
+                    .increment(1);
                     if let Err(err) = handler.handle_search(data).await {
                         match err {
                             HandlerError::PlayerAlreadyPlaying(active_match) => {
@@ -108,14 +157,17 @@ fn setup_listeners(
 
                     init_notify_on_match();
 
-                    let stop_search = move |socket: SocketRef| {
-                        info!("Socket.IO disconnected: {:?}", socket.id);
-                        if let Err(err) = handler.remove_searcher() {
-                            error!("Error removing searcher: {:?}", err);
+                    let stop_search = {
+                        let handler = handler.clone();
+                        move |socket: SocketRef| {
+                            info!("Socket.IO disconnected: {:?}", socket.id);
+                            if let Err(err) = handler.remove_searcher() {
+                                error!("Error removing searcher: {:?}", err);
+                            }
                         }
                     };
 
-                    socket.on_disconnect(stop_search.clone());
+                    on_disconnect(&socket, handler, stop_search.clone());
 
                     socket.on("stop_search", stop_search);
                 }
@@ -137,7 +189,7 @@ fn setup_listeners(
                     };
 
                     init_notify_on_match();
-                    socket.on_disconnect(move |socket: SocketRef| {
+                    on_disconnect(&socket, host_handler.clone(), move |socket: SocketRef| {
                         info!("Socket.IO disconnected: {:?}", socket.id);
                         host_handler.remove_searcher().unwrap();
                     });
@@ -156,6 +208,13 @@ fn setup_listeners(
             socket.on("join", {
                 let init_notify_on_match = notify_on_match.clone();
                 move |socket: SocketRef, Data::<Value>(data)| async move {
+                    let kind = if data.get("join_token").is_some() {
+                        "private"
+                    } else {
+                        "public"
+                    };
+                    counter!("connector_joins_total", "kind" => kind).increment(1);
+
                     if data.get("join_token").is_some() {
                         let join_data: JoinPriv = serde_json::from_value(data.clone()).unwrap();
                         if let Err(err) = join_handler.handle_join_priv(join_data).await {
@@ -169,13 +228,71 @@ fn setup_listeners(
                             return;
                         }
                     }
-                    socket.on_disconnect(move |socket: SocketRef| {
+                    on_disconnect(&socket, join_handler.clone(), move |socket: SocketRef| {
                         info!("Socket.IO disconnected: {:?}", socket.id);
                         join_handler.remove_joiner().unwrap();
                     });
                     init_notify_on_match();
                 }
             });
+
+            let party_handler = handler.clone();
+            socket.on(
+                "party_create",
+                move |socket: SocketRef, Data::<Session>(data)| async move {
+                    match party_handler.handle_party_create(data).await {
+                        Ok(party) => socket.emit("party", &PartyInfo::from(party)).ok(),
+                        Err(err) => socket.emit("error", &err.to_string()).ok(),
+                    };
+                },
+            );
+
+            let party_handler = handler.clone();
+            socket.on(
+                "party_invite",
+                move |socket: SocketRef, Data::<PartyInvite>(data)| async move {
+                    match party_handler.handle_party_invite(data).await {
+                        Ok(party) => socket.emit("party", &PartyInfo::from(party)).ok(),
+                        Err(err) => socket.emit("error", &err.to_string()).ok(),
+                    };
+                },
+            );
+
+            let party_handler = handler.clone();
+            socket.on("party_accept", {
+                let init_notify_on_match = notify_on_match.clone();
+                move |socket: SocketRef, Data::<PartyAccept>(data)| async move {
+                    match party_handler.handle_party_accept(data).await {
+                        Ok(party) => {
+                            // The match of the party is found by the search of its leader
+                            init_notify_on_match();
+                            socket.emit("party", &PartyInfo::from(party)).ok();
+                        }
+                        Err(err) => {
+                            socket.emit("error", &err.to_string()).ok();
+                        }
+                    }
+                }
+            });
+
+            let party_handler = handler.clone();
+            socket.on("party_leave", move |socket: SocketRef| async move {
+                if let Err(err) = party_handler.leave_party() {
+                    error!("Error leaving party: {:?}", err);
+                    socket.emit("error", &err.to_string()).ok();
+                }
+            });
+
+            let party_handler = handler.clone();
+            socket.on(
+                "party_info",
+                move |socket: SocketRef, Data::<Session>(data)| async move {
+                    match party_handler.handle_party_info(data).await {
+                        Ok(party) => socket.emit("party", &PartyInfo::from(party)).ok(),
+                        Err(err) => socket.emit("error", &err.to_string()).ok(),
+                    };
+                },
+            );
         }
     };
 
@@ -185,45 +302,90 @@ fn setup_listeners(
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let default_hook = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |info| {
-        default_hook(info);
-        std::process::exit(1);
-    }));
+    let shutdown = Shutdown::install();
 
     dotenv::dotenv().ok();
-    let default_redis_url: String = std::env::var("REDIS_URL").unwrap();
+    let config: config::Config = ConfigLoader::new("connector-api").load_or_exit();
+
+    let telemetry_guard = telemetry::init("connector-api", &config.telemetry)?;
 
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::DEBUG)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+    let metrics = PrometheusBuilder::new().install_recorder()?;
+    let health = Health::new(shutdown.clone());
 
     info!("Starting server");
     let adapter =
-        RedisAdapter::connect(&default_redis_url).expect("Connection to redis database failed");
+        RedisAdapter::connect(&config.redis_url).expect("Connection to redis database failed");
     let publisher = RedisInfoPublisher::new(adapter.client.get_connection().unwrap());
     let adapter = Arc::new(adapter.with_publisher(publisher));
 
+    {
+        let adapter = adapter.clone();
+        health.add_readiness("redis", move || {
+            adapter
+                .ping(HEALTH_CHECK_TIMEOUT)
+                .map_err(|err| err.to_string())
+        });
+    }
+
     let ranking_client = Arc::new(gn_ranking_client_rs::RankingClient::new(
-        std::env::var("RANKING_API_KEY").unwrap(),
+        config.ranking_api_key.clone(),
     ));
 
     let (layer, io) = SocketIo::new_layer();
-    setup_listeners(&io, adapter, ranking_client);
+    setup_listeners(
+        &io,
+        adapter,
+        ranking_client,
+        config.ezauth_url.clone(),
+        shutdown.clone(),
+        &health,
+    );
 
     let cors = CorsLayer::new().allow_origin(Any);
 
-    let app = axum::Router::new().layer(cors).layer(layer);
+    let app = axum::Router::new()
+        .route(
+            "/metrics",
+            axum::routing::get(move || std::future::ready(metrics.render())),
+        )
+        .merge(health.router())
+        .layer(cors)
+        .layer(layer);
 
-    let listener = tokio::net::TcpListener::bind(HOST_ADDR.as_str())
+    let listener = tokio::net::TcpListener::bind(config.host_addr)
         .await
         .unwrap();
 
     info!("Server listening");
-    axum::serve(listener, app).await.unwrap();
+    let server = tokio::spawn(
+        axum::serve(listener, app)
+            .with_graceful_shutdown({
+                let shutdown = shutdown.clone();
+                async move {
+                    shutdown.triggered().await;
+                    info!("Shutting down, disconnecting all sockets");
+                    // The disconnect handlers remove the searchers and host requests of the sockets
+                    if let Some(Err(errors)) = io.of("/match").map(|ns| ns.disconnect()) {
+                        error!("Failed to disconnect sockets: {:?}", errors);
+                    }
+                }
+            })
+            .into_future(),
+    );
+
+    shutdown.triggered().await;
+    shutdown
+        .drain(async {
+            match server.await {
+                Ok(Err(err)) => error!("Server failed: {}", err),
+                Err(err) => error!("Server task failed: {}", err),
+                Ok(Ok(())) => {}
+            }
+        })
+        .await;
 
-    Ok(())
+    drop(telemetry_guard);
+    std::process::exit(shutdown.exit_code());
 }
 
 #[cfg(test)]
@@ -232,6 +394,7 @@ mod tests {
     use futures_util::future::FutureExt;
     use models::GameMode;
     use rust_socketio::asynchronous::{Client, ClientBuilder};
+    use tracing_subscriber::FmtSubscriber;
 
     #[tokio::test]
     async fn test_connect() {
@@ -28,6 +28,8 @@ where
     T: FnOnce(Match) -> () + Send + Sync + 'static, // TODO: Mark this as async
 {
     handlers: HashMap<String, T>,
+    /// Listens for newly created active matches
+    listener: Option<tokio::task::JoinHandle<()>>,
 }
 
 impl<T> MatchMaker<T>
@@ -39,22 +41,29 @@ where {
         let connection = connection;
         let instance = Arc::new(Mutex::new(Self {
             handlers: HashMap::new(),
+            listener: None,
         }));
 
         let matchmaker_copy = instance.clone();
 
         let connection_clone = connection.clone();
-        ActiveMatch::on_insert(&connection, move |uuid: String| {
+        let listener = ActiveMatch::on_insert(&connection, move |uuid: String| {
             debug!("New match created with uuid: {}", uuid);
             let new: ActiveMatchDB = connection_clone.get(&uuid).unwrap();
             matchmaker_copy.lock().unwrap().create(new).unwrap();
             debug!("All player handlers for match {:?} notified", uuid);
         })
         .unwrap();
+        instance.lock().unwrap().listener = Some(listener);
 
         instance
     }
 
+    /// The task listening for newly created active matches.
+    pub fn listener(&self) -> &tokio::task::JoinHandle<()> {
+        self.listener.as_ref().unwrap()
+    }
+
     pub fn notify_on_match(&mut self, searcher_uuid: &str, handler: T) {
         self.handlers.insert(searcher_uuid.to_string(), handler);
     }
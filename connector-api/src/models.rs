@@ -1,4 +1,4 @@
-use gn_matchmaking_state_types::ActiveMatchDB;
+use gn_matchmaking_state_types::{ActiveMatchDB, PartyDB};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
@@ -50,6 +50,8 @@ pub struct Match {
     pub players: Vec<String>,
     pub game: String,
     pub mode: String,
+    /// Index of the team the player plays for, if the mode is played in teams.
+    pub team: Option<u32>,
 }
 
 impl Match {
@@ -63,6 +65,7 @@ impl Match {
                 .keys().cloned().collect(),
             game: active_match.game.clone(),
             mode: active_match.mode.clone(),
+            team: active_match.teams.get(player_id).copied(),
         }
     }
 }
@@ -72,3 +75,39 @@ pub struct HostInfo {
     pub host_id: String,
     pub join_token: String,
 }
+
+#[derive(Deserialize, Debug)]
+pub struct Session {
+    pub session_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PartyInvite {
+    pub session_token: String,
+    pub player_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PartyAccept {
+    pub session_token: String,
+    pub party_id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PartyInfo {
+    pub party_id: String,
+    pub leader: String,
+    pub members: Vec<String>,
+    pub invited: Vec<String>,
+}
+
+impl From<PartyDB> for PartyInfo {
+    fn from(party: PartyDB) -> Self {
+        Self {
+            party_id: party.uuid,
+            leader: party.leader,
+            members: party.members,
+            invited: party.invited,
+        }
+    }
+}
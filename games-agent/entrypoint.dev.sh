@@ -12,6 +12,7 @@ gn-matchmaking-state-types = { path = "/usr/matchmaking-state-types" }
 gn-communicator           = { path = "/usr/communicator" }
 gn-ranking-client-rs      = { path = "/usr/gn-ranking-client-rs" }
 gn-redisadapter-derive    = { path = "/usr/matchmaking-state/redisadapter-derive" }
+gn-service                = { path = "/usr/service" }
 TOMLPATCH
   fi
   cargo build
@@ -0,0 +1,94 @@
+use gn_communicator::models::MatchError;
+use gn_service::config::ConfigError;
+use serde::Deserialize;
+
+/// What happens to the players of a match which was closed abruptly.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ClosePolicy {
+    /// Puts the players who are not at fault back at the front of the search queue.
+    #[serde(default)]
+    pub requeue: bool,
+    /// Keeps the offender from searching the game mode of the match for this many seconds.
+    #[serde(default)]
+    pub leaver_penalty_secs: Option<u64>,
+    /// Reports the match to ranking as lost by the offender and won by everybody else.
+    #[serde(default)]
+    pub forfeit: bool,
+}
+
+impl ClosePolicy {
+    fn needs_offender(&self) -> bool {
+        self.leaver_penalty_secs.is_some() || self.forfeit
+    }
+}
+
+/// The [`ClosePolicy`] of every [`MatchError`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClosePolicies {
+    pub all_players_disconnected: ClosePolicy,
+    pub player_did_not_join: ClosePolicy,
+    pub server_crash: ClosePolicy,
+    pub timeout: ClosePolicy,
+    pub cheating_detected: ClosePolicy,
+    pub admin_abort: ClosePolicy,
+}
+
+impl Default for ClosePolicies {
+    fn default() -> Self {
+        let requeue = ClosePolicy {
+            requeue: true,
+            ..Default::default()
+        };
+        Self {
+            all_players_disconnected: ClosePolicy::default(),
+            player_did_not_join: ClosePolicy {
+                leaver_penalty_secs: Some(5 * 60),
+                ..requeue.clone()
+            },
+            server_crash: requeue.clone(),
+            timeout: ClosePolicy::default(),
+            cheating_detected: ClosePolicy {
+                requeue: true,
+                leaver_penalty_secs: Some(24 * 60 * 60),
+                forfeit: true,
+            },
+            admin_abort: requeue,
+        }
+    }
+}
+
+impl ClosePolicies {
+    pub fn get(&self, reason: &MatchError) -> &ClosePolicy {
+        match reason {
+            MatchError::AllPlayersDisconnected => &self.all_players_disconnected,
+            MatchError::PlayerDidNotJoin(_) => &self.player_did_not_join,
+            MatchError::ServerCrash => &self.server_crash,
+            MatchError::Timeout => &self.timeout,
+            MatchError::CheatingDetected(_) => &self.cheating_detected,
+            MatchError::AdminAbort => &self.admin_abort,
+        }
+    }
+
+    /// Penalties and forfeits need somebody at fault, which only some reasons name.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let without_offender = [
+            ("all_players_disconnected", &self.all_players_disconnected),
+            ("server_crash", &self.server_crash),
+            ("timeout", &self.timeout),
+            ("admin_abort", &self.admin_abort),
+        ];
+        for (reason, policy) in without_offender {
+            if policy.needs_offender() {
+                return Err(ConfigError::Invalid {
+                    field: "close_policies",
+                    reason: format!(
+                        "{} names no offender, so it can't have a leaver penalty or forfeit",
+                        reason
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use gn_service::{
+    config::{require_file, require_url, ConfigError, Validate},
+    telemetry::TelemetryConfig,
+};
+use gn_communicator::transport::TransportKind;
+use serde::Deserialize;
+
+use crate::{close_policy::ClosePolicies, healthcheck::CLIENT_TIMEOUT};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub redis_url: String,
+    /// Broker the messages are sent over. Redis Streams use `redis_url`.
+    #[serde(default)]
+    pub transport: TransportKind,
+    /// Required if the transport is RabbitMQ.
+    #[serde(default)]
+    pub amqp_url: Option<String>,
+    pub ranking_api_key: String,
+    pub chat_register_url: String,
+    pub chat_delete_url: String,
+    pub chat_token: String,
+    /// Address of the HTTP server serving `/metrics`, `/healthz` and `/readyz`.
+    #[serde(default = "default_metrics_addr")]
+    pub metrics_addr: std::net::SocketAddr,
+    /// Overrides the queue names bundled with the communicator.
+    #[serde(default)]
+    pub queues_file: Option<String>,
+    /// Tells the replicas of the service apart in the outbox. Should survive restarts, defaults to the hostname.
+    #[serde(default = "default_instance_id")]
+    pub instance_id: String,
+    /// How long consumed message ids are remembered to skip redelivered messages.
+    #[serde(default = "default_message_dedup_ttl_secs")]
+    pub message_dedup_ttl_secs: u64,
+    /// Servers which have not sent a health check for this long are removed and their matches aborted.
+    #[serde(default = "default_server_dead_period_secs")]
+    pub server_dead_period_secs: u64,
+    /// What happens to the players of an abruptly closed match, per reason. Only settable in the config file.
+    #[serde(default)]
+    pub close_policies: ClosePolicies,
+    #[serde(flatten)]
+    pub telemetry: TelemetryConfig,
+}
+
+fn default_metrics_addr() -> std::net::SocketAddr {
+    ([0, 0, 0, 0], 9000).into()
+}
+
+fn default_instance_id() -> String {
+    std::env::var("HOSTNAME").unwrap_or_else(|_| "default".to_owned())
+}
+
+fn default_message_dedup_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_server_dead_period_secs() -> u64 {
+    5 * 60
+}
+
+impl Config {
+    /// Url of the broker of the configured transport.
+    pub fn broker_url(&self) -> &str {
+        match self.transport {
+            TransportKind::Rabbitmq => self.amqp_url.as_deref().unwrap_or_default(),
+            TransportKind::RedisStreams => &self.redis_url,
+        }
+    }
+}
+
+impl Validate for Config {
+    fn validate(&self) -> Result<(), ConfigError> {
+        require_url("redis_url", &self.redis_url, &["redis", "rediss"])?;
+        if self.transport == TransportKind::Rabbitmq {
+            let amqp_url = self.amqp_url.as_deref().ok_or(ConfigError::Invalid {
+                field: "amqp_url",
+                reason: "required if the transport is rabbitmq".to_owned(),
+            })?;
+            require_url("amqp_url", amqp_url, &["amqp", "amqps"])?;
+        }
+        require_url(
+            "chat_register_url",
+            &self.chat_register_url,
+            &["http", "https"],
+        )?;
+        require_url("chat_delete_url", &self.chat_delete_url, &["http", "https"])?;
+        if let Some(queues_file) = self.queues_file.as_ref() {
+            require_file("queues_file", queues_file)?;
+        }
+        if self.server_dead_period_secs <= CLIENT_TIMEOUT as u64 {
+            return Err(ConfigError::Invalid {
+                field: "server_dead_period_secs",
+                reason: format!(
+                    "must be longer than the health check timeout of {}s",
+                    CLIENT_TIMEOUT
+                ),
+            });
+        }
+        self.close_policies.validate()?;
+        self.telemetry.validate()
+    }
+}
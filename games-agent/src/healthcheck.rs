@@ -1,81 +1,190 @@
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+use gn_communicator::models::HealthReport;
 use gn_matchmaking_state::{
     adapters::{Removable, Updateable},
     prelude::{Gettable, RedisAdapterDefault},
 };
-use gn_matchmaking_state_types::{DBGameServer, GameServerUpdater};
-use tracing::{debug, error};
+use gn_matchmaking_state_types::{DBGameServer, GameServerUpdater, ServerState};
+use tracing::{debug, error, info};
 
+/// Tracks the health checks of the game servers. When a server last sent one is kept in the state store, so every
+/// games-agent replica sees the same servers and a restart doesn't give silent servers a new timeout.
 pub struct HealthCheck {
     pub connection: Arc<RedisAdapterDefault>,
-    pub active_clients: HashMap<String, DateTime<Utc>>,
+    /// How long a server may stay silent before it is removed.
+    pub dead_period: Duration,
 }
 
-const CLIENT_TIMEOUT: i64 = 30;
+/// Seconds without a health check after which a server is marked as dead.
+pub const CLIENT_TIMEOUT: i64 = 30;
+
+/// Key of the last health check of the server `uuid`. It is removed together with the server.
+fn heartbeat_key(uuid: &str) -> String {
+    format!("{}:heartbeat", uuid)
+}
+
+/// Key claimed by the replica which removes the server `uuid`.
+fn removal_key(uuid: &str) -> String {
+    format!("{}:removal", uuid)
+}
 
 impl HealthCheck {
-    pub fn new(connection: Arc<RedisAdapterDefault>) -> Self {
+    pub fn new(connection: Arc<RedisAdapterDefault>, dead_period: Duration) -> Self {
         Self {
             connection,
-            active_clients: HashMap::new(),
+            dead_period,
         }
     }
 
+    /// Stores `at` as the time of the last health check of the server `uuid`.
+    fn beat(&self, uuid: &str, at: DateTime<Utc>) -> Result<(), Box<dyn std::error::Error>> {
+        // Kept past the dead period, so the server is seen going silent for that long before the heartbeat expires
+        let ttl = (self.dead_period * 2).to_std()?;
+        self.connection
+            .set_expiring(&heartbeat_key(uuid), &at.to_rfc3339(), ttl)
+    }
+
+    /// Gives the server `uuid`, which leaves maintenance, the full timeout from now on. Servers in maintenance don't
+    /// send health checks, so their last one is older than the timeout already.
+    pub fn resume(&self, uuid: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.beat(uuid, Utc::now())
+    }
+
+    fn last_heartbeat(
+        &self,
+        uuid: &str,
+    ) -> Result<Option<DateTime<Utc>>, Box<dyn std::error::Error>> {
+        let Some(heartbeat) = self.connection.get_value(&heartbeat_key(uuid))? else {
+            return Ok(None);
+        };
+        Ok(Some(
+            DateTime::parse_from_rfc3339(&heartbeat)?.with_timezone(&Utc),
+        ))
+    }
+
+    /// Marks the servers of `report` as alive and stores how busy they are. A dead server which reports again is
+    /// active again.
+    ///
+    /// A game server which runs several game modes is saved once per mode, all with the same `server_priv`.
     #[inline]
-    pub fn refresh(&mut self, client_id: String) {
-        debug!("Client {} has refreshed", client_id);
-        if self
-            .active_clients
-            .insert(client_id.clone(), Utc::now())
-            .is_none()
-        {
-            let mut update = GameServerUpdater::default();
-            update.healthy = Some(true);
-
-            let server = self
-                .connection
-                .all()
-                .unwrap()
-                .find(|v: &DBGameServer| v.server_priv == client_id);
-            
-            if server.is_none() {
-                error!("Tried to refresh a non-existant server");
+    pub fn refresh(&self, report: HealthReport) {
+        debug!("Client {} has refreshed", report.client_id);
+
+        let servers: Vec<DBGameServer> = match self.connection.all() {
+            Ok(servers) => servers
+                .filter(|v: &DBGameServer| v.server_priv == report.client_id)
+                .collect(),
+            Err(err) => {
+                error!("Failed to read servers: {}", err);
                 return;
             }
+        };
+        if servers.is_empty() {
+            error!("Tried to refresh a non-existant server");
+            return;
+        }
+
+        for server in servers {
+            if let Err(err) = self.beat(&server.uuid, Utc::now()) {
+                error!(
+                    "Failed to store heartbeat of server {}: {}",
+                    server.uuid, err
+                );
+            }
 
-            let client_db_id = server.unwrap().server_priv;
-            let _ = self.connection.update(&client_db_id, update);
+            let update = GameServerUpdater {
+                healthy: (!server.healthy).then_some(true),
+                state: (server.state == ServerState::Dead).then_some(ServerState::Active),
+                active_matches: Some(report.active_matches),
+                capacity: Some(report.capacity),
+                cpu_load: Some(report.cpu_load),
+                latency_ms: Some(report.latency_ms),
+                version: Some(report.version.clone()),
+                ..Default::default()
+            };
+            if let Err(err) = self.connection.update(&server.uuid, update) {
+                error!("Failed to store health of server {}: {}", server.uuid, err);
+            }
         }
     }
 
-    #[inline]
-    pub fn check(&mut self) -> bool {
+    /// Marks servers which timed out as unhealthy, and as dead if they were active, and returns the servers which have
+    /// been silent for longer than the dead period. Those are left in place until they are passed to
+    /// [`HealthCheck::remove`]. Only one replica gets a vanished server returned; if it does not remove the server,
+    /// another replica tries again after the timeout.
+    ///
+    /// Servers which never sent a health check are given the full timeout from now on.
+    /// Servers in maintenance are expected to go quiet and are neither marked as dead nor removed, they get a new
+    /// heartbeat with [`HealthCheck::resume`] when they leave maintenance.
+    pub fn check(&self) -> Result<Vec<DBGameServer>, Box<dyn std::error::Error>> {
         let now = Utc::now();
+        let servers: Vec<DBGameServer> = self.connection.all()?.collect();
+
+        let mut vanished = Vec::new();
+        for server in servers {
+            if server.state == ServerState::Maintenance {
+                continue;
+            }
+            match self.check_server(&server, now) {
+                Ok(true) => vanished.push(server),
+                Ok(false) => {}
+                Err(err) => error!(
+                    "Failed to check the health of server {}: {}",
+                    server.uuid, err
+                ),
+            }
+        }
+        Ok(vanished)
+    }
+
+    /// Checks a single server for [`HealthCheck::check`].
+    ///
+    /// # Returns
+    ///
+    /// Whether the server vanished and this replica claimed its removal.
+    fn check_server(
+        &self,
+        server: &DBGameServer,
+        now: DateTime<Utc>,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let last_heartbeat = match self.last_heartbeat(&server.uuid)? {
+            Some(last_heartbeat) => last_heartbeat,
+            None => {
+                self.beat(&server.uuid, now)?;
+                now
+            }
+        };
+        let silent = now.signed_duration_since(last_heartbeat);
+
+        if silent >= self.dead_period {
+            let timeout = Duration::seconds(CLIENT_TIMEOUT).to_std()?;
+            return self.connection.claim(&removal_key(&server.uuid), timeout);
+        }
+        if silent.num_seconds() >= CLIENT_TIMEOUT
+            && (server.healthy || server.state == ServerState::Active)
+        {
+            debug!("Server {} has timed out", server.uuid);
+            // Only active servers become dead, a draining server stays draining when it reports again
+            let update = GameServerUpdater {
+                healthy: Some(false),
+                state: (server.state == ServerState::Active).then_some(ServerState::Dead),
+                ..Default::default()
+            };
+            self.connection.update(&server.uuid, update)?;
+        }
+        Ok(false)
+    }
 
-        self.active_clients
-            .clone()
-            .into_iter()
-            .filter(|(_, v)| now.signed_duration_since(*v).num_seconds() >= CLIENT_TIMEOUT)
-            .for_each(|(k, _)| {
-                debug!("Client {} has timed out", k);
-
-                let mut update = GameServerUpdater::default();
-                update.healthy = Some(false);
-
-                let client_db_id = self
-                    .connection
-                    .all()
-                    .unwrap()
-                    .find(|v: &DBGameServer| v.server_priv == k)
-                    .unwrap()
-                    .server_priv;
-                self.connection.update(&client_db_id, update).unwrap();
-
-                self.active_clients.remove(&k);
-            });
-
-        self.active_clients.len() > 0
+    /// Removes a server returned by [`HealthCheck::check`], together with its heartbeat.
+    pub fn remove(&self, server: &DBGameServer) -> Result<(), Box<dyn std::error::Error>> {
+        self.connection.remove(&server.uuid)?;
+        info!(
+            "Server {} removed after {}s without a health check",
+            server.uuid,
+            self.dead_period.num_seconds()
+        );
+        Ok(())
     }
 }
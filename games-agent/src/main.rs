@@ -1,64 +1,94 @@
 use gn_communicator::models::AIPlayerRegister;
-use gn_communicator::Communicator;
+use gn_communicator::{Communicator, HandlerError, HandlerResult};
 use gn_ranking_client_rs::RankingClient;
 use lazy_static::lazy_static;
-use models::{AIPlayerMaker, GameServerMaker, MatchResultMaker};
+use models::{
+    AIPlayerMaker, GameModeMaker, GameServerMaker, MatchResultMaker, ServerStateMaker,
+    TEAM_PERFORMANCE,
+};
 use serde_json::{Map, Value};
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use async_once::AsyncOnce;
-use gn_communicator::rabbitmq::RabbitMQCommunicator;
+use gn_communicator::transport::Transport;
 use gn_matchmaking_state::prelude::*;
+use axum::routing::get;
+use gn_service::{config::ConfigLoader, health::Health, shutdown::Shutdown, telemetry};
 use gn_matchmaking_state_types::{
-    AIPlayer, AIPlayerDB, ActiveMatch, ActiveMatchDB, DBGameServer, GameServer,
+    AIPlayer, AIPlayerDB, ActiveMatch, ActiveMatchDB, ActiveMatchUpdater, DBGameServer,
+    DBSearcher, Game, GameDB, GameMode, GameModeDB, GameServer, GameServerUpdater, LeaverPenalty,
+    LeaverPenaltyDB, Searcher, ServerState,
 };
 use healthcheck::HealthCheck;
-use tracing::{debug, error, warn, Level};
-use tracing_subscriber::FmtSubscriber;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use tracing::{debug, error, info, instrument, warn};
 
+mod close_policy;
+mod config;
 mod healthcheck;
 mod models;
 
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+const HEALTHCHECK_TICKER_MAX_AGE: Duration = Duration::from_secs(10);
+/// How often messages whose publish failed are published again from the outbox.
+const OUTBOX_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Elo of requeued players whose stars could not be read, the same the connector-api uses.
+const DEFAULT_ELO: u32 = 1250;
+
 lazy_static! {
-    static ref ranking_client: RankingClient =
-        RankingClient::new(std::env::var("RANKING_API_KEY").unwrap().to_owned());
-    static ref communicator: AsyncOnce<RabbitMQCommunicator> = AsyncOnce::new(
-        RabbitMQCommunicator::connect(option_env!("AMQP_URL").unwrap())
-    );
+    static ref CONFIG: config::Config = ConfigLoader::new("games-agent").load_or_exit();
+    static ref ranking_client: RankingClient = RankingClient::new(CONFIG.ranking_api_key.clone());
+    static ref communicator: AsyncOnce<Transport> = AsyncOnce::new(async {
+        let mut transport = Transport::connect(CONFIG.transport, CONFIG.broker_url()).await;
+        transport.set_producer("games-agent");
+        let message_store = Arc::new(
+            RedisAdapterDefault::connect(&CONFIG.redis_url).expect("Could not connect to redis"),
+        );
+        transport.set_deduplicator(
+            message_store.clone(),
+            Duration::from_secs(CONFIG.message_dedup_ttl_secs),
+        );
+        transport.set_outbox(message_store, &CONFIG.instance_id);
+        if let Some(queues_file) = CONFIG.queues_file.as_ref() {
+            transport.load_queues(queues_file);
+        }
+        transport.flush_outbox().await;
+        transport
+    });
 }
 
-async fn create_game_chat(players: Vec<String>) -> String {
+async fn create_game_chat(players: Vec<String>) -> Result<String, reqwest::Error> {
     let mut body = HashMap::new();
 
     body.insert("user_ids", players);
 
     reqwest::Client::new()
-        .post(std::env::var("CHAT_REGISTER_URL").unwrap())
+        .post(&CONFIG.chat_register_url)
         .json(&body)
-        .header("x-token", std::env::var("CHAT_TOKEN").unwrap())
+        .header("x-token", &CONFIG.chat_token)
         .send()
-        .await
-        .unwrap()
+        .await?
+        .error_for_status()?
         .text()
         .await
-        .unwrap()
 }
 
-async fn delete_match_chat(chat_id: &str) {
+async fn delete_match_chat(chat_id: &str) -> Result<(), reqwest::Error> {
     reqwest::Client::new()
-        .delete(std::env::var("CHAT_DELETE_URL").unwrap() + "?chat_id=" + chat_id)
+        .delete(format!("{}?chat_id={}", CONFIG.chat_delete_url, chat_id))
         .send()
-        .await
-        .unwrap();
+        .await?;
+    Ok(())
 }
 
+#[instrument(skip_all, fields(match_id = %created_match.read))]
 async fn on_match_created(
     created_match: gn_communicator::models::CreatedMatch,
     conn: Arc<RedisAdapterDefault>,
-) {
+) -> HandlerResult {
     debug!("Match created: {:?}", created_match);
 
     let chat_id = create_game_chat(
@@ -69,7 +99,8 @@ async fn on_match_created(
             .map(|x| x.clone())
             .collect(),
     )
-    .await;
+    .await
+    .map_err(HandlerError::retry)?;
 
     let insert = ActiveMatch {
         region: created_match.region,
@@ -80,21 +111,38 @@ async fn on_match_created(
         server_priv: created_match.url_priv.clone(),
         read: created_match.read.clone(),
         player_write: created_match.player_write.clone(),
-        chat_id
+        chat_id,
+        started: false,
+        connected_players: Vec::new(),
+        disconnected_players: Vec::new(),
+        teams: created_match
+            .teams
+            .iter()
+            .enumerate()
+            .flat_map(|(team, players)| {
+                players
+                    .iter()
+                    .map(move |player| (player.clone(), team as u32))
+            })
+            .collect(),
     };
 
     debug!("Inserting match {:?} into State", created_match.read);
-    conn.insert(insert).unwrap();
+    conn.insert(insert).map_err(HandlerError::retry)?;
     debug!("Match {:?} inserted", created_match.read.clone());
 
     for player in created_match.ai_players {
+        let Some(write) = created_match.player_write.get(&player) else {
+            warn!("AI player {} has no write key, no task created", player);
+            continue;
+        };
         let task = gn_communicator::models::Task {
             ai_id: player.clone(),
             game: created_match.game.clone(),
             mode: created_match.mode.clone(),
             address: created_match.url_priv.clone(),
             read: created_match.read.clone(),
-            write: created_match.player_write.get(&player).unwrap().clone(),
+            write: write.clone(),
             players: created_match
                 .player_write
                 .keys()
@@ -102,88 +150,339 @@ async fn on_match_created(
                 .collect(),
         };
 
-        communicator.get().await.create_ai_task(&task).await;
+        communicator
+            .get()
+            .await
+            .create_ai_task(&task)
+            .await
+            .map_err(HandlerError::retry)?;
 
         debug!(
             "AI task created for match {:?}: {:?}",
             created_match.read, task.ai_id
         );
     }
+    Ok(())
 }
 
+#[instrument(skip_all, fields(match_id = %reason.match_id))]
 async fn on_match_abrupt_close(
     reason: gn_communicator::models::MatchAbrubtClose,
     conn: Arc<RedisAdapterDefault>,
-) {
+) -> HandlerResult {
     debug!("Match closed abruptly: {:?}", reason);
 
     let match_ = conn
         .all()
-        .unwrap()
+        .map_err(HandlerError::retry)?
         .find(|x: &ActiveMatchDB| x.read.clone() == reason.match_id);
 
-    if let Some(match_) = match_ {
-        conn.remove(&match_.uuid).unwrap();
-        debug!("Match {:?} removed", match_.uuid);
+    let Some(match_) = match_ else {
+        return Ok(());
+    };
+
+    // Requeueing and penalizing skip what is already done, so they go first in case the forfeit fails and the
+    // message is retried.
+    let policy = CONFIG.close_policies.get(&reason.reason);
+    let offender = reason.reason.offender();
+    if let (Some(offender), Some(secs)) = (offender, policy.leaver_penalty_secs) {
+        penalize_leaver(offender, &match_, Duration::from_secs(secs), &conn)
+            .map_err(HandlerError::retry)?;
+    }
+    if policy.requeue {
+        requeue_players(&match_, offender, &conn)
+            .await
+            .map_err(HandlerError::retry)?;
+    }
+    #[cfg(not(disable_ranking))]
+    if let (Some(offender), true) = (offender, policy.forfeit) {
+        let forfeit = forfeit_result(&reason.match_id, offender, &match_);
+        report_match_result(forfeit, match_.clone())
+            .await
+            .map_err(HandlerError::retry)?;
+        debug!("Forfeit of {:?} reported to ranking", offender);
+    }
+
+    conn.remove(&match_.uuid).map_err(HandlerError::retry)?;
+    debug!("Match {:?} removed", match_.uuid);
+    Ok(())
+}
+
+/// Keeps `player_id` from searching the game mode of `active_match` for `duration`. A longer running penalty is
+/// kept as it is.
+fn penalize_leaver(
+    player_id: &str,
+    active_match: &ActiveMatchDB,
+    duration: Duration,
+    conn: &RedisAdapterDefault,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let until = SystemTime::now() + duration;
+    let existing = conn.all()?.find(|x: &LeaverPenaltyDB| {
+        x.player_id == player_id && x.game == active_match.game && x.mode == active_match.mode
+    });
+    if let Some(existing) = existing {
+        if existing.until >= until {
+            return Ok(());
+        }
+        conn.remove(&existing.uuid)?;
     }
+
+    conn.insert(LeaverPenalty {
+        player_id: player_id.to_owned(),
+        game: active_match.game.clone(),
+        mode: active_match.mode.clone(),
+        until,
+    })?;
+    info!("Player {:?} penalized for {:?}", player_id, duration);
+    Ok(())
 }
 
+/// Puts the human players of `active_match`, except the `offender`, back into the search queue. They are placed
+/// in front of everybody who is waiting for the same game, mode and region.
+async fn requeue_players(
+    active_match: &ActiveMatchDB,
+    offender: Option<&str>,
+    conn: &RedisAdapterDefault,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ai_players: Vec<String> = conn.all()?.map(|x: AIPlayerDB| x.uuid).collect();
+    let searchers: Vec<DBSearcher> = conn
+        .all()?
+        .filter(|x: &DBSearcher| {
+            x.game == active_match.game
+                && x.mode == active_match.mode
+                && x.region == active_match.region
+        })
+        .collect();
+    let wait_start = searchers
+        .iter()
+        .map(|x| x.wait_start)
+        .min()
+        .unwrap_or_else(SystemTime::now)
+        - Duration::from_secs(1);
+
+    let players = active_match.player_write.keys().filter(|x| {
+        Some(x.as_str()) != offender
+            && !ai_players.contains(x)
+            && !searchers.iter().any(|searcher| searcher.player_id == **x)
+    });
+    let server = conn
+        .all()?
+        .find(|x: &DBGameServer| x.server_priv == active_match.server_priv);
+    let (min_players, max_players) = match server {
+        Some(server) => (server.min_players, server.max_players),
+        None => {
+            let players = active_match.player_write.len() as u32;
+            (players, players)
+        }
+    };
+
+    for player_id in players {
+        let searcher = Searcher {
+            player_id: player_id.clone(),
+            elo: player_elo(player_id, &active_match.game, &active_match.mode).await,
+            mode: active_match.mode.clone(),
+            ai: None,
+            game: active_match.game.clone(),
+            region: active_match.region.clone(),
+            min_players,
+            max_players,
+            wait_start,
+            party_members: Vec::new(),
+            party_size: 1,
+        };
+        conn.insert(searcher)?;
+        debug!("Player {:?} requeued", player_id);
+    }
+    Ok(())
+}
+
+async fn player_elo(player_id: &str, game: &str, mode: &str) -> u32 {
+    #[cfg(disable_ranking)]
+    return DEFAULT_ELO;
+    #[cfg(not(disable_ranking))]
+    return match ranking_client.player_stars(player_id, game, mode).await {
+        Ok(stars) => stars as u32,
+        Err(err) => {
+            warn!("Failed to get stars of player {:?}: {:?}", player_id, err);
+            DEFAULT_ELO
+        }
+    };
+}
+
+/// A result in which `offender` lost `active_match` and every other player won it.
+fn forfeit_result(
+    match_id: &str,
+    offender: &str,
+    active_match: &ActiveMatchDB,
+) -> gn_communicator::models::MatchResult {
+    let (losers, winners) = active_match
+        .player_write
+        .keys()
+        .map(|player| (player.clone(), if player == offender { 0 } else { 1 }))
+        .partition(|(player, _)| player == offender);
+
+    gn_communicator::models::MatchResult {
+        match_id: match_id.to_owned(),
+        winners,
+        losers,
+        ranking: gn_communicator::models::Ranking {
+            performances: HashMap::new(),
+        },
+        event_log: Vec::new(),
+    }
+}
+
+/// Finds the active match a lifecycle event is about. Events can overtake the `CreatedMatch` of their match, so
+/// they are retried until the match is known.
+fn find_active_match(conn: &RedisAdapterDefault, match_id: &str) -> HandlerResult<ActiveMatchDB> {
+    conn.all()
+        .map_err(HandlerError::retry)?
+        .find(|x: &ActiveMatchDB| x.read == match_id)
+        .ok_or_else(|| HandlerError::retry(format!("match {} is not active", match_id)))
+}
+
+#[instrument(skip_all, fields(match_id = %started.match_id))]
+async fn on_match_started(
+    started: gn_communicator::models::MatchStarted,
+    conn: Arc<RedisAdapterDefault>,
+) -> HandlerResult {
+    debug!("Match started: {:?}", started);
+
+    let match_ = find_active_match(&conn, &started.match_id)?;
+    let update = ActiveMatchUpdater {
+        started: Some(true),
+        connected_players: Some(match_.player_write.keys().cloned().collect()),
+        disconnected_players: Some(Vec::new()),
+        ..Default::default()
+    };
+    conn.update(&match_.uuid, update).map_err(HandlerError::retry)?;
+    debug!("Match {:?} marked as started", match_.uuid);
+    Ok(())
+}
+
+/// Moves `player_id` to the connected or the disconnected players of its match.
+async fn on_player_connection(
+    match_id: String,
+    player_id: String,
+    connected: bool,
+    conn: Arc<RedisAdapterDefault>,
+) -> HandlerResult {
+    let match_ = find_active_match(&conn, &match_id)?;
+    if !match_.player_write.contains_key(&player_id) {
+        return Err(HandlerError::reject(format!(
+            "player {} is not part of match {}",
+            player_id, match_id
+        )));
+    }
+
+    let mut connected_players = match_.connected_players;
+    let mut disconnected_players = match_.disconnected_players;
+    connected_players.retain(|x| *x != player_id);
+    disconnected_players.retain(|x| *x != player_id);
+    if connected {
+        connected_players.push(player_id.clone());
+    } else {
+        disconnected_players.push(player_id.clone());
+    }
+
+    let update = ActiveMatchUpdater {
+        connected_players: Some(connected_players),
+        disconnected_players: Some(disconnected_players),
+        ..Default::default()
+    };
+    conn.update(&match_.uuid, update).map_err(HandlerError::retry)?;
+    debug!(
+        "Player {:?} of match {:?} is {}",
+        player_id,
+        match_.uuid,
+        if connected { "connected" } else { "disconnected" }
+    );
+    Ok(())
+}
+
+#[instrument(skip_all, fields(match_id = %cancelled.match_id))]
+async fn on_match_cancelled(
+    cancelled: gn_communicator::models::MatchCancelled,
+    conn: Arc<RedisAdapterDefault>,
+) -> HandlerResult {
+    debug!("Match cancelled: {:?}", cancelled);
+
+    let match_ = conn
+        .all()
+        .map_err(HandlerError::retry)?
+        .find(|x: &ActiveMatchDB| x.read == cancelled.match_id);
+
+    let Some(match_) = match_ else {
+        debug!("Match {:?} is not active, cancellation ignored", cancelled.match_id);
+        return Ok(());
+    };
+
+    conn.remove(&match_.uuid).map_err(HandlerError::retry)?;
+    debug!("Match {:?} removed", match_.uuid);
+
+    if let Err(err) = delete_match_chat(&match_.chat_id).await {
+        error!("Failed to delete chat of match {:?}: {}", match_.uuid, err);
+    }
+    Ok(())
+}
+
+#[instrument(skip_all, fields(match_id = %result.match_id))]
 async fn on_match_result(
     result: gn_communicator::models::MatchResult,
     conn: Arc<RedisAdapterDefault>,
-) {
+) -> HandlerResult {
     debug!("Match result: {:?}", result);
 
     let match_ = conn
         .all()
-        .unwrap()
+        .map_err(HandlerError::retry)?
         .find(|x: &ActiveMatchDB| x.read.clone() == result.match_id);
 
-    if let Some(match_) = match_ {
-        conn.remove(&match_.uuid).unwrap();
-
-        delete_match_chat(&match_.chat_id).await;
-
-        #[cfg(not(disable_ranking))]
-        {
-            debug!("Match {:?} removed", match_.uuid);
-            let ranking_match = match report_match_result(result.clone(), match_.clone()).await {
-                Ok(m) => m,
-                Err(err) => {
-                    error!("Error reporting match result: {:?}", err);
-                    return;
-                }
-            };
-
-            let mut replay_data_map = Map::new();
-            replay_data_map.insert("replay".to_string(), Value::Array(result.event_log));
+    let Some(match_) = match_ else {
+        debug!("Match {:?} is not active, result ignored", result.match_id);
+        return Ok(());
+    };
 
-            let request = gn_ranking_client_rs::models::create::ReplayData {
-                match_id: ranking_match._id.clone(),
-                replay_data: Value::Object(replay_data_map),
-            };
+    // The match is only removed once the result is reported, so a failed report can be retried.
+    #[cfg(not(disable_ranking))]
+    {
+        let ranking_match = report_match_result(result.clone(), match_.clone())
+            .await
+            .map_err(HandlerError::retry)?;
 
+        let mut replay_data_map = Map::new();
+        replay_data_map.insert("replay".to_string(), Value::Array(result.event_log));
 
-            let result = ranking_client.match_replay_create(request).await;
+        let request = gn_ranking_client_rs::models::create::ReplayData {
+            match_id: ranking_match._id.clone(),
+            replay_data: Value::Object(replay_data_map),
+        };
 
+        // The match itself is already reported, retrying would report it twice
+        if let Err(err) = ranking_client.match_replay_create(request).await {
+            error!("Error reporting replay data: {:?}", err);
+        } else {
             debug!("Inserted replay data at ranking for match {}", ranking_match._id);
+        }
 
-            if let Err(err) = result {
-                error!("Error reporting replay data: {:?}", err);
-                return;
-            }
+        debug!(
+            "Match {:?} successfully reported to ranking system",
+            match_.uuid
+        );
+    }
 
-            debug!(
-                "Match {:?} successfully reported to ranking system",
-                match_.uuid
-            );
-        }
+    #[cfg(disable_ranking)]
+    {
+        debug!("Match {:?} not reported to ranking-system (Ranking disabled with feature 'disable_ranking')", match_.uuid);
+    }
 
-        #[cfg(disable_ranking)]
-        {
-            debug!("Match {:?} not reported to ranking-system (Ranking disabled with feature 'disable_ranking')", match_.uuid);
-        }
+    conn.remove(&match_.uuid).map_err(HandlerError::retry)?;
+    debug!("Match {:?} removed", match_.uuid);
+
+    if let Err(err) = delete_match_chat(&match_.chat_id).await {
+        error!("Failed to delete chat of match {:?}: {}", match_.uuid, err);
     }
+    Ok(())
 }
 
 async fn report_match_result(
@@ -197,34 +496,39 @@ async fn report_match_result(
 }
 
 async fn init_game_ranking(
-    created_game: gn_communicator::models::GameServerCreate,
+    game: &str,
+    mode: &str,
+    ranking_conf: gn_communicator::models::RankingConf,
 ) -> Result<gn_ranking_client_rs::models::read::Game, Box<dyn std::error::Error>> {
-    debug!(
-        "Initializing game at ranking server: {:?}",
-        created_game.game
-    );
+    debug!("Initializing game at ranking server: {:?}", game);
     let game = gn_ranking_client_rs::models::create::Game {
-        game_name: created_game.game.clone(),
-        game_mode: created_game.mode.clone(),
-        max_stars: created_game.ranking_conf.max_stars,
-        description: created_game.ranking_conf.description.clone(),
-        performances: created_game
-            .ranking_conf
+        game_name: game.to_owned(),
+        game_mode: mode.to_owned(),
+        max_stars: ranking_conf.max_stars,
+        description: ranking_conf.description,
+        performances: ranking_conf
             .performances
             .into_iter()
             .map(|x| gn_ranking_client_rs::models::create::Performance {
                 name: x.name,
                 weight: x.weight,
             })
-            .chain(vec![gn_ranking_client_rs::models::create::Performance {
-                name: "point".to_owned(),
-                weight: 1,
-            }])
+            .chain(vec![
+                gn_ranking_client_rs::models::create::Performance {
+                    name: "point".to_owned(),
+                    weight: 1,
+                },
+                gn_ranking_client_rs::models::create::Performance {
+                    name: TEAM_PERFORMANCE.to_owned(),
+                    weight: 0,
+                },
+            ])
             .collect(),
     };
     Ok(ranking_client.game_init(game).await?)
 }
 
+#[instrument(skip_all, fields(game = %created_game.game, mode = %created_game.mode))]
 async fn save_game(
     created_game: GameServer,
     conn: Arc<RedisAdapterDefault>,
@@ -245,6 +549,171 @@ async fn save_game(
     Ok(uuid)
 }
 
+/// Aborts the matches which were running on `server` when it vanished. They are reported as closed abruptly with
+/// `ServerCrash`, which tells the players and applies the close policy of the reason.
+async fn abort_server_matches(
+    server: &DBGameServer,
+    conn: &RedisAdapterDefault,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let matches: Vec<ActiveMatchDB> = conn
+        .all()?
+        .filter(|x: &ActiveMatchDB| {
+            x.server_priv == server.server_priv && x.game == server.game && x.mode == server.mode
+        })
+        .collect();
+
+    for active_match in matches {
+        let close = gn_communicator::models::MatchAbrubtClose {
+            match_id: active_match.read.clone(),
+            reason: gn_communicator::models::MatchError::ServerCrash,
+        };
+        communicator
+            .get()
+            .await
+            .report_match_abrupt_close(&close)
+            .await?;
+        warn!(
+            "Match {:?} aborted, its server {} vanished",
+            active_match.uuid, server.uuid
+        );
+    }
+    Ok(())
+}
+
+/// Moves a game server to another state. Draining servers get no new matches, but their active matches are kept
+/// until they finish.
+#[instrument(skip_all, fields(server_id = %change.server_id))]
+async fn on_server_state_change(
+    change: gn_communicator::models::ServerStateChange,
+    conn: Arc<RedisAdapterDefault>,
+    healthcheck: Arc<HealthCheck>,
+) -> HandlerResult {
+    let server: DBGameServer = conn
+        .get(&change.server_id)
+        .map_err(|_| HandlerError::reject(format!("unknown server {}", change.server_id)))?;
+
+    let state = ServerStateMaker::from(change.state).into();
+    // Before the state changes, so the health check never sees the server out of maintenance with its old heartbeat
+    if server.state == ServerState::Maintenance && state != ServerState::Maintenance {
+        healthcheck
+            .resume(&server.uuid)
+            .map_err(HandlerError::retry)?;
+    }
+    let update = GameServerUpdater {
+        state: Some(state),
+        ..Default::default()
+    };
+    conn.update(&server.uuid, update).map_err(HandlerError::retry)?;
+    info!("Server {:?} is now {:?}", server.uuid, state);
+    Ok(())
+}
+
+/// Fails with the reason if `mode` can't be matched or ranked as it is described.
+fn check_game_mode(mode: &gn_communicator::models::GameModeRegister) -> Result<(), String> {
+    if mode.min_players > mode.max_players {
+        return Err(format!(
+            "mode {} needs more players than it allows",
+            mode.mode
+        ));
+    }
+    // Matches are filled up to the max players, which all have to find a place in a team
+    if !mode.team_sizes.is_empty() && mode.team_sizes.iter().sum::<u32>() != mode.max_players {
+        return Err(format!(
+            "teams of mode {} don't add up to its max players",
+            mode.mode
+        ));
+    }
+    if mode.ranked && mode.ranking_conf.is_none() {
+        return Err(format!("ranked mode {} has no ranking conf", mode.mode));
+    }
+    Ok(())
+}
+
+/// Saves `game` to the catalog. The modes registered for the game before are replaced by the modes of `game`,
+/// after the new ones were saved, so the game stays searchable meanwhile.
+#[instrument(skip_all, fields(game = %game.game))]
+async fn save_game_catalog(
+    game: gn_communicator::models::GameRegister,
+    conn: Arc<RedisAdapterDefault>,
+) -> HandlerResult<String> {
+    for mode in &game.modes {
+        check_game_mode(mode).map_err(HandlerError::reject)?;
+    }
+
+    let mut replaced: Vec<String> = conn
+        .all()
+        .map_err(HandlerError::retry)?
+        .filter(|x: &GameDB| x.name == game.game)
+        .map(|x| x.uuid)
+        .collect();
+    replaced.extend(
+        conn.all()
+            .map_err(HandlerError::retry)?
+            .filter(|x: &GameModeDB| x.game == game.game)
+            .map(|x| x.uuid),
+    );
+
+    let uuid = conn
+        .insert(Game {
+            name: game.game.clone(),
+            display_name: game.display_name,
+        })
+        .map_err(HandlerError::retry)?;
+    for mode in game.modes {
+        if let (true, Some(ranking_conf)) = (mode.ranked, mode.ranking_conf.clone()) {
+            if let Err(err) = init_game_ranking(&game.game, &mode.mode, ranking_conf).await {
+                error!("Error initializing game at ranking server: {:?}", err);
+            }
+        }
+        let mode: GameMode = GameModeMaker::from((game.game.clone(), mode)).into();
+        conn.insert(mode).map_err(HandlerError::retry)?;
+    }
+
+    for uuid in replaced {
+        conn.remove(&uuid).map_err(HandlerError::retry)?;
+    }
+    info!("Game {:?} saved to the catalog", game.game);
+    Ok(uuid)
+}
+
+/// Adds the game mode of `created_game` to the catalog unless it is registered already. This keeps the modes of
+/// game servers which don't register their game searchable, as ranked modes which allow AI players.
+fn ensure_game_mode(
+    created_game: &gn_communicator::models::GameServerCreate,
+    conn: &RedisAdapterDefault,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !conn.all()?.any(|x: GameDB| x.name == created_game.game) {
+        conn.insert(Game {
+            name: created_game.game.clone(),
+            display_name: created_game.game.clone(),
+        })?;
+    }
+    if conn
+        .all()?
+        .any(|x: GameModeDB| x.game == created_game.game && x.mode == created_game.mode)
+    {
+        return Ok(());
+    }
+
+    let mode = gn_communicator::models::GameModeRegister {
+        mode: created_game.mode.clone(),
+        display_name: created_game.mode.clone(),
+        min_players: created_game.min_players,
+        max_players: created_game.max_players,
+        team_sizes: Vec::new(),
+        ranked: true,
+        ai_allowed: true,
+        ranking_conf: Some(created_game.ranking_conf.clone()),
+    };
+    let mode: GameMode = GameModeMaker::from((created_game.game.clone(), mode)).into();
+    conn.insert(mode)?;
+    debug!(
+        "Game mode {:?} of {:?} added to the catalog",
+        created_game.mode, created_game.game
+    );
+    Ok(())
+}
+
 async fn save_ai_player(
     ai_player: AIPlayerRegister,
     conn: Arc<RedisAdapterDefault>,
@@ -295,14 +764,82 @@ async fn listen_for_match_created(conn: Arc<RedisAdapterDefault>) {
         .on_match_created(
             move |created_match: gn_communicator::models::CreatedMatch| {
                 let conn = conn.clone();
-                async move {
-                    on_match_created(created_match, conn.clone()).await;
-                }
+                async move { on_match_created(created_match, conn.clone()).await }
             },
         )
         .await;
 }
 
+async fn listen_for_match_lifecycle(conn: Arc<RedisAdapterDefault>) {
+    let communicator_ = communicator.get().await;
+    {
+        let conn = conn.clone();
+        communicator_
+            .on_match_started(move |started: gn_communicator::models::MatchStarted| {
+                on_match_started(started, conn.clone())
+            })
+            .await;
+    }
+    {
+        let conn = conn.clone();
+        communicator_
+            .on_player_joined(move |joined: gn_communicator::models::PlayerJoined| {
+                on_player_connection(joined.match_id, joined.player_id, true, conn.clone())
+            })
+            .await;
+    }
+    {
+        let conn = conn.clone();
+        communicator_
+            .on_player_disconnected(
+                move |disconnected: gn_communicator::models::PlayerDisconnected| {
+                    let (match_id, player_id) = (disconnected.match_id, disconnected.player_id);
+                    on_player_connection(match_id, player_id, false, conn.clone())
+                },
+            )
+            .await;
+    }
+    {
+        let conn = conn.clone();
+        communicator_
+            .on_player_reconnected(
+                move |reconnected: gn_communicator::models::PlayerReconnected| {
+                    let (match_id, player_id) = (reconnected.match_id, reconnected.player_id);
+                    on_player_connection(match_id, player_id, true, conn.clone())
+                },
+            )
+            .await;
+    }
+    communicator_
+        .on_match_cancelled(move |cancelled: gn_communicator::models::MatchCancelled| {
+            on_match_cancelled(cancelled, conn.clone())
+        })
+        .await;
+}
+
+async fn listen_for_game_register(conn: Arc<RedisAdapterDefault>) {
+    communicator
+        .get()
+        .await
+        .on_game_register(move |game: gn_communicator::models::GameRegister| {
+            save_game_catalog(game, conn.clone())
+        })
+        .await;
+}
+
+async fn listen_for_server_state_change(
+    conn: Arc<RedisAdapterDefault>,
+    healthcheck: Arc<HealthCheck>,
+) {
+    communicator
+        .get()
+        .await
+        .on_server_state_change(move |change: gn_communicator::models::ServerStateChange| {
+            on_server_state_change(change, conn.clone(), healthcheck.clone())
+        })
+        .await;
+}
+
 async fn listen_for_game_created(conn: Arc<RedisAdapterDefault>) {
     communicator
         .get()
@@ -316,37 +853,69 @@ async fn listen_for_game_created(conn: Arc<RedisAdapterDefault>) {
                         conn.clone(),
                     )
                     .await
-                    .unwrap();
-
-                    if let Err(err) = init_game_ranking(created_game).await {
+                    .map_err(HandlerError::retry)?;
+                    ensure_game_mode(&created_game, &conn).map_err(HandlerError::retry)?;
+
+                    let gn_communicator::models::GameServerCreate {
+                        game,
+                        mode,
+                        ranking_conf,
+                        ..
+                    } = created_game;
+                    if let Err(err) = init_game_ranking(&game, &mode, ranking_conf).await {
                         error!("Error initializing game at ranking server: {:?}", err);
                     }
 
-                    game_id
+                    Ok(game_id)
                 }
             },
         )
         .await;
 }
 
-async fn listen_for_healthcheck(conn: Arc<RedisAdapterDefault>) {
-    let healthcheck = Arc::new(Mutex::new(HealthCheck::new(conn.clone())));
-
+async fn listen_for_healthcheck(
+    conn: Arc<RedisAdapterDefault>,
+    healthcheck: Arc<HealthCheck>,
+    shutdown: Shutdown,
+    health: Health,
+) {
     {
         let healthcheck = healthcheck.clone();
-        tokio::task::spawn_blocking(move || loop {
-            thread::sleep(Duration::from_secs(1));
-            healthcheck.lock().unwrap().check();
+        let heartbeat = health.heartbeat("healthcheck_ticker", HEALTHCHECK_TICKER_MAX_AGE);
+        let runtime = tokio::runtime::Handle::current();
+        tokio::task::spawn_blocking(move || {
+            while !shutdown.is_triggered() {
+                thread::sleep(Duration::from_secs(1));
+                let vanished = match healthcheck.check() {
+                    Ok(vanished) => vanished,
+                    Err(err) => {
+                        error!("Failed to check the health of servers: {}", err);
+                        continue;
+                    }
+                };
+                for server in vanished {
+                    // The server is kept until its matches are aborted, so a failure is retried
+                    if let Err(err) = runtime.block_on(abort_server_matches(&server, &conn)) {
+                        error!("Failed to abort matches of server {}: {}", server.uuid, err);
+                        continue;
+                    }
+                    if let Err(err) = healthcheck.remove(&server) {
+                        error!("Failed to remove server {}: {}", server.uuid, err);
+                    }
+                }
+                heartbeat.beat();
+            }
         });
     }
 
     communicator
         .get()
         .await
-        .on_health_check(move |client_id: String| {
+        .on_health_check(move |report: gn_communicator::models::HealthReport| {
             let healthcheck = healthcheck.clone();
             async move {
-                healthcheck.lock().unwrap().refresh(client_id);
+                healthcheck.refresh(report);
+                Ok(())
             }
         })
         .await;
@@ -360,9 +929,10 @@ async fn listen_for_ai_player_register(conn: Arc<RedisAdapterDefault>) {
             move |ai_player: gn_communicator::models::AIPlayerRegister| {
                 let conn = conn.clone();
                 async move {
-                    if let Err(e) = save_ai_player(ai_player, conn.clone()).await {
-                        error!("Failed to save AI player: {}", e);
-                    }
+                    save_ai_player(ai_player, conn.clone())
+                        .await
+                        .map(|_| ())
+                        .map_err(HandlerError::retry)
                 }
             },
         )
@@ -371,29 +941,81 @@ async fn listen_for_ai_player_register(conn: Arc<RedisAdapterDefault>) {
 
 #[tokio::main]
 async fn main() {
-    let default_hook = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |info| {
-        default_hook(info);
-        std::process::exit(1);
-    }));
-
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::DEBUG)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).unwrap();
-    let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
-
-    let state = RedisAdapter::connect(&redis_url).unwrap();
+    let shutdown = Shutdown::install();
+
+    // Loaded eagerly so invalid configuration is reported before anything else starts.
+    lazy_static::initialize(&CONFIG);
+
+    let telemetry_guard =
+        telemetry::init("games-agent", &CONFIG.telemetry).expect("Failed to initialize telemetry");
+
+    let metrics = PrometheusBuilder::new()
+        .install_recorder()
+        .expect("Could not install prometheus recorder");
+    let health = Health::new(shutdown.clone());
+
+    let app = health
+        .router()
+        .route("/metrics", get(move || std::future::ready(metrics.render())));
+    let listener = tokio::net::TcpListener::bind(CONFIG.metrics_addr)
+        .await
+        .expect("Could not bind HTTP listener");
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    info!("Serving metrics and health on {}", CONFIG.metrics_addr);
+
+    let state = RedisAdapter::connect(&CONFIG.redis_url).unwrap();
     let connection = state.client.get_connection().unwrap();
     let state = Arc::new(state.with_publisher(RedisInfoPublisher::new(connection)));
 
+    {
+        let state = state.clone();
+        health.add_readiness("redis", move || {
+            state
+                .ping(HEALTH_CHECK_TIMEOUT)
+                .map_err(|err| err.to_string())
+        });
+    }
+
     listen_for_match_created(state.clone()).await;
     listen_for_game_created(state.clone()).await;
-    listen_for_healthcheck(state.clone()).await;
+    listen_for_game_register(state.clone()).await;
+    let dead_period = chrono::Duration::seconds(CONFIG.server_dead_period_secs as i64);
+    let healthcheck = Arc::new(HealthCheck::new(state.clone(), dead_period));
+    listen_for_server_state_change(state.clone(), healthcheck.clone()).await;
+    listen_for_healthcheck(
+        state.clone(),
+        healthcheck,
+        shutdown.clone(),
+        health.clone(),
+    )
+    .await;
     listen_for_match_result(state.clone()).await;
     listen_for_match_abrupt_close(state.clone()).await;
+    listen_for_match_lifecycle(state.clone()).await;
     listen_for_ai_player_register(state.clone()).await;
-    loop {
-        thread::sleep(Duration::from_secs(1));
+
+    tokio::spawn(async {
+        communicator
+            .get()
+            .await
+            .retry_outbox(OUTBOX_RETRY_INTERVAL)
+            .await
+    });
+
+    let consumers = communicator.get().await.consumer_statuses();
+    for queue in consumers.get().into_keys() {
+        let consumers = consumers.clone();
+        health.add_readiness(&format!("amqp:{}", queue), move || consumers.check(&queue));
     }
+
+    shutdown.triggered().await;
+    info!("Shutting down");
+    shutdown
+        .drain(async {
+            communicator.get().await.shutdown().await;
+        })
+        .await;
+
+    drop(telemetry_guard);
+    std::process::exit(shutdown.exit_code());
 }
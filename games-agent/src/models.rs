@@ -2,10 +2,16 @@ use itertools::Itertools;
 
 use gn_matchmaking_state_types::ActiveMatchDB;
 
+/// Performance which carries the team index of a player in team modes. Registered with a weight of 0, so it
+/// doesn't count towards the stars of the player.
+pub const TEAM_PERFORMANCE: &str = "team";
+
 pub struct MatchResultMaker(gn_communicator::models::MatchResult, ActiveMatchDB);
 
 pub struct GameServerMaker(gn_communicator::models::GameServerCreate);
 pub struct AIPlayerMaker(gn_communicator::models::AIPlayerRegister);
+pub struct ServerStateMaker(gn_communicator::models::ServerState);
+pub struct GameModeMaker(String, gn_communicator::models::GameModeRegister);
 
 impl From<gn_communicator::models::AIPlayerRegister> for AIPlayerMaker {
     fn from(x: gn_communicator::models::AIPlayerRegister) -> Self {
@@ -40,7 +46,66 @@ impl Into<gn_matchmaking_state_types::GameServer> for GameServerMaker {
             server_pub: self.0.server_pub,
             min_players: self.0.min_players,
             max_players: self.0.max_players,
-            healthy: true
+            healthy: true,
+            active_matches: 0,
+            capacity: 0,
+            cpu_load: 0.0,
+            latency_ms: 0,
+            version: String::new(),
+            state: gn_matchmaking_state_types::ServerState::Active,
+        }
+    }
+}
+
+impl From<(String, gn_communicator::models::GameModeRegister)> for GameModeMaker {
+    fn from(x: (String, gn_communicator::models::GameModeRegister)) -> Self {
+        GameModeMaker(x.0, x.1)
+    }
+}
+
+impl Into<gn_matchmaking_state_types::GameMode> for GameModeMaker {
+    fn into(self) -> gn_matchmaking_state_types::GameMode {
+        let ranking_conf = self.1.ranking_conf.unwrap_or_else(|| {
+            gn_communicator::models::RankingConf {
+                max_stars: 0,
+                description: String::new(),
+                performances: Vec::new(),
+            }
+        });
+        gn_matchmaking_state_types::GameMode {
+            game: self.0,
+            mode: self.1.mode,
+            display_name: self.1.display_name,
+            min_players: self.1.min_players,
+            max_players: self.1.max_players,
+            team_sizes: self.1.team_sizes,
+            ranked: self.1.ranked,
+            ai_allowed: self.1.ai_allowed,
+            ranking_max_stars: ranking_conf.max_stars,
+            ranking_description: ranking_conf.description,
+            ranking_performances: ranking_conf
+                .performances
+                .into_iter()
+                .map(|x| (x.name, x.weight))
+                .collect(),
+        }
+    }
+}
+
+impl From<gn_communicator::models::ServerState> for ServerStateMaker {
+    fn from(x: gn_communicator::models::ServerState) -> Self {
+        ServerStateMaker(x)
+    }
+}
+
+impl Into<gn_matchmaking_state_types::ServerState> for ServerStateMaker {
+    fn into(self) -> gn_matchmaking_state_types::ServerState {
+        use gn_communicator::models::ServerState;
+        match self.0 {
+            ServerState::Active => gn_matchmaking_state_types::ServerState::Active,
+            ServerState::Draining => gn_matchmaking_state_types::ServerState::Draining,
+            ServerState::Maintenance => gn_matchmaking_state_types::ServerState::Maintenance,
+            ServerState::Dead => gn_matchmaking_state_types::ServerState::Dead,
         }
     }
 }
@@ -86,6 +151,12 @@ impl Into<gn_ranking_client_rs::models::create::Match> for MatchResultMaker {
                                     count: points as i32,
                                 },
                             ))
+                            .chain(active_match.teams.get(&player_id).map(|team| {
+                                gn_ranking_client_rs::models::create::PlayerPerformance {
+                                    name: TEAM_PERFORMANCE.to_string(),
+                                    count: *team as i32,
+                                }
+                            }))
                             .collect(),
                         player_id,
                     },
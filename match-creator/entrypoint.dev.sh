@@ -11,6 +11,7 @@ gn-matchmaking-state      = { path = "/usr/matchmaking-state" }
 gn-matchmaking-state-types = { path = "/usr/matchmaking-state-types" }
 gn-communicator           = { path = "/usr/communicator" }
 gn-redisadapter-derive    = { path = "/usr/matchmaking-state/redisadapter-derive" }
+gn-service                = { path = "/usr/service" }
 TOMLPATCH
   fi
   cargo build
@@ -0,0 +1,192 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use gn_matchmaking_state::prelude::*;
+use gn_matchmaking_state_types::DBGameServer;
+use serde::Deserialize;
+
+/// Picks the game server a new match runs on.
+pub trait AllocationStrategy: Send + Sync {
+    /// Picks one of `servers`, which all run the game, mode and region of the match, accept new matches and are
+    /// sorted by uuid.
+    fn pick<'a>(&self, servers: &'a [DBGameServer]) -> Option<&'a DBGameServer>;
+}
+
+pub struct LeastLoaded;
+
+impl AllocationStrategy for LeastLoaded {
+    fn pick<'a>(&self, servers: &'a [DBGameServer]) -> Option<&'a DBGameServer> {
+        servers.iter().min_by(|a, b| a.load().total_cmp(&b.load()))
+    }
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl AllocationStrategy for RoundRobin {
+    fn pick<'a>(&self, servers: &'a [DBGameServer]) -> Option<&'a DBGameServer> {
+        if servers.is_empty() {
+            return None;
+        }
+        servers.get(self.next.fetch_add(1, Ordering::Relaxed) % servers.len())
+    }
+}
+
+/// Servers which don't report their latency are only picked if no other server is left.
+pub struct LowestLatency;
+
+impl AllocationStrategy for LowestLatency {
+    fn pick<'a>(&self, servers: &'a [DBGameServer]) -> Option<&'a DBGameServer> {
+        servers
+            .iter()
+            .min_by_key(|server| (server.latency_ms == 0, server.latency_ms))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyKind {
+    #[default]
+    LeastLoaded,
+    RoundRobin,
+    LowestLatency,
+}
+
+impl StrategyKind {
+    pub fn build(self) -> Box<dyn AllocationStrategy> {
+        match self {
+            Self::LeastLoaded => Box::new(LeastLoaded),
+            Self::RoundRobin => Box::new(RoundRobin::default()),
+            Self::LowestLatency => Box::new(LowestLatency),
+        }
+    }
+}
+
+/// Allocates game servers to new matches.
+pub struct Allocator {
+    conn: Arc<RedisAdapterDefault>,
+    strategy: Box<dyn AllocationStrategy>,
+}
+
+impl Allocator {
+    pub fn new(conn: Arc<RedisAdapterDefault>, strategy: Box<dyn AllocationStrategy>) -> Self {
+        Self { conn, strategy }
+    }
+
+    /// Picks an active, healthy server for a match of `game`, `mode` and `region` and reserves a slot on it. The match is
+    /// counted as running on the server until the server reports its matches with the next health check.
+    ///
+    /// The slot is reserved in Redis, so replicas allocating at the same time never overfill a server. If another
+    /// replica took the last slot of the picked server, the next server is picked.
+    pub fn allocate(
+        &self,
+        game: &str,
+        mode: &str,
+        region: &str,
+    ) -> Result<DBGameServer, Box<dyn std::error::Error>> {
+        let mut servers: Vec<DBGameServer> = self
+            .conn
+            .all()?
+            .filter(|server: &DBGameServer| {
+                server.accepts_matches()
+                    && server.game == game
+                    && server.mode == mode
+                    && server.region == region
+            })
+            .collect();
+        servers.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+
+        while let Some(server) = self.strategy.pick(&servers).cloned() {
+            let reserved = self.conn.increment_below(
+                &format!("{}:active_matches", server.uuid),
+                &format!("{}:capacity", server.uuid),
+            )?;
+            if reserved {
+                return Ok(server);
+            }
+            servers.retain(|other| other.uuid != server.uuid);
+        }
+        Err("no active server with capacity left".into())
+    }
+
+    /// Gives back the slot [`Allocator::allocate`] reserved on the server `uuid`, for a match which never reached it.
+    pub fn release(&self, uuid: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn
+            .decrement_to_zero(&format!("{}:active_matches", uuid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gn_matchmaking_state_types::ServerState;
+
+    use super::*;
+
+    fn server(
+        uuid: &str,
+        active_matches: u32,
+        capacity: u32,
+        cpu_load: f32,
+        latency_ms: u32,
+    ) -> DBGameServer {
+        DBGameServer {
+            uuid: uuid.to_owned(),
+            region: "eu".to_owned(),
+            game: "chess".to_owned(),
+            mode: "ranked".to_owned(),
+            server_pub: "127.0.0.1:3456".to_owned(),
+            server_priv: "127.0.0.1:3456".to_owned(),
+            healthy: true,
+            min_players: 2,
+            max_players: 2,
+            active_matches,
+            capacity,
+            cpu_load,
+            latency_ms,
+            version: "1.0.0".to_owned(),
+            state: ServerState::Active,
+        }
+    }
+
+    fn picked<'a>(
+        strategy: &dyn AllocationStrategy,
+        servers: &'a [DBGameServer],
+    ) -> Option<&'a str> {
+        strategy.pick(servers).map(|server| server.uuid.as_str())
+    }
+
+    #[test]
+    fn test_least_loaded_picks_lowest_load() {
+        let servers = [
+            server("a", 3, 4, 0.1, 10),
+            server("b", 1, 4, 0.5, 10),
+            server("c", 1, 4, 0.2, 10),
+        ];
+        assert_eq!(picked(&LeastLoaded, &servers), Some("c"));
+        assert_eq!(picked(&LeastLoaded, &[]), None);
+    }
+
+    #[test]
+    fn test_round_robin_cycles_through_servers() {
+        let strategy = RoundRobin::default();
+        let servers = [server("a", 0, 0, 0.0, 0), server("b", 0, 0, 0.0, 0)];
+        let picks: Vec<_> = (0..3).map(|_| picked(&strategy, &servers)).collect();
+        assert_eq!(picks, vec![Some("a"), Some("b"), Some("a")]);
+        assert_eq!(picked(&strategy, &[]), None);
+    }
+
+    #[test]
+    fn test_lowest_latency_prefers_reported_latency() {
+        let servers = [
+            server("a", 0, 0, 0.0, 0),
+            server("b", 0, 0, 0.0, 40),
+            server("c", 0, 0, 0.0, 20),
+        ];
+        assert_eq!(picked(&LowestLatency, &servers), Some("c"));
+        assert_eq!(picked(&LowestLatency, &servers[..1]), Some("a"));
+    }
+}
@@ -0,0 +1,68 @@
+use gn_service::{
+    config::{require_file, require_url, ConfigError, Validate},
+    telemetry::TelemetryConfig,
+};
+use gn_communicator::transport::TransportKind;
+use serde::Deserialize;
+
+use crate::allocation::StrategyKind;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub redis_url: String,
+    /// Broker the messages are sent over. Redis Streams use `redis_url`.
+    #[serde(default)]
+    pub transport: TransportKind,
+    /// Required if the transport is RabbitMQ.
+    #[serde(default)]
+    pub amqp_url: Option<String>,
+    /// Address of the HTTP server serving `/metrics`, `/healthz` and `/readyz`.
+    #[serde(default = "default_metrics_addr")]
+    pub metrics_addr: std::net::SocketAddr,
+    /// Overrides the queue names bundled with the communicator.
+    #[serde(default)]
+    pub queues_file: Option<String>,
+    /// Tells the replicas of the service apart in the outbox. Should survive restarts, defaults to the hostname.
+    #[serde(default = "default_instance_id")]
+    pub instance_id: String,
+    /// How the game server of a new match is picked.
+    #[serde(default)]
+    pub allocation_strategy: StrategyKind,
+    #[serde(flatten)]
+    pub telemetry: TelemetryConfig,
+}
+
+fn default_metrics_addr() -> std::net::SocketAddr {
+    ([0, 0, 0, 0], 9000).into()
+}
+
+fn default_instance_id() -> String {
+    std::env::var("HOSTNAME").unwrap_or_else(|_| "default".to_owned())
+}
+
+impl Config {
+    /// Url of the broker of the configured transport.
+    pub fn broker_url(&self) -> &str {
+        match self.transport {
+            TransportKind::Rabbitmq => self.amqp_url.as_deref().unwrap_or_default(),
+            TransportKind::RedisStreams => &self.redis_url,
+        }
+    }
+}
+
+impl Validate for Config {
+    fn validate(&self) -> Result<(), ConfigError> {
+        require_url("redis_url", &self.redis_url, &["redis", "rediss"])?;
+        if self.transport == TransportKind::Rabbitmq {
+            let amqp_url = self.amqp_url.as_deref().ok_or(ConfigError::Invalid {
+                field: "amqp_url",
+                reason: "required if the transport is rabbitmq".to_owned(),
+            })?;
+            require_url("amqp_url", amqp_url, &["amqp", "amqps"])?;
+        }
+        if let Some(queues_file) = self.queues_file.as_ref() {
+            require_file("queues_file", queues_file)?;
+        }
+        self.telemetry.validate()
+    }
+}
@@ -1,69 +1,196 @@
-use gn_communicator::Communicator;
-use gn_matchmaking_state_types::DBSearcher;
-use std::{collections::HashMap, sync::Arc};
+use allocation::Allocator;
+use gn_communicator::{transport::Transport, Communicator};
+use gn_matchmaking_state_types::{AIPlayerDB, DBSearcher, GameModeDB};
+use axum::routing::get;
+use gn_service::{config::ConfigLoader, health::Health, shutdown::Shutdown, telemetry};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use tokio::runtime::Runtime;
-use tracing::{debug, info, warn, Level};
-use tracing_subscriber::FmtSubscriber;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 use gn_matchmaking_state::models::Match;
 
 use gn_matchmaking_state::prelude::*;
 use serde::{Deserialize, Serialize};
 
+mod allocation;
+mod config;
 mod model;
 
+const QUEUE_SIZE_INTERVAL: Duration = Duration::from_secs(5);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often messages whose publish failed are published again from the outbox.
+const OUTBOX_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
 fn handle_match(
     new_match: Match,
     conn: Arc<RedisAdapterDefault>,
+    allocator: &Allocator,
 ) -> Result<gn_communicator::models::CreateMatch, Box<dyn std::error::Error>> {
     debug!("Matched players: {:?}", new_match);
 
     // TODO: Write the logic for the pool to look up the existence of the game (If needed. Else remove the pool)
 
     let mut ai_players = Vec::new();
+    // Every party with the elo its players count with, to build the teams from. Players searching alone and AI players
+    // are parties of one.
+    let mut parties = Vec::new();
 
-    let players: Vec<_> = new_match
-        .players
-        .into_iter()
-        .map(|player_id| match conn.get(&player_id) {
+    for player_id in new_match.players {
+        match conn.get(&player_id) {
             Ok(player) => {
                 let player: DBSearcher = player;
-                player.player_id
+                parties.push((player.players().cloned().collect::<Vec<_>>(), player.elo));
+                let waited = SystemTime::now()
+                    .duration_since(player.wait_start)
+                    .unwrap_or_default();
+                histogram!(
+                    "matchmaking_wait_time_seconds",
+                    "game" => player.game,
+                    "mode" => player.mode,
+                    "region" => player.region
+                )
+                .record(waited.as_secs_f64());
             }
             Err(err) => {
                 warn!("Player not found: {}", err);
-                ai_players.push(player_id.clone());
-                player_id
+                // The matcher fills matches with the display names of AI players
+                let elo = conn
+                    .all()?
+                    .find(|ai_player: &AIPlayerDB| {
+                        ai_player.display_name == player_id
+                            && ai_player.game == new_match.game
+                            && ai_player.mode == new_match.mode
+                    })
+                    .map(|ai_player| ai_player.elo)
+                    .unwrap_or_default();
+                parties.push((vec![player_id.clone()], elo));
+                ai_players.push(player_id);
             }
-        })
+        }
+    }
+    let players: Vec<String> = parties
+        .iter()
+        .flat_map(|(party, _)| party.iter().cloned())
         .collect();
 
     if (ai_players.len() == players.len()) {
         return Err("All players are AI players".into());
     }
 
+    let game_mode = conn.all()?.find(|game_mode: &GameModeDB| {
+        game_mode.game == new_match.game && game_mode.mode == new_match.mode
+    });
+    let teams = match game_mode {
+        Some(game_mode) => game_mode
+            .build_teams(&parties)
+            .ok_or("More players than the teams of the mode have room for")?,
+        None => Vec::new(),
+    };
+
+    let server = match allocator.allocate(&new_match.game, &new_match.mode, &new_match.region) {
+        Ok(server) => {
+            debug!("Allocated server {:?} ({})", server.uuid, server.server_priv);
+            Some(server.uuid)
+        }
+        Err(err) => {
+            // Published without an address, so any server of the game and mode may take it
+            warn!("Could not allocate a server: {}", err);
+            counter!(
+                "matchmaking_allocation_failures_total",
+                "game" => new_match.game.clone(),
+                "mode" => new_match.mode.clone(),
+                "region" => new_match.region.clone()
+            )
+            .increment(1);
+            None
+        }
+    };
+
     Ok(gn_communicator::models::CreateMatch {
         game: new_match.game,
         players,
         ai_players,
         mode: new_match.mode.clone().into(),
+        server,
+        teams,
+    })
+}
+
+/// Periodically reports the amount of waiting searchers per game, mode and region.
+/// Combinations which had searchers in a previous run but not anymore are reset to zero.
+fn start_queue_size_report(conn: Arc<RedisAdapterDefault>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut reported: HashSet<(String, String, String)> = HashSet::new();
+        let mut interval = tokio::time::interval(QUEUE_SIZE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let searchers = match conn.all() {
+                Ok(searchers) => searchers.collect::<Vec<DBSearcher>>(),
+                Err(err) => {
+                    warn!("Could not read searchers for queue size report: {}", err);
+                    continue;
+                }
+            };
+
+            let mut sizes: HashMap<(String, String, String), usize> = HashMap::new();
+            for searcher in searchers {
+                *sizes
+                    .entry((searcher.game, searcher.mode, searcher.region))
+                    .or_default() += 1;
+            }
+
+            for key in reported.iter().filter(|key| !sizes.contains_key(*key)) {
+                let (game, mode, region) = key.clone();
+                gauge!("matchmaking_queue_size", "game" => game, "mode" => mode, "region" => region)
+                    .set(0.0);
+            }
+
+            for ((game, mode, region), size) in sizes.iter() {
+                gauge!(
+                    "matchmaking_queue_size",
+                    "game" => game.clone(),
+                    "mode" => mode.clone(),
+                    "region" => region.clone()
+                )
+                .set(*size as f64);
+            }
+            reported = sizes.into_keys().collect();
+        }
     })
 }
 
 #[tokio::main]
 async fn main() {
-    let default_hook = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |info| {
-        default_hook(info);
-        std::process::exit(1);
-    }));
-
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::DEBUG)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).unwrap();
-    let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
-    let connector = RedisAdapter::connect(&redis_url).expect("Could not connect to Redis database");
+    let shutdown = Shutdown::install();
+
+    let config: config::Config = ConfigLoader::new("match-creator").load_or_exit();
+
+    let telemetry_guard = telemetry::init("match-creator", &config.telemetry)
+        .expect("Failed to initialize telemetry");
+
+    let metrics = PrometheusBuilder::new()
+        .install_recorder()
+        .expect("Could not install prometheus recorder");
+    let health = Health::new(shutdown.clone());
+
+    let app = health
+        .router()
+        .route("/metrics", get(move || std::future::ready(metrics.render())));
+    let listener = tokio::net::TcpListener::bind(config.metrics_addr)
+        .await
+        .expect("Could not bind HTTP listener");
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    info!("Serving metrics and health on {}", config.metrics_addr);
+
+    let connector = RedisAdapter::connect(&config.redis_url).expect("Could not connect to Redis database");
 
     let redis_connection = connector.client.get_connection().unwrap();
     let connector = Arc::new(
@@ -72,27 +199,101 @@ async fn main() {
             .with_auto_timeout(60),
     );
 
-    let amqp_url = std::env::var("AMQP_URL").expect("AMQP_URL must be set");
-    let communicator =
-        Arc::new(gn_communicator::rabbitmq::RabbitMQCommunicator::connect(&amqp_url).await);
+    let mut communicator = Transport::connect(config.transport, config.broker_url()).await;
+    communicator.set_producer("match-creator");
+    // Matched searchers are removed before the match is published, so keep it until the broker confirmed it
+    communicator.set_outbox(
+        Arc::new(
+            RedisAdapterDefault::connect(&config.redis_url)
+                .expect("Could not connect to Redis database"),
+        ),
+        &config.instance_id,
+    );
+    if let Some(queues_file) = config.queues_file.as_ref() {
+        communicator.load_queues(queues_file);
+    }
+    communicator.flush_outbox().await;
+    let communicator = Arc::new(communicator);
+    {
+        let communicator = communicator.clone();
+        tokio::spawn(async move { communicator.retry_outbox(OUTBOX_RETRY_INTERVAL).await });
+    }
+
+    {
+        let connector = connector.clone();
+        health.add_readiness("redis", move || {
+            connector
+                .ping(HEALTH_CHECK_TIMEOUT)
+                .map_err(|err| err.to_string())
+        });
+    }
 
+    health.add_task("queue_size_report", &start_queue_size_report(connector.clone()));
     info!("Started pool auto-update");
     info!("Started match check");
 
+    let allocator = Arc::new(Allocator::new(
+        connector.clone(),
+        config.allocation_strategy.build(),
+    ));
     let match_checker = connector.clone();
+    let on_match_communicator = communicator.clone();
+    // Publishes of created matches which have not finished yet
+    let in_flight = TaskTracker::new();
+    let on_match_in_flight = in_flight.clone();
     connector.clone().on_match(move |new_match: Match| {
         info!("New match: {:?}", new_match);
 
         let connector = connector.clone();
 
-        let created_match = handle_match(new_match, connector.clone());
+        let span = info_span!(
+            "match",
+            game = %new_match.game,
+            mode = %new_match.mode,
+            region = %new_match.region
+        );
+        let region = new_match.region.clone();
+        let created_match =
+            span.in_scope(|| handle_match(new_match, connector.clone(), &allocator));
 
         match created_match {
             Ok(created_match) => {
-                let communicator = communicator.clone();
-                tokio::spawn(async move {
-                    communicator.create_match(&created_match).await;
-                });
+                let communicator = on_match_communicator.clone();
+                let allocator = allocator.clone();
+                on_match_in_flight.spawn(
+                    async move {
+                        if let Err(err) = communicator.create_match(&created_match).await {
+                            error!("Could not publish match: {}", err);
+                            // The server never gets the match, so its slot is free again
+                            if let Some(server) = created_match.server.as_ref() {
+                                if let Err(err) = allocator.release(server) {
+                                    error!(
+                                        "Could not release the slot on server {:?}: {}",
+                                        server, err
+                                    );
+                                }
+                            }
+                            return;
+                        }
+
+                        counter!(
+                            "matchmaking_matches_created_total",
+                            "game" => created_match.game.clone(),
+                            "mode" => created_match.mode.clone(),
+                            "region" => region
+                        )
+                        .increment(1);
+                        if !created_match.ai_players.is_empty() {
+                            counter!(
+                                "matchmaking_ai_fills_total",
+                                "game" => created_match.game.clone(),
+                                "mode" => created_match.mode.clone()
+                            )
+                            .increment(1);
+                        }
+                    }
+                    .instrument(span),
+                );
             }
             Err(err) => {
                 warn!("Error creating match: {}", err);
@@ -100,5 +301,23 @@ async fn main() {
         }
     });
     info!("On match handler registered");
-    match_checker.start_match_check().await.unwrap();
+    let match_check = match_checker.start_match_check();
+    health.add_task("match_check", &match_check);
+
+    shutdown.triggered().await;
+    info!("Shutting down");
+    match_checker.stop_match_check();
+    shutdown
+        .drain(async {
+            if let Err(err) = match_check.await {
+                error!("Match check failed: {}", err);
+            }
+            in_flight.close();
+            in_flight.wait().await;
+            communicator.shutdown().await;
+        })
+        .await;
+
+    drop(telemetry_guard);
+    std::process::exit(shutdown.exit_code());
 }
@@ -0,0 +1,31 @@
+use gn_service::{
+    config::{require_url, ConfigError, Validate},
+    telemetry::TelemetryConfig,
+};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// Address the HTTP server binds to.
+    pub host_url: std::net::SocketAddr,
+    pub redis_url: String,
+    pub ezauth_url: String,
+    /// Bearer token administrators authorize changes of game servers with.
+    pub admin_api_key: String,
+    #[serde(flatten)]
+    pub telemetry: TelemetryConfig,
+}
+
+impl Validate for Config {
+    fn validate(&self) -> Result<(), ConfigError> {
+        require_url("redis_url", &self.redis_url, &["redis", "rediss"])?;
+        require_url("ezauth_url", &self.ezauth_url, &["http", "https"])?;
+        if self.admin_api_key.is_empty() {
+            return Err(ConfigError::Invalid {
+                field: "admin_api_key",
+                reason: "must not be empty".to_owned(),
+            });
+        }
+        self.telemetry.validate()
+    }
+}
@@ -44,9 +44,10 @@ async fn get_active_matches(
 async fn leave(
     req: HttpRequest,
     state: web::Data<gn_matchmaking_state::adapters::redis::RedisAdapterDefault>,
+    settings: web::Data<crate::config::Config>,
     session_token: web::Path<String>,
 ) -> Result<HttpResponse, Error> {
-    let validation = ezauth::validate_user(&session_token, &super::EZAUTH_URL).await?;
+    let validation = ezauth::validate_user(&session_token, &settings.ezauth_url).await?;
 
     let mut filter = ActiveMatchFilter::default();
     filter.player = Some(validation._id.clone());
@@ -105,9 +106,10 @@ async fn get_write_token(
     req: HttpRequest,
     state: web::Data<gn_matchmaking_state::adapters::redis::RedisAdapterDefault>,
     session_token: web::Path<String>,
+    settings: web::Data<crate::config::Config>,
     read: web::Path<String>,
 ) -> Result<HttpResponse, Error> {
-    let validation = ezauth::validate_user(&session_token, &super::EZAUTH_URL).await?;
+    let validation = ezauth::validate_user(&session_token, &settings.ezauth_url).await?;
 
     let mut filter = ActiveMatchFilter::default();
     filter.player = Some(validation._id.clone());
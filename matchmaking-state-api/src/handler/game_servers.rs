@@ -1,10 +1,15 @@
 use std::sync::Arc;
 
-use actix_web::{delete, get, http::StatusCode, post, web, Error, HttpRequest, HttpResponse};
-use gn_matchmaking_state::adapters::{redis::Commands, Gettable};
-use gn_matchmaking_state_types::{ActiveMatchDB, DBGameServer};
+use actix_web::{
+    delete, get, http::{header, StatusCode}, post, put, web, Error, HttpRequest, HttpResponse,
+};
+use gn_matchmaking_state::adapters::{redis::Commands, Gettable, Updateable};
+use gn_matchmaking_state_types::{ActiveMatchDB, DBGameServer, GameServerUpdater, ServerState};
+use tracing::info;
 
-use crate::models::{ActiveMatch, ActiveMatchFilter, GameServer, GameServerFilter};
+use crate::models::{
+    ActiveMatch, ActiveMatchFilter, GameServer, GameServerFilter, GameServerStateUpdate,
+};
 
 #[utoipa::path(
     context_path = "/game-servers",
@@ -54,4 +59,70 @@ async fn get_game_server_by_uuid(
     Ok(HttpResponse::Ok()
         .content_type("application/json")
         .json(GameServer::from(m)))
-}
\ No newline at end of file
+}
+
+
+#[utoipa::path(
+    context_path = "/game-servers",
+    request_body = GameServerStateUpdate,
+    responses(
+        (status = 200, description = "Game Server in its new state", body = GameServer),
+        (status = 401, description = "Missing or wrong admin api key"),
+        (status = 404, description = "No Game Server with the requested uuid")
+    ),
+    params(
+        ("uuid" = String, Path, description = "UUID of the game server")
+    ),
+    security(
+        ("admin_api_key" = [])
+    )
+)]
+#[put("/{uuid}/state")]
+async fn set_game_server_state(
+    req: HttpRequest,
+    client: web::Data<gn_matchmaking_state::adapters::redis::RedisAdapterDefault>,
+    settings: web::Data<crate::config::Config>,
+    uuid: web::Path<String>,
+    update: web::Json<GameServerStateUpdate>,
+) -> Result<HttpResponse, Error> {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|key| key == settings.admin_api_key);
+    if !authorized {
+        return Err(actix_web::error::ErrorUnauthorized("Missing or wrong admin api key"));
+    }
+
+    let mut server: DBGameServer = client.get(&uuid).map_err(|e| {
+        actix_web::error::ErrorNotFound(format!("Failed to fetch game server: {}", e))
+    })?;
+
+    // games-agent gives servers without a heartbeat the full health check timeout, so a server leaving maintenance
+    // isn't dead right away for the health checks it didn't send meanwhile
+    if server.state == ServerState::Maintenance && update.state != ServerState::Maintenance {
+        client
+            .release(&format!("{}:heartbeat", server.uuid))
+            .map_err(|e| {
+                actix_web::error::ErrorInternalServerError(format!(
+                    "Failed to reset heartbeat of game server: {}",
+                    e
+                ))
+            })?;
+    }
+
+    let updater = GameServerUpdater {
+        state: Some(update.state),
+        ..Default::default()
+    };
+    client.update(&server.uuid, updater).map_err(|e| {
+        actix_web::error::ErrorInternalServerError(format!("Failed to update game server: {}", e))
+    })?;
+    info!("Server {:?} is now {:?}", server.uuid, update.state);
+    server.state = update.state;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(GameServer::from(server)))
+}
@@ -0,0 +1,70 @@
+use actix_web::{get, web, Error, HttpResponse};
+use gn_matchmaking_state::adapters::Gettable;
+use gn_matchmaking_state_types::{GameDB, GameModeDB};
+
+use crate::models::Game;
+
+fn game_modes(
+    client: &gn_matchmaking_state::adapters::redis::RedisAdapterDefault,
+) -> Result<Vec<GameModeDB>, Error> {
+    Ok(client
+        .all()
+        .map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("Failed to fetch game modes: {}", e))
+        })?
+        .collect())
+}
+
+#[utoipa::path(
+    context_path = "/games",
+    responses(
+        (status = 200, description = "Every game of the catalog with its modes", body = Vec<Game>),
+    )
+)]
+#[get("/")]
+async fn get_games(
+    client: web::Data<gn_matchmaking_state::adapters::redis::RedisAdapterDefault>,
+) -> Result<HttpResponse, Error> {
+    let modes = game_modes(&client)?;
+    let games: Vec<Game> = client
+        .all()
+        .map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("Failed to fetch games: {}", e))
+        })?
+        .map(|game: GameDB| Game::new(game, &modes))
+        .collect();
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(games))
+}
+
+
+#[utoipa::path(
+    context_path = "/games",
+    responses(
+        (status = 200, description = "Game with the requested name and its modes", body = Game),
+        (status = 404, description = "No game with the requested name")
+    ),
+    params(
+        ("name" = String, Path, description = "Name of the game")
+    )
+)]
+#[get("/{name}")]
+async fn get_game_by_name(
+    client: web::Data<gn_matchmaking_state::adapters::redis::RedisAdapterDefault>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let game = client
+        .all()
+        .map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("Failed to fetch games: {}", e))
+        })?
+        .find(|game: &GameDB| game.name == *name)
+        .ok_or_else(|| actix_web::error::ErrorNotFound(format!("Game {} not found", name)))?;
+    let modes = game_modes(&client)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(Game::new(game, &modes)))
+}
@@ -0,0 +1,21 @@
+use actix_web::{get, web, Error, HttpResponse};
+use gn_service::health::{Health, Report};
+
+fn respond(report: Report) -> HttpResponse {
+    match report.is_ok() {
+        true => HttpResponse::Ok().json(report),
+        false => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
+
+#[get("/healthz")]
+async fn get_liveness(health: web::Data<Health>) -> Result<HttpResponse, Error> {
+    let report = web::block(move || health.liveness()).await?;
+    Ok(respond(report))
+}
+
+#[get("/readyz")]
+async fn get_readiness(health: web::Data<Health>) -> Result<HttpResponse, Error> {
+    let report = web::block(move || health.readiness()).await?;
+    Ok(respond(report))
+}
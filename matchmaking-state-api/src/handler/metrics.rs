@@ -0,0 +1,9 @@
+use actix_web::{get, web, HttpResponse};
+use metrics_exporter_prometheus::PrometheusHandle;
+
+#[get("/metrics")]
+async fn get_metrics(handle: web::Data<PrometheusHandle>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(handle.render())
+}
@@ -1,7 +1,10 @@
 pub mod active_matches;
 pub mod game_servers;
 pub mod ai_players;
+pub mod games;
 pub mod host_requests;
+pub mod health;
+pub mod metrics;
 
 
 use std::sync::Arc;
@@ -9,15 +12,9 @@ use std::sync::Arc;
 use actix_web::{delete, get, http::StatusCode, post, web, Error, HttpRequest, HttpResponse};
 use gn_matchmaking_state::adapters::{redis::{Commands, RedisIdentifiable, RedisOutputReader}, Gettable};
 use gn_matchmaking_state_types::ActiveMatchDB;
-use lazy_static::lazy_static;
 
 use crate::models::{ActiveMatch, ActiveMatchFilter, Filter};
 
-
-lazy_static! {
-    pub static ref EZAUTH_URL: String = std::env::var("EZAUTH_URL").unwrap();
-}
-
 async fn filter<T, D, F>(
     state: Arc<gn_matchmaking_state::adapters::redis::RedisAdapterDefault>,
     filter: &F,
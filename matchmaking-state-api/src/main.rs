@@ -1,16 +1,25 @@
+mod config;
 mod handler;
 mod models;
 mod swagger_docs;
 
 use actix_web::{web, App, HttpServer};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use gn_service::{
+    config::ConfigLoader,
+    health::Health,
+    shutdown::{Shutdown, DEFAULT_GRACE_PERIOD},
+    telemetry,
+};
 use tracing::info;
 use tracing_actix_web::TracingLogger;
-use tracing_subscriber::FmtSubscriber;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
 use crate::swagger_docs::ApiDoc;
 
+const HEALTH_CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
 fn config(conf: &mut web::ServiceConfig) {
     let scope = web::scope("/active-matches")
         .service(handler::active_matches::get_active_matches)
@@ -21,7 +30,8 @@ fn config(conf: &mut web::ServiceConfig) {
 
     let scope = web::scope("/game-servers")
         .service(handler::game_servers::get_game_server_by_uuid)
-        .service(handler::game_servers::get_game_servers);
+        .service(handler::game_servers::get_game_servers)
+        .service(handler::game_servers::set_game_server_state);
     conf.service(scope);
 
     let scope = web::scope("/host-requests")
@@ -29,45 +39,86 @@ fn config(conf: &mut web::ServiceConfig) {
         .service(handler::host_requests::get_host_requests);
     conf.service(scope);
 
+    let scope = web::scope("/games")
+        .service(handler::games::get_game_by_name)
+        .service(handler::games::get_games);
+    conf.service(scope);
+
     let scope = web::scope("/ai-players")
         .service(handler::ai_players::get_ai_player_by_uuid)
         .service(handler::ai_players::get_ai_players);
     conf.service(scope);
+
+    conf.service(handler::metrics::get_metrics);
+    conf.service(handler::health::get_liveness);
+    conf.service(handler::health::get_readiness);
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let default_hook = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |info| {
-        default_hook(info);
-        std::process::exit(1);
-    }));
+    let shutdown = Shutdown::install();
+
+    let settings: config::Config = ConfigLoader::new("matchmaking-state-api").load_or_exit();
 
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(tracing::Level::DEBUG)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).expect("Failed to set subscriber");
+    let telemetry_guard = telemetry::init("matchmaking-state-api", &settings.telemetry)
+        .expect("Failed to initialize telemetry");
 
-    let host_url = std::env::var("HOST_URL").expect("HOST_URL must be set");
+    let metrics = web::Data::new(
+        PrometheusBuilder::new()
+            .install_recorder()
+            .expect("Failed to install prometheus recorder"),
+    );
+
+    let host_url = settings.host_url;
 
     info!("Starting HTTP server at: {}", host_url);
 
-    let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
     let client = web::Data::new(
-        gn_matchmaking_state::adapters::redis::RedisAdapterDefault::connect(&redis_url)
+        gn_matchmaking_state::adapters::redis::RedisAdapterDefault::connect(&settings.redis_url)
             .expect("Failed to connect to Redis"),
     );
+    let settings = web::Data::new(settings);
+
+    let health = Health::new(shutdown.clone());
+    {
+        let client = client.clone();
+        health.add_readiness("redis", move || {
+            client
+                .ping(HEALTH_CHECK_TIMEOUT)
+                .map_err(|err| err.to_string())
+        });
+    }
+    let health = web::Data::new(health);
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(client.clone())
+            .app_data(metrics.clone())
+            .app_data(settings.clone())
+            .app_data(health.clone())
             .wrap(TracingLogger::default())
             .configure(config)
             .service(
                 SwaggerUi::new("/docs/{_:.*}").url("/api-docs/openapi.json", ApiDoc::openapi()),
             )
     })
+    // Signals are handled by `Shutdown` so panics stop the server gracefully as well
+    .disable_signals()
+    .shutdown_timeout(DEFAULT_GRACE_PERIOD.as_secs())
     .bind(host_url)?
-    .run()
-    .await
+    .run();
+
+    let handle = server.handle();
+    actix_web::rt::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown.triggered().await;
+            info!("Shutting down");
+            handle.stop(true).await;
+        }
+    });
+    server.await?;
+
+    drop(telemetry_guard);
+    std::process::exit(shutdown.exit_code());
 }
@@ -1,4 +1,6 @@
-use gn_matchmaking_state_types::ActiveMatchDB;
+use std::collections::HashMap;
+
+use gn_matchmaking_state_types::{ActiveMatchDB, GameDB, GameModeDB, ServerState};
 use serde::{Deserialize, Serialize};
 use utoipa::{IntoParams, ToSchema};
 
@@ -12,7 +14,9 @@ pub struct ActiveMatch {
     pub address: String,
     pub region: String,
     pub read: String,
-    pub players: Vec<String>
+    pub players: Vec<String>,
+    /// Index of the team of every player. Empty if the mode is not played in teams.
+    pub teams: HashMap<String, u32>,
 }
 
 impl From<ActiveMatchDB> for ActiveMatch {
@@ -25,7 +29,8 @@ impl From<ActiveMatchDB> for ActiveMatch {
             address: am.server_pub,
             region: am.region,
             read: am.read,
-            players: am.player_write.keys().cloned().collect()
+            players: am.player_write.keys().cloned().collect(),
+            teams: am.teams,
         }
     }
 }
@@ -82,7 +87,7 @@ pub struct ActiveMatchFilter {
     pub player: Option<String>
 }
 
-#[derive(ToSchema, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(ToSchema, Serialize, Deserialize, Debug, PartialEq)]
 pub struct GameServer {
     pub uuid: String,
     pub region: String,
@@ -92,6 +97,21 @@ pub struct GameServer {
     pub healthy: bool,
     pub min_players: u32,
     pub max_players: u32,
+    pub active_matches: u32,
+    pub capacity: u32,
+    pub cpu_load: f32,
+    pub latency_ms: u32,
+    pub version: String,
+    #[schema(value_type = String, example = "active")]
+    pub state: ServerState,
+}
+
+/// New state of a game server. Draining servers get no new matches, but keep their active matches until they
+/// finish.
+#[derive(ToSchema, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct GameServerStateUpdate {
+    #[schema(value_type = String, example = "draining")]
+    pub state: ServerState,
 }
 
 
@@ -101,6 +121,9 @@ pub struct GameServerFilter {
     pub game: Option<String>,
     pub mode: Option<String>,
     pub healthy: Option<bool>,
+    #[schema(value_type = Option<String>)]
+    #[param(value_type = Option<String>)]
+    pub state: Option<ServerState>,
     pub min_players: Option<u32>,
     pub max_players: Option<u32>,
 }
@@ -117,6 +140,12 @@ impl From<gn_matchmaking_state_types::DBGameServer> for GameServer {
             healthy: gs.healthy,
             min_players: gs.min_players,
             max_players: gs.max_players,
+            active_matches: gs.active_matches,
+            capacity: gs.capacity,
+            cpu_load: gs.cpu_load,
+            latency_ms: gs.latency_ms,
+            version: gs.version,
+            state: gs.state,
         }
     }
 }
@@ -148,6 +177,12 @@ impl Filter<GameServerFilter> for gn_matchmaking_state_types::DBGameServer {
             }
         }
 
+        if let Some(state) = filter.state {
+            if self.state != state {
+                return false;
+            }
+        }
+
         if let Some(min_players) = filter.min_players {
             if self.min_players < min_players {
                 return false;
@@ -335,3 +370,60 @@ impl Filter<AIPlayerFilter> for gn_matchmaking_state_types::AIPlayerDB {
         true
     }
 }
+
+#[derive(ToSchema, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Game {
+    pub uuid: String,
+    pub name: String,
+    pub display_name: String,
+    pub modes: Vec<GameMode>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct GameMode {
+    pub mode: String,
+    pub display_name: String,
+    pub min_players: u32,
+    pub max_players: u32,
+    /// Players per team. Empty if every player plays for themselves.
+    pub team_sizes: Vec<u32>,
+    pub ranked: bool,
+    pub ai_allowed: bool,
+    pub ranking_max_stars: i32,
+    pub ranking_description: String,
+    pub ranking_performances: HashMap<String, i32>,
+}
+
+impl Game {
+    /// `game` with those of `modes` which belong to it.
+    pub fn new(game: GameDB, modes: &[GameModeDB]) -> Self {
+        Game {
+            modes: modes
+                .iter()
+                .filter(|mode| mode.game == game.name)
+                .cloned()
+                .map(GameMode::from)
+                .collect(),
+            uuid: game.uuid,
+            name: game.name,
+            display_name: game.display_name,
+        }
+    }
+}
+
+impl From<GameModeDB> for GameMode {
+    fn from(mode: GameModeDB) -> Self {
+        GameMode {
+            mode: mode.mode,
+            display_name: mode.display_name,
+            min_players: mode.min_players,
+            max_players: mode.max_players,
+            team_sizes: mode.team_sizes,
+            ranked: mode.ranked,
+            ai_allowed: mode.ai_allowed,
+            ranking_max_stars: mode.ranking_max_stars,
+            ranking_description: mode.ranking_description,
+            ranking_performances: mode.ranking_performances,
+        }
+    }
+}
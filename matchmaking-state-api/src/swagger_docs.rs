@@ -19,10 +19,14 @@ use crate::models;
 
         super::handler::game_servers::get_game_servers,
         super::handler::game_servers::get_game_server_by_uuid,
+        super::handler::game_servers::set_game_server_state,
 
         super::handler::host_requests::get_host_requests,
         super::handler::host_requests::get_host_request_by_uuid,
 
+        super::handler::games::get_games,
+        super::handler::games::get_game_by_name,
+
         super::handler::ai_players::get_ai_players,
         super::handler::ai_players::get_ai_player_by_uuid,
     ),
@@ -33,10 +37,27 @@ use crate::models;
             models::ActiveMatchFilter,
             models::GameServer,
             models::GameServerFilter,
+            models::GameServerStateUpdate,
+            models::Game,
+            models::GameMode,
             models::HostRequest,
             models::HostRequestFilter,
         )
     ),
+    modifiers(&SecurityAddon),
     tags((name = "Matchmaking State API", description = "Accessing matchmaking-state runtime-values")),
 )]
 pub struct ApiDoc;
+
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "admin_api_key",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        }
+    }
+}
@@ -3,13 +3,75 @@ use std::{collections::HashMap, time::SystemTime};
 use gn_redisadapter_derive::{
     RedisIdentifiable, RedisInsertWriter, RedisOutputReader, RedisUpdater,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "redis")]
-use gn_matchmaking_state::adapters::redis::RedisFilter;
+use gn_matchmaking_state::adapters::redis::{
+    Commands, Connection, Pipeline, RedisFilter, RedisInsertWriter, RedisOutputReader,
+};
+
+/// Where a game server is in its lifecycle. Only active servers get new matches.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerState {
+    #[default]
+    Active,
+    /// Takes no new matches, but keeps its active matches until they finish.
+    Draining,
+    /// Taken out of rotation by an administrator.
+    Maintenance,
+    /// Stopped sending health checks.
+    Dead,
+}
+
+impl ServerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Active => "active",
+            Self::Draining => "draining",
+            Self::Maintenance => "maintenance",
+            Self::Dead => "dead",
+        }
+    }
+}
+
+impl std::str::FromStr for ServerState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(Self::Active),
+            "draining" => Ok(Self::Draining),
+            "maintenance" => Ok(Self::Maintenance),
+            "dead" => Ok(Self::Dead),
+            _ => Err(format!("unknown server state {}", s)),
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+impl RedisInsertWriter for ServerState {
+    fn write(&self, pipe: &mut Pipeline, base_key: &str) -> Result<(), Box<dyn std::error::Error>> {
+        pipe.set(base_key, self.as_str());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "redis")]
+impl RedisOutputReader for ServerState {
+    /// Servers which were saved before they had a state are active.
+    fn read(conn: &mut Connection, base_key: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let state: Option<String> = conn.get(base_key)?;
+        match state {
+            Some(state) => Ok(state.parse()?),
+            None => Ok(Self::Active),
+        }
+    }
+}
 
-#[derive(Debug, Clone, PartialEq, Deserialize, RedisInsertWriter, RedisIdentifiable)]
-#[name("game_servers")]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "redis", derive(RedisInsertWriter, RedisIdentifiable))]
+#[cfg_attr(feature = "redis", name("game_servers"))]
 pub struct GameServer {
     pub region: String,
     pub game: String,
@@ -19,10 +81,26 @@ pub struct GameServer {
     pub healthy: bool,
     pub min_players: u32,
     pub max_players: u32,
+    /// Matches running on the server as of its last health check.
+    #[serde(default)]
+    pub active_matches: u32,
+    /// Matches the server can run at once. 0 if the server doesn't report it.
+    #[serde(default)]
+    pub capacity: u32,
+    /// CPU usage between 0 and 1.
+    #[serde(default)]
+    pub cpu_load: f32,
+    #[serde(default)]
+    pub latency_ms: u32,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub state: ServerState,
 }
 
-#[derive(Debug, Clone, RedisOutputReader, RedisIdentifiable)]
-#[name("game_servers")]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "redis", derive(RedisOutputReader, RedisIdentifiable))]
+#[cfg_attr(feature = "redis", name("game_servers"))]
 pub struct DBGameServer {
     #[cfg_attr(feature = "redis", uuid)]
     pub uuid: String,
@@ -34,6 +112,40 @@ pub struct DBGameServer {
     pub healthy: bool,
     pub min_players: u32,
     pub max_players: u32,
+    // Servers stored before these were reported read as idle
+    #[cfg_attr(feature = "redis", or_default)]
+    pub active_matches: u32,
+    #[cfg_attr(feature = "redis", or_default)]
+    pub capacity: u32,
+    #[cfg_attr(feature = "redis", or_default)]
+    pub cpu_load: f32,
+    #[cfg_attr(feature = "redis", or_default)]
+    pub latency_ms: u32,
+    #[cfg_attr(feature = "redis", or_default)]
+    pub version: String,
+    pub state: ServerState,
+}
+
+impl DBGameServer {
+    /// Whether the server can take another match. Servers which don't report a capacity always can.
+    pub fn has_capacity(&self) -> bool {
+        self.capacity == 0 || self.active_matches < self.capacity
+    }
+
+    /// Whether new matches may be placed on the server.
+    pub fn accepts_matches(&self) -> bool {
+        self.healthy && self.state == ServerState::Active && self.has_capacity()
+    }
+
+    /// How busy the server is between 0 and 1, the higher of its CPU usage and the share of its capacity in use.
+    pub fn load(&self) -> f32 {
+        let matches = if self.capacity == 0 {
+            0.0
+        } else {
+            self.active_matches as f32 / self.capacity as f32
+        };
+        matches.max(self.cpu_load).min(1.0)
+    }
 }
 
 impl PartialEq for DBGameServer {
@@ -42,8 +154,9 @@ impl PartialEq for DBGameServer {
     }
 }
 
-#[derive(Debug, Clone, Default, RedisUpdater)]
-#[name("game_servers")]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "redis", derive(RedisUpdater))]
+#[cfg_attr(feature = "redis", name("game_servers"))]
 pub struct GameServerUpdater {
     pub game: Option<String>,
     pub mode: Option<String>,
@@ -53,6 +166,12 @@ pub struct GameServerUpdater {
     pub healthy: Option<bool>,
     pub min_players: Option<u32>,
     pub max_players: Option<u32>,
+    pub active_matches: Option<u32>,
+    pub capacity: Option<u32>,
+    pub cpu_load: Option<f32>,
+    pub latency_ms: Option<u32>,
+    pub version: Option<String>,
+    pub state: Option<ServerState>,
 }
 
 #[derive(Debug, Default)]
@@ -129,6 +248,11 @@ pub struct Searcher {
     pub min_players: u32,
     pub max_players: u32,
     pub wait_start: SystemTime,
+    /// The other players of the party `player_id` leads, who are matched together with it. Empty for players
+    /// searching alone.
+    pub party_members: Vec<String>,
+    /// The player and their party members, so the matcher can count them without reading `party_members`.
+    pub party_size: u32,
 }
 
 #[derive(Debug, Clone, RedisUpdater)]
@@ -157,6 +281,17 @@ pub struct DBSearcher {
     pub min_players: u32,
     pub max_players: u32,
     pub wait_start: SystemTime,
+    pub party_members: Vec<String>,
+    // Searchers stored before parties existed search alone, which the matcher assumes for a missing size
+    #[cfg_attr(feature = "redis", or_default)]
+    pub party_size: u32,
+}
+
+impl DBSearcher {
+    /// The player and the members of their party.
+    pub fn players(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.player_id).chain(self.party_members.iter())
+    }
 }
 
 #[derive(Debug, Default)]
@@ -186,7 +321,14 @@ pub struct ActiveMatch {
     pub region: String,
     pub read: String,
     pub player_write: HashMap<String, String>,
-    pub chat_id: String
+    pub chat_id: String,
+    /// Whether every player joined and the game server started the match.
+    pub started: bool,
+    pub connected_players: Vec<String>,
+    /// Players who lost the connection and have not reconnected yet.
+    pub disconnected_players: Vec<String>,
+    /// Index of the team of every player. Empty if the mode is not played in teams.
+    pub teams: HashMap<String, u32>,
 }
 
 #[derive(Debug, Clone, RedisOutputReader, RedisIdentifiable)]
@@ -202,7 +344,46 @@ pub struct ActiveMatchDB {
     pub region: String,
     pub read: String,
     pub player_write: HashMap<String, String>,
-    pub chat_id: String
+    pub chat_id: String,
+    /// Whether every player joined and the game server started the match.
+    pub started: bool,
+    pub connected_players: Vec<String>,
+    /// Players who lost the connection and have not reconnected yet.
+    pub disconnected_players: Vec<String>,
+    /// Index of the team of every player. Empty if the mode is not played in teams.
+    pub teams: HashMap<String, u32>,
+}
+
+#[derive(Debug, Clone, Default, RedisUpdater)]
+#[name("active_matches")]
+pub struct ActiveMatchUpdater {
+    pub server_pub: Option<String>,
+    pub server_priv: Option<String>,
+    pub chat_id: Option<String>,
+    pub started: Option<bool>,
+    pub connected_players: Option<Vec<String>>,
+    pub disconnected_players: Option<Vec<String>>,
+}
+
+/// Keeps a player who left or broke a match from searching a game mode until `until`.
+#[derive(Debug, Clone, RedisInsertWriter, RedisIdentifiable)]
+#[name("leaver_penalties")]
+pub struct LeaverPenalty {
+    pub player_id: String,
+    pub game: String,
+    pub mode: String,
+    pub until: SystemTime,
+}
+
+#[derive(Debug, Clone, RedisOutputReader, RedisIdentifiable)]
+#[name("leaver_penalties")]
+pub struct LeaverPenaltyDB {
+    #[cfg_attr(feature = "redis", uuid)]
+    pub uuid: String,
+    pub player_id: String,
+    pub game: String,
+    pub mode: String,
+    pub until: SystemTime,
 }
 
 #[derive(Debug, Clone, RedisInsertWriter, RedisIdentifiable)]
@@ -225,13 +406,151 @@ pub struct AIPlayerDB {
     pub display_name: String,
 }
 
+/// Players who search together. Only the leader searches, for the whole party, which is matched as one unit.
+#[derive(Debug, Clone, RedisInsertWriter, RedisIdentifiable)]
+#[name("parties")]
+pub struct Party {
+    pub leader: String,
+    /// Every player of the party, including the leader.
+    pub members: Vec<String>,
+    /// Players the leader invited who have not accepted yet.
+    pub invited: Vec<String>,
+}
+
+#[derive(Debug, Clone, RedisOutputReader, RedisIdentifiable)]
+#[name("parties")]
+pub struct PartyDB {
+    #[uuid]
+    pub uuid: String,
+    pub leader: String,
+    pub members: Vec<String>,
+    pub invited: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, RedisUpdater)]
+#[name("parties")]
+pub struct PartyUpdate {
+    pub leader: Option<String>,
+    pub members: Option<Vec<String>>,
+    pub invited: Option<Vec<String>>,
+}
+
+/// A game players can search for. How it is played is described by its [`GameMode`]s.
+#[derive(Debug, Clone, RedisInsertWriter, RedisIdentifiable)]
+#[name("games")]
+pub struct Game {
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Debug, Clone, RedisOutputReader, RedisIdentifiable)]
+#[name("games")]
+pub struct GameDB {
+    #[uuid]
+    pub uuid: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+/// A mode of a [`Game`] and the rules matches of it are made by.
+#[derive(Debug, Clone, RedisInsertWriter, RedisIdentifiable)]
+#[name("game_modes")]
+pub struct GameMode {
+    pub game: String,
+    pub mode: String,
+    pub display_name: String,
+    pub min_players: u32,
+    pub max_players: u32,
+    /// Players per team, e.g. `[2, 2]` for 2v2. Empty if every player plays for themselves.
+    pub team_sizes: Vec<u32>,
+    /// Whether the results of the mode are reported to ranking.
+    pub ranked: bool,
+    /// Whether players may search for a match against an AI player.
+    pub ai_allowed: bool,
+    pub ranking_max_stars: i32,
+    pub ranking_description: String,
+    /// Weight of every performance a player can get in a match, by its name.
+    pub ranking_performances: HashMap<String, i32>,
+}
+
+#[derive(Debug, Clone, RedisOutputReader, RedisIdentifiable)]
+#[name("game_modes")]
+pub struct GameModeDB {
+    #[uuid]
+    pub uuid: String,
+    pub game: String,
+    pub mode: String,
+    pub display_name: String,
+    pub min_players: u32,
+    pub max_players: u32,
+    pub team_sizes: Vec<u32>,
+    pub ranked: bool,
+    pub ai_allowed: bool,
+    pub ranking_max_stars: i32,
+    pub ranking_description: String,
+    pub ranking_performances: HashMap<String, i32>,
+}
+
+impl GameModeDB {
+    /// Splits `parties` into the teams of the mode. Every party is given with the elo each of its players counts
+    /// with; a player searching alone is a party of one. Largest and strongest first, every party joins the team with
+    /// the lowest total elo which has room for all of it, so the totals of the teams end up close. The players of
+    /// parties which don't fit into any team are spread over the teams one by one.
+    ///
+    /// Returns no teams if the mode is not played in teams, and `None` if the players don't fit into them.
+    pub fn build_teams(&self, parties: &[(Vec<String>, u32)]) -> Option<Vec<Vec<String>>> {
+        if self.team_sizes.is_empty() {
+            return Some(Vec::new());
+        }
+        let players: usize = parties.iter().map(|(party, _)| party.len()).sum();
+        if players as u32 > self.team_sizes.iter().sum::<u32>() {
+            return None;
+        }
+
+        let mut parties = parties.to_vec();
+        parties.sort_by_key(|p| std::cmp::Reverse((p.0.len(), p.1)));
+
+        let mut teams: Vec<(Vec<String>, u32)> = vec![(Vec::new(), 0); self.team_sizes.len()];
+        let mut split = Vec::new();
+        for (party, elo) in parties {
+            let team = teams
+                .iter_mut()
+                .zip(self.team_sizes.iter())
+                .filter(|((team, _), size)| team.len() + party.len() <= **size as usize)
+                .map(|(team, _)| team)
+                .min_by_key(|(_, total)| *total);
+            match team {
+                Some((team, total)) => {
+                    *total += elo * party.len() as u32;
+                    team.extend(party);
+                }
+                None => split.extend(party.into_iter().map(|player| (player, elo))),
+            }
+        }
+        for (player, elo) in split {
+            let (team, total) = teams
+                .iter_mut()
+                .zip(self.team_sizes.iter())
+                .filter(|((team, _), size)| (team.len() as u32) < **size)
+                .map(|(team, _)| team)
+                .min_by_key(|(_, total)| *total)?;
+            team.push(player);
+            *total += elo;
+        }
+        Some(teams.into_iter().map(|(team, _)| team).collect())
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "redis")]
     use std::error::Error;
 
+    #[cfg(feature = "redis")]
     use gn_matchmaking_state::adapters::{Gettable, Insertable, Removable, Updateable};
 
     #[test]
+    #[cfg(feature = "redis")]
 
     fn test_redis_adapter_insert_game_server() {
         use super::*;
@@ -252,11 +571,18 @@ mod tests {
             healthy: true,
             min_players: 2,
             max_players: 2,
+            active_matches: 0,
+            capacity: 4,
+            cpu_load: 0.1,
+            latency_ms: 20,
+            version: "1.0.0".to_owned(),
+            state: ServerState::Active,
         };
         adapter.insert(game_server).unwrap();
     }
 
     #[test]
+    #[cfg(feature = "redis")]
     fn test_redis_adapter_all_game_server() {
         use super::*;
         use gn_matchmaking_state::adapters::redis::publisher::native::RedisInfoPublisher;
@@ -275,6 +601,12 @@ mod tests {
             healthy: true,
             min_players: 2,
             max_players: 2,
+            active_matches: 0,
+            capacity: 4,
+            cpu_load: 0.1,
+            latency_ms: 20,
+            version: "1.0.0".to_owned(),
+            state: ServerState::Active,
         };
         adapter.insert(game_server.clone()).unwrap();
 
@@ -289,6 +621,66 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "redis")]
+    fn test_redis_adapter_read_old_game_server() {
+        use super::*;
+        use gn_matchmaking_state::adapters::redis::publisher::native::RedisInfoPublisher;
+        use gn_matchmaking_state::adapters::redis::RedisAdapter;
+
+        let adapter = RedisAdapter::connect("redis://0.0.0.0:6379").unwrap();
+        let mut connection = adapter.client.get_connection().unwrap();
+        let publisher = RedisInfoPublisher::new(adapter.client.get_connection().unwrap());
+        let adapter = adapter.with_publisher(publisher);
+
+        let game_server = GameServer {
+            region: "eu".to_owned(),
+            game: "Old Test Server".to_owned(),
+            mode: "Test Mode".to_owned(),
+            server_pub: "127.0.0.1:3456".to_owned(),
+            server_priv: "127.0.0.1:3456".to_owned(),
+            healthy: true,
+            min_players: 2,
+            max_players: 2,
+            active_matches: 1,
+            capacity: 4,
+            cpu_load: 0.1,
+            latency_ms: 20,
+            version: "1.0.0".to_owned(),
+            state: ServerState::Draining,
+        };
+        let uuid = adapter.insert(game_server).unwrap();
+        // Written before the load and state were stored
+        for field in [
+            "active_matches",
+            "capacity",
+            "cpu_load",
+            "latency_ms",
+            "version",
+            "state",
+        ] {
+            connection
+                .del::<_, ()>(format!("{}:{}", uuid, field))
+                .unwrap();
+        }
+
+        let found: DBGameServer = adapter.get(&uuid).unwrap();
+        assert_eq!(found.game, "Old Test Server");
+        assert_eq!(found.active_matches, 0);
+        assert_eq!(found.capacity, 0);
+        assert_eq!(found.cpu_load, 0.0);
+        assert_eq!(found.latency_ms, 0);
+        assert_eq!(found.version, "");
+        assert_eq!(found.state, ServerState::Active);
+        assert!(found.accepts_matches());
+
+        let found_servers = adapter.all().unwrap().collect::<Vec<DBGameServer>>();
+        assert!(found_servers.iter().any(|x| x.uuid == uuid));
+
+        adapter.remove(&uuid).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "redis")]
     fn test_redis_adapter_remove_game_server() {
         use super::*;
         use gn_matchmaking_state::adapters::redis::publisher::native::RedisInfoPublisher;
@@ -307,6 +699,12 @@ mod tests {
             healthy: true,
             min_players: 2,
             max_players: 2,
+            active_matches: 0,
+            capacity: 4,
+            cpu_load: 0.1,
+            latency_ms: 20,
+            version: "1.0.0".to_owned(),
+            state: ServerState::Active,
         };
         let uuid = adapter.insert(game_server.clone()).unwrap();
 
@@ -317,6 +715,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "redis")]
     fn test_redis_adapter_update_game_server() {
         use super::*;
         use gn_matchmaking_state::adapters::redis::publisher::native::RedisInfoPublisher;
@@ -335,6 +734,12 @@ mod tests {
             healthy: true,
             min_players: 2,
             max_players: 2,
+            active_matches: 0,
+            capacity: 4,
+            cpu_load: 0.1,
+            latency_ms: 20,
+            version: "1.0.0".to_owned(),
+            state: ServerState::Active,
         };
         let uuid = adapter.insert(game_server.clone()).unwrap();
 
@@ -346,4 +751,101 @@ mod tests {
 
         assert!(result.game == "CSS Battle (Cum Sum Sus Battle)");
     }
+
+    #[test]
+    fn test_game_server_load() {
+        use super::*;
+
+        let mut server = DBGameServer {
+            uuid: "1".to_owned(),
+            region: "eu".to_owned(),
+            game: "Test Server".to_owned(),
+            mode: "Test Mode".to_owned(),
+            server_pub: "127.0.0.1:3456".to_owned(),
+            server_priv: "127.0.0.1:3456".to_owned(),
+            healthy: true,
+            min_players: 2,
+            max_players: 2,
+            active_matches: 3,
+            capacity: 4,
+            cpu_load: 0.5,
+            latency_ms: 20,
+            version: "1.0.0".to_owned(),
+            state: ServerState::Active,
+        };
+        assert!(server.has_capacity());
+        assert!(server.accepts_matches());
+        assert_eq!(server.load(), 0.75);
+
+        server.state = ServerState::Draining;
+        assert!(!server.accepts_matches());
+        server.state = ServerState::Active;
+
+        server.active_matches = 4;
+        assert!(!server.has_capacity());
+        assert!(!server.accepts_matches());
+
+        server.capacity = 0;
+        assert!(server.has_capacity());
+        assert_eq!(server.load(), 0.5);
+    }
+
+    #[test]
+    fn test_build_teams() {
+        use super::*;
+
+        let mut mode = GameModeDB {
+            uuid: "1".to_owned(),
+            game: "Test Game".to_owned(),
+            mode: "2v2".to_owned(),
+            display_name: "2 vs 2".to_owned(),
+            min_players: 4,
+            max_players: 4,
+            team_sizes: vec![2, 2],
+            ranked: true,
+            ai_allowed: false,
+            ranking_max_stars: 5,
+            ranking_description: String::new(),
+            ranking_performances: HashMap::new(),
+        };
+        let party = |players: &[&str], elo: u32| {
+            (players.iter().map(|player| player.to_string()).collect(), elo)
+        };
+        let solos = vec![
+            party(&["a"], 1500),
+            party(&["b"], 1000),
+            party(&["c"], 1400),
+            party(&["d"], 1200),
+        ];
+
+        let teams = mode.build_teams(&solos).unwrap();
+        assert_eq!(teams, vec![vec!["a", "b"], vec!["c", "d"]]);
+        assert!(mode.build_teams(&solos[..3]).is_some());
+
+        let teams = mode
+            .build_teams(&[party(&["c"], 1400), party(&["a", "b"], 1000), party(&["d"], 1200)])
+            .unwrap();
+        assert_eq!(teams, vec![vec!["a", "b"], vec!["c", "d"]]);
+
+        let teams = mode
+            .build_teams(&[party(&["a", "b", "c"], 1000), party(&["d"], 1200)])
+            .unwrap();
+        assert_eq!(teams.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 2]);
+
+        // AI players are parties of one with their own elo
+        let teams = mode
+            .build_teams(&[
+                party(&["a"], 1500),
+                party(&["Easy Bot"], 800),
+                party(&["Hard Bot"], 1600),
+                party(&["b"], 1000),
+            ])
+            .unwrap();
+        assert_eq!(teams, vec![vec!["Hard Bot", "Easy Bot"], vec!["a", "b"]]);
+
+        mode.team_sizes = vec![1, 1];
+        assert!(mode.build_teams(&solos).is_none());
+        mode.team_sizes = Vec::new();
+        assert!(mode.build_teams(&solos).unwrap().is_empty());
+    }
 }
@@ -32,7 +32,9 @@ pub fn insert_writer_derive(input: TokenStream) -> TokenStream {
     impl_insert_writer(&ast)
 }
 
-#[proc_macro_derive(RedisOutputReader, attributes(uuid))]
+/// Fields marked with `#[or_default]` are read as their default if they are not stored, e.g. because the entry was
+/// written before the field existed. Only supported for fields stored under a single key.
+#[proc_macro_derive(RedisOutputReader, attributes(uuid, or_default))]
 pub fn output_reader_derive(input: TokenStream) -> TokenStream {
     let ast = syn::parse(input).unwrap();
     insert_new_struct(&ast, ImplType::OutputReader);
@@ -126,6 +128,17 @@ fn impl_output_reader(ast: &syn::DeriveInput) -> TokenStream {
         .map(|field| {
             let field_name = field.ident.as_ref().unwrap();
             let ty = &field.ty;
+            if field.attrs.iter().any(|x| x.path.is_ident("or_default")) {
+                return quote! {
+                    #field_name: {
+                        let key = format!("{base_key}:{}", stringify!(#field_name));
+                        match gn_matchmaking_state::adapters::redis::Commands::exists(connection, &key)? {
+                            true => <#ty as gn_matchmaking_state::adapters::redis::RedisOutputReader>::read(connection, &key)?,
+                            false => <#ty as Default>::default(),
+                        }
+                    }
+                };
+            }
             quote! {
                 #field_name: <#ty as gn_matchmaking_state::adapters::redis::RedisOutputReader>::read(connection, &format!("{base_key}:{}", stringify!(#field_name)))?
             }
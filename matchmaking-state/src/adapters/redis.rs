@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap, future::Future, sync::{Arc, Mutex}
+    collections::HashMap, future::Future, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration
 };
 
 use crate::models::Match;
@@ -9,8 +9,9 @@ use super::{
     Updateable,
 };
 pub use redis::{Commands, Connection, FromRedisValue, Msg, Pipeline, PubSub, ToRedisArgs};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
+mod instrument;
 mod io;
 pub mod publisher;
 
@@ -41,8 +42,12 @@ pub struct RedisAdapter<I> {
     connection: Arc<Mutex<redis::Connection>>,
     publisher: Option<Arc<Mutex<dyn InfoPublisher<I> + Send + Sync>>>,
     handlers: Arc<Mutex<Vec<Arc<dyn Send + Sync + 'static + Fn(Match) -> ()>>>>,
+    match_check_stopped: Arc<AtomicBool>,
 }
 
+/// Interval in which a running match check looks whether it should stop.
+const MATCH_CHECK_POLL: Duration = Duration::from_secs(1);
+
 impl<I> From<redis::Client> for RedisAdapter<I> {
     fn from(client: redis::Client) -> Self {
         let connection =
@@ -55,6 +60,7 @@ impl<I> From<redis::Client> for RedisAdapter<I> {
             publisher: None,
             handlers: Arc::new(Mutex::new(Vec::new())),
             auto_delete: None,
+            match_check_stopped: Arc::new(AtomicBool::new(false)),
         }
     }
 }
@@ -68,6 +74,7 @@ impl<I> Clone for RedisAdapter<I> {
             client,
             handlers: self.handlers.clone(),
             auto_delete: self.auto_delete,
+            match_check_stopped: self.match_check_stopped.clone(),
         }
     }
 }
@@ -111,6 +118,14 @@ where
         Ok(self.client.get_connection()?)
     }
 
+    /// Checks whether the redis server is reachable by sending a `PING` over a new connection.
+    pub fn ping(&self, timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
+        let mut connection = self.client.get_connection_with_timeout(timeout)?;
+        connection.set_read_timeout(Some(timeout))?;
+        redis::cmd("PING").query::<String>(&mut connection)?;
+        Ok(())
+    }
+
     /// Starts the match check in a new task. Creates a new seperate connection to the redis server.
     ///
     /// # Returns
@@ -127,12 +142,11 @@ where
     ///
     /// # Returns
     ///
-    /// A `Result` with the error if any occured. Under normal conditions this function will not exit and therefore the result should be `!`.
-    /// This is currently an experimental feature in Rust and therefore not implemented here yet.
+    /// A `Result` with the error if any occured. Under normal conditions this function only returns after `stop_match_check` was called.
     pub fn match_check(self) -> Result<(), Box<dyn std::error::Error>> {
-        // NOTE: Result should be '!' for Ok values. This is currently expermintal tough and therefore not implemented here.
         let mut connection = self.client.get_connection()?;
         let mut connection = connection.as_pubsub();
+        connection.set_read_timeout(Some(MATCH_CHECK_POLL))?;
 
         connection.psubscribe("*:match:*")?;
         info!("Subscribed to match events");
@@ -144,11 +158,135 @@ where
         let mut match_proposal = MatchProposal::default();
 
         // TODO: Multithread this as soon as the problem with the order of messages is fixed.
-        loop {
-            let msg = connection.get_message().unwrap();
+        while !self.match_check_stopped.load(Ordering::SeqCst) {
+            let msg = match connection.get_message() {
+                Ok(msg) => msg,
+                Err(err) if err.is_timeout() => continue,
+                Err(err) => return Err(err.into()),
+            };
             info!("Message received: {:?}", msg);
             self.handle_msg(msg, &mut match_proposal);
         }
+        info!("Match check stopped");
+        Ok(())
+    }
+
+    /// Stops the match check of this adapter and all of its clones. Matches which are already being handled are not affected.
+    /// The match check returns within a second after this was called.
+    pub fn stop_match_check(&self) {
+        self.match_check_stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Sets `key` if it is not set yet. The key expires after `ttl`.
+    ///
+    /// # Returns
+    ///
+    /// Whether the key was set by this call, i.e. whether nobody else claimed `key` within `ttl`.
+    pub fn claim(&self, key: &str, ttl: Duration) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut connection = self.connection.lock().unwrap();
+        let set: Option<String> = redis::cmd("SET")
+            .arg(key)
+            .arg(1)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query(&mut *connection)?;
+        Ok(set.is_some())
+    }
+
+    /// Deletes `key`, e.g. one set by [`RedisAdapter::claim`] so it can be claimed again.
+    pub fn release(&self, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.connection.lock().unwrap().del::<_, ()>(key)?;
+        Ok(())
+    }
+
+    /// Sets `key` to `value`, replacing what it was set to before. The key expires after `ttl`.
+    pub fn set_expiring(
+        &self,
+        key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.connection
+            .lock()
+            .unwrap()
+            .pset_ex::<_, _, ()>(key, value, ttl.as_millis() as u64)?;
+        Ok(())
+    }
+
+    /// The value of `key`. `None` if it is not set or expired.
+    pub fn get_value(&self, key: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        Ok(self.connection.lock().unwrap().get(key)?)
+    }
+
+    /// Sets `field` of the hash `key` to `value`.
+    pub fn hash_set(
+        &self,
+        key: &str,
+        field: &str,
+        value: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.connection
+            .lock()
+            .unwrap()
+            .hset::<_, _, _, ()>(key, field, value)?;
+        Ok(())
+    }
+
+    /// Removes `field` from the hash `key`.
+    pub fn hash_delete(&self, key: &str, field: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.connection.lock().unwrap().hdel::<_, _, ()>(key, field)?;
+        Ok(())
+    }
+
+    /// All values of the hash `key`. Empty if the hash does not exist.
+    pub fn hash_values(&self, key: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(self.connection.lock().unwrap().hvals(key)?)
+    }
+
+    /// Increments the number at `key` by one, unless it reached the number at `limit_key` already. A missing or zero
+    /// limit doesn't limit anything. The check and the increment are atomic, so concurrent callers never exceed the
+    /// limit together.
+    ///
+    /// # Returns
+    ///
+    /// Whether `key` was incremented.
+    pub fn increment_below(
+        &self,
+        key: &str,
+        limit_key: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let script = redis::Script::new(
+            r"
+            local limit = tonumber(redis.call('GET', KEYS[2]) or 0)
+            if limit > 0 and tonumber(redis.call('GET', KEYS[1]) or 0) >= limit then
+                return 0
+            end
+            redis.call('INCR', KEYS[1])
+            return 1
+            ",
+        );
+        let incremented: i32 = script
+            .key(key)
+            .key(limit_key)
+            .invoke(&mut *self.connection.lock().unwrap())?;
+        Ok(incremented == 1)
+    }
+
+    /// Decrements the number at `key` by one, unless it is zero or missing already. Gives back what
+    /// [`RedisAdapter::increment_below`] took.
+    pub fn decrement_to_zero(&self, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let script = redis::Script::new(
+            r"
+            if tonumber(redis.call('GET', KEYS[1]) or 0) > 0 then
+                redis.call('DECR', KEYS[1])
+            end
+            ",
+        );
+        script
+            .key(key)
+            .invoke::<()>(&mut *self.connection.lock().unwrap())?;
+        Ok(())
     }
 
     fn handle_msg(&mut self, msg: Msg, match_proposal: &mut MatchProposal) {
@@ -324,26 +462,28 @@ where
     std::string::String: Publishable<I>,
 {
     fn remove(&self, uuid: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let mut connection = self.connection.lock().unwrap();
-        let iter = connection
-            .scan_match(format!("{}*", uuid))?
-            .into_iter()
-            .collect::<Vec<String>>();
-
-        redis::transaction::<_, _, (), _>(&mut connection, iter.as_slice(), |conn, pipe| {
-            iter.iter().for_each(|key| {
-                pipe.del(key).ignore();
-            });
-            pipe.query(conn)
-        })?;
-
-        if let Some(publisher) = self.publisher.as_ref() {
-            publisher
-                .lock()
-                .unwrap()
-                .publish(&uuid.to_string(), format!("remove:{uuid}"))?;
-        }
-        Ok(())
+        instrument::record("remove", instrument::entity_of(uuid), || {
+            let mut connection = self.connection.lock().unwrap();
+            let iter = connection
+                .scan_match(format!("{}*", uuid))?
+                .into_iter()
+                .collect::<Vec<String>>();
+
+            redis::transaction::<_, _, (), _>(&mut connection, iter.as_slice(), |conn, pipe| {
+                iter.iter().for_each(|key| {
+                    pipe.del(key).ignore();
+                });
+                pipe.query(conn)
+            })?;
+
+            if let Some(publisher) = self.publisher.as_ref() {
+                publisher
+                    .lock()
+                    .unwrap()
+                    .publish(&uuid.to_string(), format!("remove:{uuid}"))?;
+            }
+            Ok(())
+        })
     }
 }
 
@@ -353,43 +493,57 @@ where
     std::string::String: Publishable<I>,
 {
     fn insert(&self, data: T) -> Result<String, Box<dyn std::error::Error>> {
-        let key = { T::next_uuid(&mut self.connection.lock().unwrap())? };
+        instrument::record("insert", T::name(), || {
+            let key = { T::next_uuid(&mut self.connection.lock().unwrap())? };
 
-        let mut pipe = redis::pipe();
-        pipe.atomic();
-        data.write(&mut pipe, &key)?;
-        pipe.set(key.clone(), "");
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            data.write(&mut pipe, &key)?;
+            pipe.set(key.clone(), "");
 
-        if let Some(auto_delete) = self.auto_delete {
-            pipe.expire(key.clone(), auto_delete);
-            data.expire(&mut pipe, &key, auto_delete)?;
-        }
+            if let Some(auto_delete) = self.auto_delete {
+                pipe.expire(key.clone(), auto_delete);
+                data.expire(&mut pipe, &key, auto_delete)?;
+            }
 
-        'query: {
-            let mut connection = self.connection.lock().unwrap();
-            pipe.query::<()>(&mut connection)?;
+            'query: {
+                let mut connection = self.connection.lock().unwrap();
+                pipe.query::<()>(&mut connection)?;
 
-            if self.publisher.is_none() {
-                break 'query;
-            }
+                if self.publisher.is_none() {
+                    break 'query;
+                }
 
-            self.publisher
-                .as_ref()
-                .unwrap()
-                .lock()
-                .unwrap()
-                .publish(&key, format!("insert:{key}"))?;
-        }
+                self.publisher
+                    .as_ref()
+                    .unwrap()
+                    .lock()
+                    .unwrap()
+                    .publish(&key, format!("insert:{key}"))?;
+            }
 
-        let mut split = key.split(":");
-        Ok(split
-            .next()
-            .expect(format!("Invalid id on object of type {}", T::name()).as_str())
-            .to_string()
-            + ":"
-            + split
+            let mut split = key.split(":");
+            Ok(split
                 .next()
-                .expect(format!("Invalid id on object of type {}", T::name()).as_str()))
+                .expect(format!("Invalid id on object of type {}", T::name()).as_str())
+                .to_string()
+                + ":"
+                + split
+                    .next()
+                    .expect(format!("Invalid id on object of type {}", T::name()).as_str()))
+        })
+    }
+}
+
+/// Reads the entry at `key`, or `None` if it can't be read, e.g. because it was removed after it was found.
+/// Unreadable entries are skipped so they don't hide the entries after them.
+fn read_or_skip<O: RedisOutputReader>(connection: &mut Connection, key: &str) -> Option<O> {
+    match O::read(connection, key) {
+        Ok(res) => Some(res),
+        Err(err) => {
+            warn!("Skipping unreadable entry {}: {}", key, err);
+            None
+        }
     }
 }
 
@@ -400,28 +554,28 @@ where
     type Type = Box<dyn Iterator<Item = O> + 'a>;
 
     fn all(&'a self) -> Result<Self::Type, Box<dyn std::error::Error>> {
-        let mut iter = self
-            .connection
-            .lock()
-            .unwrap()
-            .scan_match(format!("*:{}", O::name()))?
-            .collect::<Vec<String>>()
-            .into_iter();
+        let mut iter = instrument::record("all", O::name(), || {
+            Ok(self
+                .connection
+                .lock()
+                .unwrap()
+                .scan_match(format!("*:{}", O::name()))?
+                .collect::<Vec<String>>()
+                .into_iter())
+        })?;
 
         let connection_ref = self.connection.clone();
         let iter_fun = std::iter::from_fn(move || {
-            if let Some(key) = iter.next() {
-                let res = O::read(&mut connection_ref.lock().unwrap(), &key).ok()?;
-                return Some(res);
-            }
-            None
+            iter.find_map(|key| read_or_skip(&mut connection_ref.lock().unwrap(), &key))
         });
 
         Ok(Box::new(iter_fun))
     }
 
     fn get(&self, uuid: &str) -> Result<O, Box<dyn std::error::Error>> {
-        O::read(&mut self.connection.lock().unwrap(), uuid)
+        instrument::record("get", O::name(), || {
+            O::read(&mut self.connection.lock().unwrap(), uuid)
+        })
     }
 }
 
@@ -433,23 +587,22 @@ where
     type Type = Box<dyn Iterator<Item = O> + 'a>;
 
     fn filter(&'a self, filter: F) -> Result<Self::Type, Box<dyn std::error::Error>> {
-        let mut iter = self
-            .connection
-            .lock()
-            .unwrap()
-            .scan_match(format!("*:{}", O::name()))?
-            .collect::<Vec<String>>()
-            .into_iter();
+        let mut iter = instrument::record("filter", O::name(), || {
+            Ok(self
+                .connection
+                .lock()
+                .unwrap()
+                .scan_match(format!("*:{}", O::name()))?
+                .collect::<Vec<String>>()
+                .into_iter())
+        })?;
 
         let connection_ref = self.connection.clone();
         let iter = std::iter::from_fn(move || {
-            while let Some(key) = iter.next() {
-                let res = O::read(&mut connection_ref.lock().unwrap(), &key).ok()?;
-                if filter.is_ok(&res) {
-                    return Some(res);
-                }
-            }
-            None
+            iter.find_map(|key| {
+                read_or_skip::<O>(&mut connection_ref.lock().unwrap(), &key)
+                    .filter(|res| filter.is_ok(res))
+            })
         });
 
         Ok(Box::new(iter))
@@ -462,20 +615,22 @@ where
     std::string::String: Publishable<I>,
 {
     fn update(&self, uuid: &str, data: U) -> Result<(), Box<dyn std::error::Error>> {
-        let mut pipe = redis::pipe();
-        pipe.atomic();
-        data.clone().update(&mut pipe, uuid)?;
+        instrument::record("update", instrument::entity_of(uuid), || {
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            data.clone().update(&mut pipe, uuid)?;
 
-        let mut connection = self.connection.lock().unwrap();
-        pipe.query::<()>(&mut connection)?;
+            let mut connection = self.connection.lock().unwrap();
+            pipe.query::<()>(&mut connection)?;
 
-        if let Some(publisher) = self.publisher.as_ref() {
-            publisher
-                .lock()
-                .unwrap()
-                .publish(&uuid.to_string(), format!("update:{uuid}"))?;
-        }
-        Ok(())
+            if let Some(publisher) = self.publisher.as_ref() {
+                publisher
+                    .lock()
+                    .unwrap()
+                    .publish(&uuid.to_string(), format!("update:{uuid}"))?;
+            }
+            Ok(())
+        })
     }
 }
 
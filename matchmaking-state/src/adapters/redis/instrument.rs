@@ -0,0 +1,29 @@
+use std::time::Instant;
+
+use metrics::{counter, histogram};
+
+pub(super) const OP_DURATION: &str = "matchmaking_state_op_duration_seconds";
+pub(super) const OP_ERRORS: &str = "matchmaking_state_op_errors_total";
+
+/// Extracts the entity name out of a uuid in the format `<counter>:<entity>[:<field>...]`.
+#[inline]
+pub(super) fn entity_of(uuid: &str) -> String {
+    uuid.split(':').nth(1).unwrap_or("unknown").to_owned()
+}
+
+/// Runs `op` and records its latency and, if it failed, an error for the given entity.
+pub(super) fn record<R>(
+    op: &'static str,
+    entity: String,
+    f: impl FnOnce() -> Result<R, Box<dyn std::error::Error>>,
+) -> Result<R, Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    let result = f();
+
+    histogram!(OP_DURATION, "op" => op, "entity" => entity.clone())
+        .record(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        counter!(OP_ERRORS, "op" => op, "entity" => entity).increment(1);
+    }
+    result
+}
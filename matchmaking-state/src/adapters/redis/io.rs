@@ -26,6 +26,8 @@ where
         for (i, mode) in self.iter().enumerate() {
             mode.write(pipe, format!("{base_key}:{}", i).as_str())?;
         }
+        // Lists are read up to the first missing index, so this ends a list which was longer before
+        pipe.del(format!("{base_key}:{}", self.len()));
         Ok(())
     }
 }
@@ -0,0 +1,116 @@
+use std::{fmt, path::Path};
+
+use ::config::{Config, Environment, File, FileFormat};
+use serde::de::DeserializeOwned;
+
+/// Environment variable pointing to the configuration file of a service.
+pub const CONFIG_FILE_ENV: &str = "CONFIG_FILE";
+/// File which is read if `CONFIG_FILE` is not set. It is allowed to be missing.
+pub const DEFAULT_CONFIG_FILE: &str = "config.yml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The sources could not be read or a value has the wrong type / is missing.
+    Load(::config::ConfigError),
+    /// A value was read but does not pass validation.
+    Invalid { field: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Load(err) => write!(f, "{err}"),
+            Self::Invalid { field, reason } => write!(f, "invalid value for `{field}`: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<::config::ConfigError> for ConfigError {
+    fn from(err: ::config::ConfigError) -> Self {
+        Self::Load(err)
+    }
+}
+
+/// Checks a loaded configuration for values which deserialize but can't be used.
+pub trait Validate {
+    fn validate(&self) -> Result<(), ConfigError> {
+        Ok(())
+    }
+}
+
+/// Fails if `value` is not an url with one of the given `schemes`.
+pub fn require_url(field: &'static str, value: &str, schemes: &[&str]) -> Result<(), ConfigError> {
+    match value.split_once("://") {
+        Some((scheme, rest)) if schemes.contains(&scheme) && !rest.is_empty() => Ok(()),
+        _ => Err(ConfigError::Invalid {
+            field,
+            reason: format!(
+                "'{value}' is not a valid url, expected one of the schemes {}",
+                schemes.join(", ")
+            ),
+        }),
+    }
+}
+
+/// Fails if `path` does not point to an existing file.
+pub fn require_file(field: &'static str, path: &str) -> Result<(), ConfigError> {
+    if Path::new(path).is_file() {
+        return Ok(());
+    }
+    Err(ConfigError::Invalid {
+        field,
+        reason: format!("file '{path}' does not exist"),
+    })
+}
+
+/// Loads typed configuration of a service.
+///
+/// Values are read from the YAML file given by `CONFIG_FILE` (or `config.yml` if present) and are overridden by
+/// environment variables. Keys are the lowercase names of the environment variables, e.g. `REDIS_URL` sets `redis_url`.
+pub struct ConfigLoader {
+    service_name: String,
+}
+
+impl ConfigLoader {
+    pub fn new(service_name: &str) -> Self {
+        Self {
+            service_name: service_name.to_owned(),
+        }
+    }
+
+    fn source(&self) -> Result<Config, ConfigError> {
+        let file = match std::env::var(CONFIG_FILE_ENV) {
+            Ok(path) => File::new(&path, FileFormat::Yaml).required(true),
+            Err(_) => File::new(DEFAULT_CONFIG_FILE, FileFormat::Yaml).required(false),
+        };
+
+        Ok(Config::builder()
+            .add_source(file)
+            .add_source(Environment::default().try_parsing(true))
+            .build()?)
+    }
+
+    /// Loads and validates the configuration.
+    pub fn load<T>(&self) -> Result<T, ConfigError>
+    where
+        T: DeserializeOwned + Validate,
+    {
+        let config: T = self.source()?.try_deserialize()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Loads and validates the configuration. Prints the error and exits the process if this is not possible,
+    /// as a service can't start without valid configuration.
+    pub fn load_or_exit<T>(&self) -> T
+    where
+        T: DeserializeOwned + Validate,
+    {
+        self.load().unwrap_or_else(|err| {
+            eprintln!("Invalid configuration of {}: {}", self.service_name, err);
+            std::process::exit(1);
+        })
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use axum::{http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use serde::Serialize;
+
+use crate::shutdown::Shutdown;
+
+type Check = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Unavailable,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Result of all checks of a probe, served by `/healthz` and `/readyz`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub status: Status,
+    pub checks: BTreeMap<String, CheckResult>,
+}
+
+impl Report {
+    fn from_checks<'a>(checks: impl Iterator<Item = (&'a String, &'a Check)>) -> Self {
+        let checks: BTreeMap<_, _> = checks
+            .map(|(name, check)| {
+                let result = match check() {
+                    Ok(()) => CheckResult {
+                        status: Status::Ok,
+                        error: None,
+                    },
+                    Err(error) => CheckResult {
+                        status: Status::Unavailable,
+                        error: Some(error),
+                    },
+                };
+                (name.clone(), result)
+            })
+            .collect();
+
+        let status = if checks.values().all(|x| x.status == Status::Ok) {
+            Status::Ok
+        } else {
+            Status::Unavailable
+        };
+        Self { status, checks }
+    }
+
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.status == Status::Ok
+    }
+}
+
+/// Collects the liveness and readiness checks of a service.
+///
+/// Liveness checks report whether the service is still working, e.g. whether its background loops are running.
+/// Readiness checks report whether it can currently do its work, e.g. whether its dependencies are reachable.
+/// A service is only ready if it is alive and not shutting down.
+///
+/// Checks are called on every request and may block, so they should not take longer than about a second.
+#[derive(Clone)]
+pub struct Health {
+    liveness: Arc<RwLock<BTreeMap<String, Check>>>,
+    readiness: Arc<RwLock<BTreeMap<String, Check>>>,
+    shutdown: Shutdown,
+}
+
+impl Health {
+    pub fn new(shutdown: Shutdown) -> Self {
+        Self {
+            liveness: Default::default(),
+            readiness: Default::default(),
+            shutdown,
+        }
+    }
+
+    /// Adds a liveness check. A check with the same name is replaced.
+    pub fn add_liveness<F>(&self, name: &str, check: F)
+    where
+        F: Fn() -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.liveness
+            .write()
+            .unwrap()
+            .insert(name.to_owned(), Arc::new(check));
+    }
+
+    /// Adds a readiness check. A check with the same name is replaced.
+    pub fn add_readiness<F>(&self, name: &str, check: F)
+    where
+        F: Fn() -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.readiness
+            .write()
+            .unwrap()
+            .insert(name.to_owned(), Arc::new(check));
+    }
+
+    /// Adds a liveness check for a loop which is alive as long as it calls [`Heartbeat::beat`] at least every `max_age`.
+    pub fn heartbeat(&self, name: &str, max_age: Duration) -> Heartbeat {
+        let heartbeat = Heartbeat {
+            last: Arc::new(Mutex::new(Instant::now())),
+        };
+
+        let last = heartbeat.last.clone();
+        self.add_liveness(name, move || {
+            let age = last.lock().unwrap().elapsed();
+            if age > max_age {
+                return Err(format!("no heartbeat for {:.1}s", age.as_secs_f64()));
+            }
+            Ok(())
+        });
+        heartbeat
+    }
+
+    /// Adds a liveness check for a background task which is alive as long as it did not finish.
+    pub fn add_task<T>(&self, name: &str, task: &tokio::task::JoinHandle<T>) {
+        let task = task.abort_handle();
+        self.add_liveness(name, move || match task.is_finished() {
+            true => Err("task stopped".to_owned()),
+            false => Ok(()),
+        });
+    }
+
+    pub fn liveness(&self) -> Report {
+        Report::from_checks(self.liveness.read().unwrap().iter())
+    }
+
+    pub fn readiness(&self) -> Report {
+        let liveness = self.liveness.read().unwrap();
+        let readiness = self.readiness.read().unwrap();
+
+        let shutdown = self.shutdown.clone();
+        let shutdown_check: (String, Check) = (
+            "shutdown".to_owned(),
+            Arc::new(move || match shutdown.is_triggered() {
+                true => Err("shutting down".to_owned()),
+                false => Ok(()),
+            }),
+        );
+
+        Report::from_checks(
+            liveness
+                .iter()
+                .chain(readiness.iter())
+                .chain(std::iter::once((&shutdown_check.0, &shutdown_check.1))),
+        )
+    }
+
+    /// Router serving `/healthz` (liveness) and `/readyz` (readiness). Both respond with `503` if a check fails.
+    pub fn router(&self) -> Router {
+        let liveness = self.clone();
+        let readiness = self.clone();
+        Router::new()
+            .route(
+                "/healthz",
+                get(move || respond(liveness.clone(), Health::liveness)),
+            )
+            .route(
+                "/readyz",
+                get(move || respond(readiness.clone(), Health::readiness)),
+            )
+    }
+}
+
+async fn respond(health: Health, probe: fn(&Health) -> Report) -> impl IntoResponse {
+    let report = tokio::task::spawn_blocking(move || probe(&health))
+        .await
+        .expect("Health check panicked");
+    let code = match report.is_ok() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (code, Json(report))
+}
+
+/// Keeps a liveness check created by [`Health::heartbeat`] healthy.
+#[derive(Clone)]
+pub struct Heartbeat {
+    last: Arc<Mutex<Instant>>,
+}
+
+impl Heartbeat {
+    #[inline]
+    pub fn beat(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readiness_includes_liveness_and_shutdown() {
+        let shutdown = Shutdown::new();
+        let health = Health::new(shutdown.clone());
+        health.add_liveness("loop", || Ok(()));
+        health.add_readiness("redis", || Err("connection refused".to_owned()));
+
+        assert!(health.liveness().is_ok());
+
+        let report = health.readiness();
+        assert!(!report.is_ok());
+        assert_eq!(report.checks.len(), 3);
+        assert_eq!(
+            report.checks["redis"].error.as_deref(),
+            Some("connection refused")
+        );
+        assert_eq!(report.checks["shutdown"].status, Status::Ok);
+
+        shutdown.trigger();
+        assert_eq!(
+            health.readiness().checks["shutdown"].status,
+            Status::Unavailable
+        );
+    }
+
+    #[test]
+    fn test_heartbeat_expires() {
+        let health = Health::new(Shutdown::new());
+        let heartbeat = health.heartbeat("ticker", Duration::from_millis(20));
+        assert!(health.liveness().is_ok());
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!health.liveness().is_ok());
+
+        heartbeat.beat();
+        assert!(health.liveness().is_ok());
+    }
+}
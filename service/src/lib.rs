@@ -0,0 +1,4 @@
+pub mod config;
+pub mod health;
+pub mod shutdown;
+pub mod telemetry;
@@ -0,0 +1,116 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Time given to a service to drain its work after the shutdown was triggered.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Coordinates the graceful shutdown of a service.
+///
+/// The shutdown is triggered by SIGTERM, Ctrl+C or a panic in any thread. Components wait for [`Shutdown::triggered`]
+/// (or use [`Shutdown::token`]) to stop taking new work, after which the service drains in-flight work with
+/// [`Shutdown::drain`] and exits with [`Shutdown::exit_code`].
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    failed: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new `Shutdown` which is triggered by SIGTERM, Ctrl+C and panics.
+    ///
+    /// Replaces the panic hook: after the default hook reported the panic, the shutdown is triggered and the process
+    /// will exit with a failure code instead of continuing in a broken state.
+    ///
+    /// Has to be called from within a tokio runtime.
+    pub fn install() -> Self {
+        let shutdown = Self::new();
+
+        let default_hook = std::panic::take_hook();
+        let on_panic = shutdown.clone();
+        std::panic::set_hook(Box::new(move |info| {
+            default_hook(info);
+            on_panic.failed.store(true, Ordering::SeqCst);
+            on_panic.trigger();
+        }));
+
+        let on_signal = shutdown.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            info!("Shutdown signal received");
+            on_signal.trigger();
+        });
+
+        shutdown
+    }
+
+    /// Starts the shutdown. Calling this more than once has no effect.
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    #[inline]
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Resolves as soon as the shutdown was triggered.
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Token which is cancelled when the shutdown is triggered.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// Runs `cleanup`, giving up after [`DEFAULT_GRACE_PERIOD`].
+    pub async fn drain<F>(&self, cleanup: F)
+    where
+        F: Future<Output = ()>,
+    {
+        if tokio::time::timeout(DEFAULT_GRACE_PERIOD, cleanup)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Graceful shutdown did not finish within {:?}",
+                DEFAULT_GRACE_PERIOD
+            );
+            self.failed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// `1` if the service is shutting down because of a panic or an incomplete drain, `0` otherwise.
+    pub fn exit_code(&self) -> i32 {
+        self.failed.load(Ordering::SeqCst) as i32
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,122 @@
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource,
+};
+use serde::Deserialize;
+use tracing::{error, Subscriber};
+
+use crate::config::{require_url, ConfigError, Validate};
+use tracing_subscriber::{
+    layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Logging and tracing settings of a service.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TelemetryConfig {
+    /// Filter directive in the `RUST_LOG` syntax, e.g. `info` or `info,gn_communicator=debug`.
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Endpoint of an OTLP (gRPC) collector. If not set spans are not exported.
+    #[serde(default, alias = "otel_exporter_otlp_endpoint")]
+    pub otlp_endpoint: Option<String>,
+}
+
+fn default_log_level() -> String {
+    "debug".to_owned()
+}
+
+impl Validate for TelemetryConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        EnvFilter::try_new(&self.log_level).map_err(|err| ConfigError::Invalid {
+            field: "log_level",
+            reason: err.to_string(),
+        })?;
+        if let Some(endpoint) = self.otlp_endpoint.as_ref() {
+            require_url("otlp_endpoint", endpoint, &["http", "https"])?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the span exporter alive. Dropping it flushes all pending spans.
+pub struct TelemetryGuard {
+    provider: Option<TracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(err) = provider.shutdown() {
+                error!("Failed to shut down tracer provider: {:?}", err);
+            }
+        }
+    }
+}
+
+fn fmt_layer<S>(format: LogFormat) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    }
+}
+
+/// Installs the global tracing subscriber and, if configured, the OpenTelemetry span exporter.
+/// The W3C trace-context propagator is always installed so trace ids are forwarded between services.
+///
+/// Has to be called from within a tokio runtime.
+pub fn init(
+    service_name: &str,
+    config: &TelemetryConfig,
+) -> Result<TelemetryGuard, Box<dyn std::error::Error>> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let filter = EnvFilter::try_new(&config.log_level)?;
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> =
+        vec![fmt_layer(config.log_format)];
+
+    let provider = match config.otlp_endpoint.as_ref() {
+        Some(endpoint) => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build()?;
+            let provider = TracerProvider::builder()
+                .with_batch_exporter(exporter, runtime::Tokio)
+                .with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    service_name.to_owned(),
+                )]))
+                .build();
+
+            layers.push(
+                tracing_opentelemetry::layer()
+                    .with_tracer(provider.tracer(service_name.to_owned()))
+                    .boxed(),
+            );
+            global::set_tracer_provider(provider.clone());
+            Some(provider)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .try_init()?;
+
+    Ok(TelemetryGuard { provider })
+}